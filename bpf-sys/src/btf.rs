@@ -0,0 +1,252 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The BTF format, as read by the loader of `redbpf` and by the header
+//! generator of `cargo-bpf`.
+//!
+//! The kind specific data of each type is left to the users of the types.
+use std::convert::TryInto;
+
+pub const BTF_MAGIC: u16 = 0xeb9f;
+
+pub const BTF_KIND_INT: u32 = 1;
+pub const BTF_KIND_PTR: u32 = 2;
+pub const BTF_KIND_ARRAY: u32 = 3;
+pub const BTF_KIND_STRUCT: u32 = 4;
+pub const BTF_KIND_UNION: u32 = 5;
+pub const BTF_KIND_ENUM: u32 = 6;
+pub const BTF_KIND_FWD: u32 = 7;
+pub const BTF_KIND_TYPEDEF: u32 = 8;
+pub const BTF_KIND_VOLATILE: u32 = 9;
+pub const BTF_KIND_CONST: u32 = 10;
+pub const BTF_KIND_RESTRICT: u32 = 11;
+pub const BTF_KIND_FUNC: u32 = 12;
+pub const BTF_KIND_FUNC_PROTO: u32 = 13;
+pub const BTF_KIND_VAR: u32 = 14;
+pub const BTF_KIND_DATASEC: u32 = 15;
+pub const BTF_KIND_FLOAT: u32 = 16;
+pub const BTF_KIND_DECL_TAG: u32 = 17;
+pub const BTF_KIND_TYPE_TAG: u32 = 18;
+pub const BTF_KIND_ENUM64: u32 = 19;
+
+/// The size of `struct btf_type`, which the kind specific data follows.
+pub const BTF_TYPE_SIZE: usize = 12;
+
+/// A type of a BTF type section.
+#[derive(Debug, Clone, Copy)]
+pub struct BtfType {
+    /// The offset of the type in the type section.
+    pub offset: usize,
+    pub name_off: u32,
+    pub kind: u32,
+    pub kind_flag: bool,
+    pub vlen: usize,
+    /// The size of the type, or the id of the type it refers to.
+    pub size_or_type: u32,
+}
+
+/// Returns the type and string sections of the BTF blob `btf`.
+pub fn sections(btf: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    if btf.len() < 24 || u16::from_ne_bytes([btf[0], btf[1]]) != BTF_MAGIC {
+        return Err("invalid header".to_string());
+    }
+    let hdr_len = read_u32(btf, 4)? as usize;
+    let section = |off_at: usize, len_at: usize| -> Result<&[u8], String> {
+        let off = read_u32(btf, off_at)? as usize;
+        let len = read_u32(btf, len_at)? as usize;
+        hdr_len
+            .checked_add(off)
+            .and_then(|start| btf.get(start..start.checked_add(len)?))
+            .ok_or_else(|| "section truncated".to_string())
+    };
+
+    Ok((section(8, 12)?, section(16, 20)?))
+}
+
+/// Parses the types of the type section `data`.
+///
+/// The id of a type is its index plus one, as id 0 is `void`.
+pub fn parse_types(data: &[u8]) -> Result<Vec<BtfType>, String> {
+    let mut types = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let name_off = read_u32(data, offset)?;
+        let info = read_u32(data, offset + 4)?;
+        let size_or_type = read_u32(data, offset + 8)?;
+        let kind = (info >> 24) & 0x1f;
+        let kind_flag = info >> 31 == 1;
+        let vlen = (info & 0xffff) as usize;
+
+        types.push(BtfType {
+            offset,
+            name_off,
+            kind,
+            kind_flag,
+            vlen,
+            size_or_type,
+        });
+
+        offset += BTF_TYPE_SIZE
+            + match kind {
+                BTF_KIND_INT | BTF_KIND_VAR | BTF_KIND_DECL_TAG => 4,
+                BTF_KIND_PTR | BTF_KIND_FWD | BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE
+                | BTF_KIND_CONST | BTF_KIND_RESTRICT | BTF_KIND_FUNC | BTF_KIND_FLOAT
+                | BTF_KIND_TYPE_TAG => 0,
+                BTF_KIND_ARRAY => 12,
+                BTF_KIND_STRUCT | BTF_KIND_UNION | BTF_KIND_DATASEC | BTF_KIND_ENUM64 => 12 * vlen,
+                BTF_KIND_ENUM | BTF_KIND_FUNC_PROTO => 8 * vlen,
+                _ => return Err(format!("unknown type kind {}", kind)),
+            };
+        if offset > data.len() {
+            return Err("type section truncated".to_string());
+        }
+    }
+
+    Ok(types)
+}
+
+/// Reads the native endian `u32` at `offset` in `data`.
+pub fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    offset
+        .checked_add(4)
+        .and_then(|end| data.get(offset..end))
+        .map(|b| u32::from_ne_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "truncated BTF".to_string())
+}
+
+/// Builds BTF blobs type by type, for the tests of the users of the format.
+#[doc(hidden)]
+pub mod test_support {
+    use super::*;
+
+    pub struct Builder {
+        types: Vec<u8>,
+        strings: Vec<u8>,
+        count: u32,
+    }
+
+    impl Builder {
+        pub fn new() -> Builder {
+            Builder {
+                types: Vec::new(),
+                strings: vec![0],
+                count: 0,
+            }
+        }
+
+        /// Adds `name` to the string section, and returns its offset.
+        pub fn name(&mut self, name: &str) -> u32 {
+            if name.is_empty() {
+                return 0;
+            }
+            let offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            offset
+        }
+
+        /// Adds a type followed by `data`, and returns its id.
+        ///
+        /// The high bit of `kind` is the `kind_flag` of the type.
+        pub fn add(
+            &mut self,
+            name: &str,
+            kind: u32,
+            vlen: u32,
+            size_or_type: u32,
+            data: &[u32],
+        ) -> u32 {
+            let name_off = self.name(name);
+            let kind_flag = (kind >> 31) << 31;
+            let info = kind_flag | (kind & 0x1f) << 24 | vlen;
+            for word in [name_off, info, size_or_type].iter().chain(data) {
+                self.types.extend_from_slice(&word.to_ne_bytes());
+            }
+            self.count += 1;
+            self.count
+        }
+
+        pub fn int(&mut self, name: &str, size: u32) -> u32 {
+            self.add(name, BTF_KIND_INT, 0, size, &[size * 8])
+        }
+
+        /// Adds a struct and returns its id.
+        ///
+        /// `members` are (name, type, offset in bits). Bitfields need
+        /// `kind_flag` and the size of the field in the high bits of the
+        /// offset.
+        pub fn structure(
+            &mut self,
+            name: &str,
+            size: u32,
+            kind_flag: bool,
+            members: &[(&str, u32, u32)],
+        ) -> u32 {
+            let mut data = Vec::new();
+            for (name, ty, offset) in members {
+                data.extend_from_slice(&[self.name(name), *ty, *offset]);
+            }
+            let kind = BTF_KIND_STRUCT | (kind_flag as u32) << 31;
+            self.add(name, kind, members.len() as u32, size, &data)
+        }
+
+        /// Returns the BTF blob of the types.
+        pub fn build(&self) -> Vec<u8> {
+            let mut btf = Vec::new();
+            btf.extend_from_slice(&BTF_MAGIC.to_ne_bytes());
+            btf.extend_from_slice(&[1, 0]);
+            for word in &[
+                24,
+                0,
+                self.types.len() as u32,
+                self.types.len() as u32,
+                self.strings.len() as u32,
+            ] {
+                btf.extend_from_slice(&word.to_ne_bytes());
+            }
+            btf.extend_from_slice(&self.types);
+            btf.extend_from_slice(&self.strings);
+            btf
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::test_support::Builder;
+    use super::*;
+
+    fn parse(btf: &[u8]) -> Result<Vec<BtfType>, String> {
+        parse_types(sections(btf)?.0)
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let mut b = Builder::new();
+        b.int("int", 4);
+        let btf = b.build();
+        assert_eq!(parse(&btf).unwrap().len(), 1);
+
+        assert!(parse(&btf[..20]).is_err());
+        let mut bad_magic = btf.clone();
+        bad_magic[0] ^= 0xff;
+        assert!(parse(&bad_magic).is_err());
+        assert!(parse(&btf[..btf.len() - 1]).is_err());
+        // the string section is out of bounds
+        let mut far = btf.clone();
+        far[16..24].copy_from_slice(&[0xff; 8]);
+        assert!(parse(&far).is_err());
+        // the type is truncated
+        let mut short = btf.clone();
+        short[12..16].copy_from_slice(&12u32.to_ne_bytes());
+        assert!(parse(&short).is_err());
+
+        let mut b = Builder::new();
+        b.add("", 31, 0, 0, &[]);
+        assert!(parse(&b.build()).is_err());
+    }
+}
@@ -11,6 +11,7 @@
 #![allow(clippy::all)]
 
 extern crate zero;
+pub mod btf;
 pub mod headers;
 pub mod perf_reader;
 pub mod uname;
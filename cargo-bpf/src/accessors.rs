@@ -166,13 +166,38 @@ pub fn generate_read_accessors(bindings: &str, whitelist: &[&str]) -> String {
                 .fold(HashMap::new(), |mut cache, acc| {
                     let ident = acc.field.ident.clone().unwrap();
                     let ty = &acc.field.ty;
+                    let item = Ident::new(item_id, Span::call_site());
                     let prefix = acc.prefix.iter().map(|p| Ident::new(p, Span::call_site()));
+                    // the loader patches the address of FIELD with the offset
+                    // of the field in the running kernel, see
+                    // redbpf::Module::parse. If the running kernel has no
+                    // BTF, the offset the probe was built with is used, and
+                    // if the field doesn't exist there the accessor returns
+                    // None. The array records the type in the BTF, and the
+                    // byte is there because the kernel rejects the BTF of
+                    // empty sections.
+                    let access = std::iter::once(item_id.as_str())
+                        .chain(acc.prefix.iter().skip(1).map(String::as_str))
+                        .chain(std::iter::once(ident.to_string().as_str()))
+                        .collect::<Vec<_>>()
+                        .join(".");
+                    let section = format!("core_reloc/{}", access);
+                    let read = quote! {
+                        #[link_section = #section]
+                        static FIELD: (u8, [fn(#item); 0]) = (0, []);
+                        let field = match &FIELD as *const _ as usize {
+                            0xffff_fff8 => return None,
+                            0xffff_fff0 => unsafe { &#(#prefix).*.#ident as *const #ty },
+                            offset => (self as *const Self as usize + offset) as *const #ty,
+                        };
+                    };
 
                     let _ = cache.entry(ident.to_string()).or_insert_with(|| match ty {
                         Type::Ptr(_) => {
                             quote! {
                                 pub fn #ident(&self) -> Option<#ty> {
-                                    let v = unsafe { bpf_probe_read(&#(#prefix).*.#ident) }.ok()?;
+                                    #read
+                                    let v = unsafe { bpf_probe_read(field) }.ok()?;
                                     if v.is_null() {
                                         None
                                     } else {
@@ -184,7 +209,8 @@ pub fn generate_read_accessors(bindings: &str, whitelist: &[&str]) -> String {
                         _ => {
                             quote! {
                                 pub fn #ident(&self) -> Option<#ty> {
-                                    unsafe { bpf_probe_read(&#(#prefix).*.#ident) }.ok()
+                                    #read
+                                    unsafe { bpf_probe_read(field) }.ok()
                                 }
                             }
                         }
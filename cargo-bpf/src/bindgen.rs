@@ -7,15 +7,22 @@
 
 pub use bindgen::Builder;
 use bindgen::{self, callbacks::ParseCallbacks};
+use std::collections::HashMap;
+use std::fs;
 use std::io::{self, Write};
 use std::path::Path;
 use std::process::Command;
 use std::str;
 
 pub use crate::accessors::generate_read_accessors;
+use crate::btf::vmlinux_header;
 use crate::build_constants::{kernel_headers, BUILD_FLAGS};
 use crate::CommandError;
 
+/// The BTF of the running kernel, available when it's built with
+/// `CONFIG_DEBUG_INFO_BTF`.
+pub const VMLINUX_BTF: &str = "/sys/kernel/btf/vmlinux";
+
 pub fn builder() -> Builder {
     let kernel_headers = kernel_headers().expect("couldn't find kernel headers");
     let mut flags: Vec<String> = kernel_headers
@@ -24,8 +31,35 @@ pub fn builder() -> Builder {
         .collect();
     flags.extend(BUILD_FLAGS.iter().map(|f| f.to_string()));
 
-    bindgen::builder()
-        .clang_args(&flags)
+    configure(bindgen::builder().clang_args(&flags))
+}
+
+/// Returns a builder that takes the kernel types from the BTF file `btf`
+/// instead of the kernel headers.
+///
+/// The types are written as C declarations to `vmlinux.h` in `out_dir`,
+/// which is added to the include path so that headers passed to the
+/// builder can `#include "vmlinux.h"`. The header only has types, macros
+/// from the kernel headers aren't available.
+pub fn btf_builder(btf: &Path, out_dir: &Path) -> Result<Builder, String> {
+    let data = fs::read(btf).map_err(|e| format!("couldn't read {}: {}", btf.display(), e))?;
+    let header = vmlinux_header(&data).map_err(|e| format!("{}: {}", btf.display(), e))?;
+    fs::write(out_dir.join("vmlinux.h"), header).map_err(|e| e.to_string())?;
+
+    let mut flags = vec![format!("-I{}", out_dir.display())];
+    // the generated declarations aren't worth failing the build over
+    flags.extend(
+        BUILD_FLAGS
+            .iter()
+            .filter(|f| **f != "-Werror")
+            .map(|f| f.to_string()),
+    );
+
+    Ok(configure(bindgen::builder().clang_args(&flags)))
+}
+
+fn configure(builder: Builder) -> Builder {
+    builder
         .use_core()
         .ctypes_prefix("::cty")
         .opaque_type("xregs_state")
@@ -53,7 +87,46 @@ pub fn generate(builder: &Builder, extra_args: &[&str]) -> Result<String, String
     Ok(bindings)
 }
 
-pub fn cmd_bindgen(header: &Path, extra_args: &[&str]) -> Result<(), CommandError> {
+/// Returns the ids of the BPF helpers defined in `header`.
+///
+/// Helper ids aren't contiguous, so they're read from the definitions in
+/// `bpf_helper_defs.h`, eg
+/// `static void *(*bpf_map_lookup_elem)(void *map, const void *key) = (void *) 1;`
+pub fn helper_ids(header: &str) -> HashMap<String, usize> {
+    header
+        .lines()
+        .filter(|line| line.starts_with("static "))
+        .filter_map(|line| {
+            let start = line.find("(*")? + 2;
+            let end = start + line[start..].find(')')?;
+            let id = line
+                .rsplit("(void *)")
+                .next()?
+                .trim()
+                .trim_end_matches(';')
+                .parse()
+                .ok()?;
+            Some((line[start..end].to_string(), id))
+        })
+        .collect()
+}
+
+pub fn cmd_bindgen(
+    header: Option<&Path>,
+    btf: Option<&Path>,
+    extra_args: &[&str],
+) -> Result<(), CommandError> {
+    if let Some(btf) = btf {
+        let out_dir = tempfile::tempdir()?;
+        let builder = btf_builder(btf, out_dir.path()).map_err(CommandError)?;
+        // without a header, generate bindings for all the kernel types
+        let header = header
+            .map(Path::to_owned)
+            .unwrap_or_else(|| out_dir.path().join("vmlinux.h"));
+        return write_bindings(&builder.header(header.to_str().unwrap()), extra_args);
+    }
+
+    let header = header.ok_or_else(|| CommandError("no header given".to_string()))?;
     let (_temp, header) = if !header.exists() {
         // try to find find the file in the kernel include path
         let path = header.to_str().unwrap();
@@ -74,8 +147,11 @@ pub fn cmd_bindgen(header: &Path, extra_args: &[&str]) -> Result<(), CommandErro
         (None, header.to_owned())
     };
 
-    let builder = builder().header(header.to_str().unwrap());
-    let bindings = generate(&builder, extra_args).map_err(CommandError)?;
+    write_bindings(&builder().header(header.to_str().unwrap()), extra_args)
+}
+
+fn write_bindings(builder: &Builder, extra_args: &[&str]) -> Result<(), CommandError> {
+    let bindings = generate(builder, extra_args).map_err(CommandError)?;
     let mut out = io::stdout();
     writeln!(
         &mut out,
@@ -106,3 +182,34 @@ impl ParseCallbacks for Callbacks {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_helper_ids() {
+        let header = r#"
+/* Forward declarations of BPF structs */
+struct bpf_fib_lookup;
+
+/*
+ * bpf_map_lookup_elem
+ *
+ * static void *(*bpf_map_lookup_elem)(void *map, const void *key) = (void *) 99;
+ */
+static void *(*bpf_map_lookup_elem)(void *map, const void *key) = (void *) 1;
+static long (*bpf_probe_read)(void *dst, __u32 size, const void *unsafe_ptr) = (void *) 4;
+static __u64 (*bpf_ktime_get_ns)(void) = (void *) 5;
+static long (*bpf_ringbuf_output)(void *ringbuf, void *data, __u64 size, __u64 flags) = (void *) 130;
+static long (*bpf_broken)(void) = (void *) foo;
+static inline int not_a_helper(void) { return 0; }
+"#;
+        let ids = helper_ids(header);
+        assert_eq!(ids.len(), 4);
+        assert_eq!(ids["bpf_map_lookup_elem"], 1);
+        assert_eq!(ids["bpf_probe_read"], 4);
+        assert_eq!(ids["bpf_ktime_get_ns"], 5);
+        assert_eq!(ids["bpf_ringbuf_output"], 130);
+    }
+}
@@ -0,0 +1,734 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Writes the types of a BTF blob as C declarations, so that bindings can be
+//! generated from the BTF of a kernel instead of its headers.
+//!
+//! The layout of the types is taken as is from the BTF: holes are filled
+//! with unnamed bitfields and structs are packed where needed, so the
+//! declarations don't depend on the attributes the kernel was built with.
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use bpf_sys::btf::{
+    self, read_u32, BTF_KIND_ARRAY, BTF_KIND_CONST, BTF_KIND_DATASEC, BTF_KIND_DECL_TAG,
+    BTF_KIND_ENUM, BTF_KIND_ENUM64, BTF_KIND_FLOAT, BTF_KIND_FUNC, BTF_KIND_FUNC_PROTO,
+    BTF_KIND_FWD, BTF_KIND_INT, BTF_KIND_PTR, BTF_KIND_RESTRICT, BTF_KIND_STRUCT, BTF_KIND_TYPEDEF,
+    BTF_KIND_TYPE_TAG, BTF_KIND_UNION, BTF_KIND_VAR, BTF_KIND_VOLATILE, BTF_TYPE_SIZE,
+};
+
+enum Kind {
+    Void,
+    Int {
+        bits: u32,
+    },
+    Float,
+    Ptr(u32),
+    Array {
+        elem: u32,
+        len: u32,
+    },
+    Struct {
+        union: bool,
+        members: Vec<Member>,
+    },
+    Enum {
+        values: Vec<(String, String)>,
+        signed: bool,
+    },
+    Fwd {
+        union: bool,
+    },
+    Typedef(u32),
+    Qualifier(&'static str, u32),
+    FuncProto {
+        ret: u32,
+        params: Vec<(String, u32)>,
+    },
+    // functions, variables and other kinds that don't declare types
+    Other,
+}
+
+struct Member {
+    name: String,
+    ty: u32,
+    // in bits
+    offset: u32,
+    bitfield_size: u32,
+}
+
+struct Type {
+    name: String,
+    size: u32,
+    kind: Kind,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    NotEmitted,
+    Emitting,
+    Emitted,
+}
+
+/// Returns the C declarations of all the types of the BTF blob `btf`.
+pub(crate) fn vmlinux_header(btf: &[u8]) -> Result<String, String> {
+    let types = parse(btf)?;
+    let mut dump = Dump::new(&types);
+    dump.dump();
+    Ok(dump.out)
+}
+
+fn parse(btf: &[u8]) -> Result<Vec<Type>, String> {
+    let (data, strings) = btf::sections(btf)?;
+    let string = |offset: u32| -> Result<String, String> {
+        let bytes = strings.get(offset as usize..).ok_or("invalid BTF string")?;
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    };
+
+    // type 0 is void
+    let mut types = vec![Type {
+        name: String::new(),
+        size: 0,
+        kind: Kind::Void,
+    }];
+    for ty in btf::parse_types(data)? {
+        let (kind_flag, vlen, size_or_type) = (ty.kind_flag, ty.vlen, ty.size_or_type);
+        let u32_at = |i: usize| read_u32(data, ty.offset + BTF_TYPE_SIZE + 4 * i);
+
+        let kind = match ty.kind {
+            BTF_KIND_INT => Kind::Int {
+                bits: u32_at(0)? & 0xff,
+            },
+            BTF_KIND_FLOAT => Kind::Float,
+            BTF_KIND_PTR => Kind::Ptr(size_or_type),
+            BTF_KIND_ARRAY => Kind::Array {
+                elem: u32_at(0)?,
+                len: u32_at(2)?,
+            },
+            kind @ BTF_KIND_STRUCT | kind @ BTF_KIND_UNION => {
+                let mut members = Vec::with_capacity(vlen);
+                for i in 0..vlen {
+                    let mut offset = u32_at(3 * i + 2)?;
+                    let mut bitfield_size = 0;
+                    if kind_flag {
+                        bitfield_size = offset >> 24;
+                        offset &= 0xff_ffff;
+                    }
+                    members.push(Member {
+                        name: string(u32_at(3 * i)?)?,
+                        ty: u32_at(3 * i + 1)?,
+                        offset,
+                        bitfield_size,
+                    });
+                }
+                let union = kind == BTF_KIND_UNION;
+                Kind::Struct { union, members }
+            }
+            BTF_KIND_ENUM => {
+                let mut values = Vec::with_capacity(vlen);
+                for i in 0..vlen {
+                    let value = u32_at(2 * i + 1)?;
+                    let value = if kind_flag {
+                        (value as i32).to_string()
+                    } else {
+                        value.to_string()
+                    };
+                    values.push((string(u32_at(2 * i)?)?, value));
+                }
+                let signed = kind_flag;
+                Kind::Enum { values, signed }
+            }
+            BTF_KIND_ENUM64 => {
+                let mut values = Vec::with_capacity(vlen);
+                for i in 0..vlen {
+                    let value = u64::from(u32_at(3 * i + 2)?) << 32 | u64::from(u32_at(3 * i + 1)?);
+                    let value = if kind_flag {
+                        (value as i64).to_string()
+                    } else if value > i64::MAX as u64 {
+                        format!("{}ULL", value)
+                    } else {
+                        value.to_string()
+                    };
+                    values.push((string(u32_at(3 * i)?)?, value));
+                }
+                let signed = kind_flag;
+                Kind::Enum { values, signed }
+            }
+            BTF_KIND_FWD => Kind::Fwd { union: kind_flag },
+            BTF_KIND_TYPEDEF => Kind::Typedef(size_or_type),
+            BTF_KIND_VOLATILE => Kind::Qualifier("volatile", size_or_type),
+            BTF_KIND_CONST => Kind::Qualifier("const", size_or_type),
+            BTF_KIND_RESTRICT => Kind::Qualifier("restrict", size_or_type),
+            // type tags are only meaningful to the verifier
+            BTF_KIND_TYPE_TAG => Kind::Qualifier("", size_or_type),
+            BTF_KIND_FUNC_PROTO => {
+                let mut params = Vec::with_capacity(vlen);
+                for i in 0..vlen {
+                    params.push((string(u32_at(2 * i)?)?, u32_at(2 * i + 1)?));
+                }
+                let ret = size_or_type;
+                Kind::FuncProto { ret, params }
+            }
+            BTF_KIND_FUNC | BTF_KIND_VAR | BTF_KIND_DECL_TAG | BTF_KIND_DATASEC => Kind::Other,
+            kind => return Err(format!("unknown BTF type kind {}", kind)),
+        };
+
+        types.push(Type {
+            name: string(ty.name_off)?,
+            size: size_or_type,
+            kind,
+        });
+    }
+
+    Ok(types)
+}
+
+struct Dump<'t> {
+    types: &'t [Type],
+    // the unique C names of the types and enum values
+    names: Vec<String>,
+    enum_values: HashMap<(u32, usize), String>,
+    state: Vec<State>,
+    aligns: Vec<Cell<u32>>,
+    out: String,
+}
+
+impl<'t> Dump<'t> {
+    fn new(types: &'t [Type]) -> Dump<'t> {
+        // struct, union and enum tags share a namespace, and typedefs share
+        // theirs with enum values
+        let mut tags = HashMap::new();
+        let mut ordinary = HashMap::new();
+        let unique = |namespace: &mut HashMap<String, usize>, name: &str| {
+            let count = namespace.entry(name.to_string()).or_insert(0);
+            *count += 1;
+            if *count == 1 {
+                name.to_string()
+            } else {
+                format!("{}___{}", name, count)
+            }
+        };
+
+        let mut names = Vec::with_capacity(types.len());
+        let mut enum_values = HashMap::new();
+        for (id, ty) in types.iter().enumerate() {
+            let name = match &ty.kind {
+                _ if ty.name.is_empty() => String::new(),
+                Kind::Struct { .. } => unique(&mut tags, &ty.name),
+                Kind::Typedef(_) => unique(&mut ordinary, &ty.name),
+                Kind::Enum { .. } => unique(&mut tags, &ty.name),
+                _ => ty.name.clone(),
+            };
+            if let Kind::Enum { values, .. } = &ty.kind {
+                for (i, (value, _)) in values.iter().enumerate() {
+                    enum_values.insert((id as u32, i), unique(&mut ordinary, value));
+                }
+            }
+            names.push(name);
+        }
+
+        Dump {
+            types,
+            names,
+            enum_values,
+            state: vec![State::NotEmitted; types.len()],
+            aligns: (0..types.len()).map(|_| Cell::new(0)).collect(),
+            out: String::new(),
+        }
+    }
+
+    fn dump(&mut self) {
+        self.out
+            .push_str("#ifndef __VMLINUX_H__\n#define __VMLINUX_H__\n\n");
+
+        // pointers only need the declarations of structs and unions
+        let mut declared = HashSet::new();
+        for (id, ty) in self.types.iter().enumerate() {
+            let union = match ty.kind {
+                Kind::Struct { union, .. } | Kind::Fwd { union } => union,
+                _ => continue,
+            };
+            let name = &self.names[id];
+            if !name.is_empty() && declared.insert((union, name)) {
+                let keyword = if union { "union" } else { "struct" };
+                let _ = writeln!(self.out, "{} {};", keyword, name);
+            }
+        }
+        self.out.push('\n');
+
+        // enums don't depend on other types, so they all come first
+        for (id, ty) in self.types.iter().enumerate() {
+            if let Kind::Enum { values, .. } = &ty.kind {
+                if values.is_empty() {
+                    continue;
+                }
+                let name = &self.names[id];
+                let _ = writeln!(
+                    self.out,
+                    "enum {}{}{{",
+                    name,
+                    if name.is_empty() { "" } else { " " }
+                );
+                for (i, (_, value)) in values.iter().enumerate() {
+                    let _ = writeln!(
+                        self.out,
+                        "\t{} = {},",
+                        self.enum_values[&(id as u32, i)],
+                        value
+                    );
+                }
+                self.out.push_str("};\n\n");
+            }
+        }
+
+        for id in 0..self.types.len() as u32 {
+            match self.types[id as usize].kind {
+                Kind::Struct { .. } | Kind::Typedef(_) => self.emit(id, true),
+                _ => (),
+            }
+        }
+
+        self.out.push_str("#endif /* __VMLINUX_H__ */\n");
+    }
+
+    // Emits the definitions that type `id` depends on, then its own if it's a
+    // named struct, union or typedef. Types used by value (`complete`) need
+    // the definitions of structs and unions, while pointers only need their
+    // declarations.
+    fn emit(&mut self, id: u32, complete: bool) {
+        let types = self.types;
+        let ty = &types[id as usize];
+        match &ty.kind {
+            Kind::Ptr(target) => self.emit(*target, false),
+            Kind::Array { elem, .. } => self.emit(*elem, complete),
+            Kind::Qualifier(_, target) => self.emit(*target, complete),
+            Kind::FuncProto { ret, params } => {
+                self.emit(*ret, false);
+                for (_, param) in params {
+                    self.emit(*param, false);
+                }
+            }
+            Kind::Struct { members, .. } if ty.name.is_empty() => {
+                // anonymous structs and unions are defined where they're used
+                for member in members {
+                    self.emit(member.ty, true);
+                }
+            }
+            Kind::Struct { members, .. } => {
+                if !complete || self.state[id as usize] != State::NotEmitted {
+                    return;
+                }
+                self.state[id as usize] = State::Emitting;
+                for member in members {
+                    self.emit(member.ty, true);
+                }
+                let definition = format!("{};\n\n", self.decl(id, "", 0, true));
+                self.out.push_str(&definition);
+                self.state[id as usize] = State::Emitted;
+            }
+            // a compiler builtin, which can't be redefined
+            Kind::Typedef(_) if ty.name == "__builtin_va_list" => (),
+            Kind::Typedef(target) => {
+                if self.state[id as usize] == State::NotEmitted {
+                    self.state[id as usize] = State::Emitting;
+                    self.emit(*target, false);
+                    let definition = format!(
+                        "typedef {};\n\n",
+                        self.decl(*target, &self.names[id as usize], 0, false)
+                    );
+                    self.out.push_str(&definition);
+                    self.state[id as usize] = State::Emitted;
+                }
+                if complete {
+                    self.emit(*target, true);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Returns the declaration of `inner` with type `id`. Named structs and
+    // unions are only referred to by name, unless `define` is set.
+    fn decl(&self, id: u32, inner: &str, indent: usize, define: bool) -> String {
+        let ty = &self.types[id as usize];
+        let name = &self.names[id as usize];
+        let with_inner = |base: &str| {
+            if inner.is_empty() {
+                base.to_string()
+            } else {
+                format!("{} {}", base, inner)
+            }
+        };
+
+        match &ty.kind {
+            Kind::Void | Kind::Other => with_inner("void"),
+            Kind::Int { .. } | Kind::Float => with_inner(&ty.name),
+            Kind::Typedef(_) => with_inner(name),
+            Kind::Fwd { union } => with_inner(&format!("{} {}", keyword(*union), name)),
+            Kind::Enum { values, signed } => {
+                // values are defined separately, and the size of enums can
+                // differ from the one C would pick
+                if !name.is_empty() && !values.is_empty() && ty.size == 4 {
+                    with_inner(&format!("enum {}", name))
+                } else {
+                    with_inner(int_type(ty.size, *signed))
+                }
+            }
+            Kind::Ptr(target) => {
+                let inner = format!("*{}", inner);
+                match self.types[*target as usize].kind {
+                    Kind::Array { .. } | Kind::FuncProto { .. } => {
+                        self.decl(*target, &format!("({})", inner), indent, false)
+                    }
+                    _ => self.decl(*target, &inner, indent, false),
+                }
+            }
+            Kind::Array { elem, len } => {
+                self.decl(*elem, &format!("{}[{}]", inner, len), indent, false)
+            }
+            Kind::Qualifier("", target) => self.decl(*target, inner, indent, false),
+            Kind::Qualifier(qualifier, target) => match self.types[*target as usize].kind {
+                Kind::Ptr(_) => {
+                    self.decl(*target, &format!("{} {}", qualifier, inner), indent, false)
+                }
+                _ => {
+                    let decl = self.decl(*target, inner, indent, false);
+                    // the elements of const arrays can be const already
+                    if decl.starts_with(&format!("{} ", qualifier)) {
+                        decl
+                    } else {
+                        format!("{} {}", qualifier, decl)
+                    }
+                }
+            },
+            Kind::FuncProto { ret, params } => {
+                let params = if params.is_empty() {
+                    "void".to_string()
+                } else {
+                    params
+                        .iter()
+                        .map(|(name, param)| match param {
+                            // variadic functions end with a void parameter
+                            0 => "...".to_string(),
+                            _ => self.decl(*param, name, indent, false),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                self.decl(*ret, &format!("{}({})", inner, params), indent, false)
+            }
+            Kind::Struct { union, .. } if define || name.is_empty() => {
+                let mut body = String::new();
+                let _ = write!(body, "{} ", keyword(*union));
+                if !name.is_empty() {
+                    let _ = write!(body, "{} ", name);
+                }
+                body.push_str("{\n");
+                self.members(id, indent + 1, &mut body);
+                let _ = write!(body, "{}}}", "\t".repeat(indent));
+                if self.is_packed(id) {
+                    body.push_str(" __attribute__((packed))");
+                }
+                with_inner(&body)
+            }
+            Kind::Struct { union, .. } => with_inner(&format!("{} {}", keyword(*union), name)),
+        }
+    }
+
+    fn members(&self, id: u32, indent: usize, out: &mut String) {
+        let ty = &self.types[id as usize];
+        let (union, members) = match &ty.kind {
+            Kind::Struct { union, members } => (*union, members),
+            _ => return,
+        };
+        let packed = self.is_packed(id);
+        let tabs = "\t".repeat(indent);
+
+        let mut offset = 0;
+        for member in members {
+            let bitfield_size = self.bitfield_size(member);
+            if !union {
+                // the compiler aligns members that aren't bitfields, so
+                // padding is only needed when that's not enough
+                let align = if packed || bitfield_size != 0 {
+                    1
+                } else {
+                    self.align(member.ty) * 8
+                };
+                if round_up(offset, align) != member.offset {
+                    pad(offset, member.offset, &tabs, out);
+                }
+            }
+
+            let _ = write!(
+                out,
+                "{}{}",
+                tabs,
+                self.decl(member.ty, &member.name, indent, false)
+            );
+            if bitfield_size != 0 {
+                let _ = write!(out, ": {}", bitfield_size);
+            }
+            out.push_str(";\n");
+
+            offset = member.offset
+                + if bitfield_size != 0 {
+                    bitfield_size
+                } else {
+                    self.size(member.ty) * 8
+                };
+        }
+
+        let size = ty.size * 8;
+        let align = if packed { 1 } else { self.align(id) * 8 };
+        if !union && offset < size && round_up(offset, align) != size {
+            pad(offset, size, &tabs, out);
+        }
+    }
+
+    fn bitfield_size(&self, member: &Member) -> u32 {
+        if member.bitfield_size != 0 {
+            return member.bitfield_size;
+        }
+        // without kind_flag, bitfields are encoded in their int type
+        match self.types[self.resolve(member.ty) as usize] {
+            Type {
+                kind: Kind::Int { bits },
+                size,
+                ..
+            } if bits != size * 8 => bits,
+            _ => 0,
+        }
+    }
+
+    fn resolve(&self, mut id: u32) -> u32 {
+        loop {
+            match self.types[id as usize].kind {
+                Kind::Typedef(target) | Kind::Qualifier(_, target) => id = target,
+                _ => return id,
+            }
+        }
+    }
+
+    fn size(&self, id: u32) -> u32 {
+        let ty = &self.types[self.resolve(id) as usize];
+        match ty.kind {
+            Kind::Int { .. } | Kind::Float | Kind::Struct { .. } | Kind::Enum { .. } => ty.size,
+            Kind::Ptr(_) => 8,
+            Kind::Array { elem, len } => self.size(elem) * len,
+            _ => 0,
+        }
+    }
+
+    fn align(&self, id: u32) -> u32 {
+        let id = self.resolve(id);
+        let cached = self.aligns[id as usize].get();
+        if cached != 0 {
+            return cached;
+        }
+
+        let ty = &self.types[id as usize];
+        let align = match &ty.kind {
+            Kind::Int { .. } | Kind::Float | Kind::Enum { .. } => ty.size.clamp(1, 16),
+            Kind::Ptr(_) => 8,
+            Kind::Array { elem, .. } => self.align(*elem),
+            Kind::Struct { members, .. } => {
+                // set first, so packed structs can ask for their natural
+                // alignment
+                self.aligns[id as usize].set(
+                    members
+                        .iter()
+                        .map(|member| self.align(member.ty))
+                        .max()
+                        .unwrap_or(1),
+                );
+                if self.is_packed(id) {
+                    1
+                } else {
+                    self.aligns[id as usize].get()
+                }
+            }
+            _ => 1,
+        };
+        self.aligns[id as usize].set(align);
+        align
+    }
+
+    // Returns whether the layout of struct or union `id` can't be obtained
+    // by aligning its members naturally.
+    fn is_packed(&self, id: u32) -> bool {
+        let ty = &self.types[id as usize];
+        let members = match &ty.kind {
+            Kind::Struct { members, .. } => members,
+            _ => return false,
+        };
+        let align = members
+            .iter()
+            .map(|member| self.align(member.ty))
+            .max()
+            .unwrap_or(1);
+        ty.size % align != 0
+            || members.iter().any(|member| {
+                self.bitfield_size(member) == 0 && member.offset % (self.align(member.ty) * 8) != 0
+            })
+    }
+}
+
+fn keyword(union: bool) -> &'static str {
+    if union {
+        "union"
+    } else {
+        "struct"
+    }
+}
+
+fn int_type(size: u32, signed: bool) -> &'static str {
+    match (size, signed) {
+        (1, false) => "unsigned char",
+        (1, true) => "signed char",
+        (2, false) => "unsigned short",
+        (2, true) => "short",
+        (8, false) => "unsigned long long",
+        (8, true) => "long long",
+        (_, false) => "unsigned int",
+        (_, true) => "int",
+    }
+}
+
+fn round_up(offset: u32, align: u32) -> u32 {
+    (offset + align - 1) / align * align
+}
+
+// Fills the bits between `offset` and `end` with unnamed bitfields
+fn pad(mut offset: u32, end: u32, tabs: &str, out: &mut String) {
+    while offset < end {
+        let gap = end - offset;
+        let (ty, bits) = if offset % 8 != 0 || gap < 8 {
+            ("char", gap.min(8 - offset % 8))
+        } else if offset % 64 == 0 && gap >= 64 {
+            ("long", 64)
+        } else if offset % 32 == 0 && gap >= 32 {
+            ("int", 32)
+        } else if offset % 16 == 0 && gap >= 16 {
+            ("short", 16)
+        } else {
+            ("char", 8)
+        };
+        let _ = writeln!(out, "{}{}: {};", tabs, ty, bits);
+        offset += bits;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bpf_sys::btf::test_support::Builder;
+
+    // Returns the body of the header generated from the types of `b`.
+    fn header(b: &Builder) -> String {
+        let header = vmlinux_header(&b.build()).unwrap();
+        header
+            .trim_start_matches("#ifndef __VMLINUX_H__\n#define __VMLINUX_H__\n\n")
+            .trim_end_matches("#endif /* __VMLINUX_H__ */\n")
+            .to_string()
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut b = Builder::new();
+        let ch = b.int("char", 1);
+        let int = b.int("int", 4);
+        b.structure("natural", 8, false, &[("a", ch, 0), ("b", int, 32)]);
+        b.structure("hole", 12, false, &[("a", ch, 0), ("b", int, 64)]);
+        b.structure("packed", 5, false, &[("a", ch, 0), ("b", int, 8)]);
+        b.structure("tail", 8, false, &[("a", int, 0)]);
+        assert_eq!(
+            header(&b),
+            "struct natural;\nstruct hole;\nstruct packed;\nstruct tail;\n\n\
+             struct natural {\n\tchar a;\n\tint b;\n};\n\n\
+             struct hole {\n\tchar a;\n\tchar: 8;\n\tshort: 16;\n\tint: 32;\n\tint b;\n};\n\n\
+             struct packed {\n\tchar a;\n\tint b;\n} __attribute__((packed));\n\n\
+             struct tail {\n\tint a;\n\tint: 32;\n};\n\n"
+        );
+    }
+
+    #[test]
+    fn test_bitfields() {
+        let mut b = Builder::new();
+        let uint = b.int("unsigned int", 4);
+        let bits = b.add("unsigned int", BTF_KIND_INT, 0, 4, &[3]);
+        b.structure(
+            "flags",
+            4,
+            true,
+            &[("a", uint, 3 << 24), ("b", uint, 5 << 24 | 8)],
+        );
+        // without kind_flag, the size is in the int type
+        b.structure("old", 4, false, &[("a", bits, 0), ("b", bits, 4)]);
+        assert_eq!(
+            header(&b),
+            "struct flags;\nstruct old;\n\n\
+             struct flags {\n\tunsigned int a: 3;\n\tchar: 5;\n\tunsigned int b: 5;\n};\n\n\
+             struct old {\n\tunsigned int a: 3;\n\tchar: 1;\n\tunsigned int b: 3;\n};\n\n"
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        let mut b = Builder::new();
+        let uint = b.int("unsigned int", 4);
+        let foo = b.structure("foo", 4, false, &[("x", uint, 0)]);
+        let foo2 = b.structure("foo", 8, false, &[("y", uint, 0), ("z", uint, 32)]);
+        b.structure("bar", 12, false, &[("f", foo, 0), ("g", foo2, 32)]);
+        let value = b.name("foo");
+        b.add("e", BTF_KIND_ENUM, 1, 4, &[value, 1]);
+        // typedefs share their namespace with enum values
+        b.add("foo", BTF_KIND_TYPEDEF, 0, foo2, &[]);
+        assert_eq!(
+            header(&b),
+            "struct foo;\nstruct foo___2;\nstruct bar;\n\n\
+             enum e {\n\tfoo = 1,\n};\n\n\
+             struct foo {\n\tunsigned int x;\n};\n\n\
+             struct foo___2 {\n\tunsigned int y;\n\tunsigned int z;\n};\n\n\
+             struct bar {\n\tstruct foo f;\n\tstruct foo___2 g;\n};\n\n\
+             typedef struct foo___2 foo___2;\n\n"
+        );
+    }
+
+    #[test]
+    fn test_order() {
+        let mut b = Builder::new();
+        let int = b.int("int", 4);
+        let fwd = b.add("node", BTF_KIND_FWD, 0, 0, &[]);
+        let ptr = b.add("", BTF_KIND_PTR, 0, fwd, &[]);
+        let list = b.add("list_t", BTF_KIND_TYPEDEF, 0, ptr, &[]);
+        let inner_t = b.add("inner_t", BTF_KIND_TYPEDEF, 0, 7, &[]);
+        b.structure(
+            "outer",
+            16,
+            false,
+            &[("head", list, 0), ("in", inner_t, 64)],
+        );
+        let inner = b.structure("inner", 4, false, &[("v", int, 0)]);
+        assert_eq!(inner, 7);
+        let node = b.structure("node", 16, false, &[("next", 9, 0), ("val", int, 64)]);
+        assert_eq!(b.add("", BTF_KIND_PTR, 0, node, &[]), 9);
+        // pointers only need declarations, while types used by value need
+        // their definitions first
+        assert_eq!(
+            header(&b),
+            "struct node;\nstruct outer;\nstruct inner;\n\n\
+             typedef struct node *list_t;\n\n\
+             typedef struct inner inner_t;\n\n\
+             struct inner {\n\tint v;\n};\n\n\
+             struct outer {\n\tlist_t head;\n\tinner_t in;\n};\n\n\
+             struct node {\n\tstruct node *next;\n\tint val;\n};\n\n"
+        );
+    }
+}
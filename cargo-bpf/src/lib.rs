@@ -10,6 +10,8 @@ mod build_constants;
 mod accessors;
 #[cfg(feature = "bindings")]
 pub mod bindgen;
+#[cfg(feature = "bindings")]
+mod btf;
 
 #[cfg(feature = "build")]
 mod build;
@@ -1,7 +1,7 @@
 use anyhow::{anyhow, Result};
 use llvm_sys::bit_writer::LLVMWriteBitcodeToFile;
 use llvm_sys::core::*;
-use llvm_sys::debuginfo::LLVMStripModuleDebugInfo;
+use llvm_sys::debuginfo::*;
 use llvm_sys::ir_reader::LLVMParseIRInContext;
 use llvm_sys::prelude::*;
 use llvm_sys::target::*;
@@ -10,6 +10,7 @@ use llvm_sys::transforms::ipo::LLVMAddAlwaysInlinerPass;
 use llvm_sys::transforms::pass_manager_builder::*;
 use llvm_sys::{LLVMAttributeFunctionIndex, LLVMInlineAsmDialect::*};
 use llvm_sys::support::LLVMParseCommandLineOptions;
+use std::collections::HashSet;
 use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 use std::path::Path;
@@ -131,13 +132,167 @@ pub unsafe fn process_ir(context: LLVMContextRef, module: LLVMModuleRef) -> Resu
         func = LLVMGetNextFunction(func);
     }
 
-    // the debug info generated by rustc seems to trigger a segfault in the
-    // BTF code in llvm, so strip it until that is fixed
-    LLVMStripModuleDebugInfo(module);
+    // llvm generates the BTF of the module from its debug info, but crashes on
+    // the types it can't represent
+    sanitize_debug_info(context, module);
 
     Ok(())
 }
 
+/// Drops the debug info that llvm can't turn into BTF.
+///
+/// BTF has no equivalent for the variant parts rustc generates for enums with
+/// fields, and the BTF code in llvm segfaults on them. The debug info of the
+/// globals and functions whose types contain such enums is removed, so that
+/// BTF is still generated for everything else.
+unsafe fn sanitize_debug_info(context: LLVMContextRef, module: LLVMModuleRef) {
+    let dbg = CString::new("dbg").unwrap();
+    let dbg_kind = LLVMGetMDKindIDInContext(context, dbg.as_ptr(), "dbg".len() as u32);
+
+    let mut global = LLVMGetFirstGlobal(module);
+    while !global.is_null() {
+        let mut count: libc::size_t = 0;
+        let entries = LLVMGlobalCopyAllMetadata(global, &mut count);
+        for i in 0..count as u32 {
+            if LLVMValueMetadataEntriesGetKind(entries, i) == dbg_kind {
+                let md = LLVMValueMetadataEntriesGetMetadata(entries, i);
+                if !supports_btf(context, md, &mut HashSet::new()) {
+                    LLVMGlobalEraseMetadata(global, dbg_kind);
+                    break;
+                }
+            }
+        }
+        if !entries.is_null() {
+            LLVMDisposeValueMetadataEntries(entries);
+        }
+        global = LLVMGetNextGlobal(global);
+    }
+
+    let mut func = LLVMGetFirstFunction(module);
+    while !func.is_null() {
+        let sp = LLVMGetSubprogram(func);
+        if !sp.is_null() && !supports_btf(context, sp, &mut HashSet::new()) {
+            strip_function_debug_info(func);
+        }
+        func = LLVMGetNextFunction(func);
+    }
+}
+
+/// Returns whether all the types reachable from the debug info node `md` can
+/// be represented in BTF.
+unsafe fn supports_btf(
+    context: LLVMContextRef,
+    md: LLVMMetadataRef,
+    visited: &mut HashSet<LLVMMetadataRef>,
+) -> bool {
+    use LLVMMetadataKind::*;
+
+    if !visited.insert(md) {
+        return true;
+    }
+
+    let kind = LLVMGetMetadataKind(md);
+    let operands = md_operands(context, md);
+    let operand = |i: usize| operands.get(i).copied().flatten();
+
+    // only follow the operands pointing to types. Following scopes would
+    // eventually reach the compile unit, and with it the whole module.
+    let types = match kind {
+        LLVMMDTupleMetadataKind => operands.iter().copied().collect(),
+        LLVMDIGlobalVariableExpressionMetadataKind => vec![operand(0)],
+        LLVMDIGlobalVariableMetadataKind
+        | LLVMDIDerivedTypeMetadataKind
+        | LLVMDISubroutineTypeMetadataKind => vec![operand(3)],
+        LLVMDISubprogramMetadataKind => vec![operand(4)],
+        LLVMDICompositeTypeMetadataKind => {
+            // the members of structs are derived types, while enums with
+            // fields have a composite variant part instead
+            if let Some(elements) = operand(4) {
+                let is_variant_part = |e: &LLVMMetadataRef| {
+                    matches!(LLVMGetMetadataKind(*e), LLVMDICompositeTypeMetadataKind)
+                };
+                if md_operands(context, elements)
+                    .iter()
+                    .flatten()
+                    .any(is_variant_part)
+                {
+                    return false;
+                }
+            }
+            vec![operand(3), operand(4)]
+        }
+        _ => return true,
+    };
+
+    types
+        .into_iter()
+        .flatten()
+        .all(|ty| supports_btf(context, ty, visited))
+}
+
+/// Returns the operands of the metadata node `md`.
+///
+/// Operands that are null or aren't metadata nodes are returned as `None`.
+unsafe fn md_operands(
+    context: LLVMContextRef,
+    md: LLVMMetadataRef,
+) -> Vec<Option<LLVMMetadataRef>> {
+    use LLVMMetadataKind::*;
+
+    let value = LLVMMetadataAsValue(context, md);
+    let count = LLVMGetMDNodeNumOperands(value) as usize;
+    let mut operands = vec![ptr::null_mut(); count];
+    LLVMGetMDNodeOperands(value, operands.as_mut_ptr());
+    operands
+        .into_iter()
+        .map(|op| {
+            if op.is_null() || LLVMIsAMDNode(op).is_null() {
+                return None;
+            }
+            let md = LLVMValueAsMetadata(op);
+            match LLVMGetMetadataKind(md) {
+                LLVMMDStringMetadataKind
+                | LLVMConstantAsMetadataMetadataKind
+                | LLVMLocalAsMetadataMetadataKind
+                | LLVMDistinctMDOperandPlaceholderMetadataKind => None,
+                _ => Some(md),
+            }
+        })
+        .collect()
+}
+
+unsafe fn strip_function_debug_info(func: LLVMValueRef) {
+    LLVMSetSubprogram(func, ptr::null_mut());
+
+    let mut block = LLVMGetFirstBasicBlock(func);
+    while !block.is_null() {
+        let mut inst = LLVMGetFirstInstruction(block);
+        while !inst.is_null() {
+            let next = LLVMGetNextInstruction(inst);
+            if is_debug_intrinsic(inst) {
+                LLVMInstructionEraseFromParent(inst);
+            } else {
+                LLVMInstructionSetDebugLoc(inst, ptr::null_mut());
+            }
+            inst = next;
+        }
+        block = LLVMGetNextBasicBlock(block);
+    }
+}
+
+unsafe fn is_debug_intrinsic(inst: LLVMValueRef) -> bool {
+    if LLVMIsACallInst(inst).is_null() {
+        return false;
+    }
+    let callee = LLVMGetCalledValue(inst);
+    if callee.is_null() || LLVMIsAFunction(callee).is_null() {
+        return false;
+    }
+    let mut size: libc::size_t = 0;
+    let name = CStr::from_ptr(LLVMGetValueName2(callee, &mut size));
+    name.to_bytes().starts_with(b"llvm.dbg.")
+}
+
 unsafe fn create_target_machine() -> Result<LLVMTargetMachineRef> {
     let mut error = ptr::null_mut();
     let triple = CString::new("bpf").unwrap();
@@ -7,7 +7,7 @@
 
 use crate::CommandError;
 
-use futures::{future, stream::StreamExt};
+use futures::stream::StreamExt;
 use hexdump::hexdump;
 use redbpf::xdp;
 use redbpf::{load::Loader, Program::*};
@@ -24,7 +24,8 @@ pub fn load(
     let mut runtime = Runtime::new().unwrap();
     runtime.block_on(async {
         // Load all the programs and maps included in the program
-        let mut loader = Loader::load_file(&program).expect("error loading file");
+        let mut loader = Loader::load_file(&program)
+            .map_err(|e| CommandError(format!("failed to load {:?}: {}", program, e)))?;
 
         // attach the programs
         for program in loader.module.programs.iter_mut() {
@@ -40,8 +41,21 @@ pub fn load(
                         }
                     };
                     prog.attach_xdp(&iface, xdp::Flags::default())
+                        .map(|_| Vec::new())
                 }
-                KProbe(prog) | KRetProbe(prog) => prog.attach_kprobe(&name, 0),
+                SocketFilter(prog) => {
+                    let iface = match interface {
+                        Some(i) => i,
+                        None => {
+                            return Err(CommandError(
+                                "socket filter found, but no interface specified".to_string(),
+                            ))
+                        }
+                    };
+                    // the socket stays open until the command exits
+                    prog.attach_socket_filter(iface).map(|_| Vec::new())
+                }
+                KProbe(prog) | KRetProbe(prog) => prog.attach().map(|link| vec![link]),
                 UProbe(prog) | URetProbe(prog) => {
                     let path = match uprobe_path {
                         Some(p) => p,
@@ -52,34 +66,62 @@ pub fn load(
                         }
                     };
                     prog.attach_uprobe(Some(&prog.name()), 0, path, pid)
+                        .map(|link| vec![link])
+                }
+                Usdt(prog) => {
+                    let path = match uprobe_path {
+                        Some(p) => p,
+                        None => {
+                            return Err(CommandError(
+                                "USDT program found, but no path specified".to_string(),
+                            ))
+                        }
+                    };
+                    prog.attach(path, pid)
+                }
+                TracePoint(prog) => prog.attach().map(|link| vec![link]),
+                RawTracePoint(prog) => prog.attach().map(|link| vec![link]),
+                FEntry(prog) => prog.attach().map(|link| vec![link]),
+                FExit(prog) => prog.attach().map(|link| vec![link]),
+                PerfEvent(_) => {
+                    return Err(CommandError(format!(
+                        "perf_event program {} can't be attached from the command line",
+                        name
+                    )))
                 }
-                _ => Ok(()),
             };
-            if let Err(e) = ret {
-                return Err(CommandError(format!(
-                    "failed to attach program {}: {:?}",
-                    name, e
-                )));
+            match ret {
+                Ok(links) => loader.links.extend(links),
+                Err(e) => {
+                    return Err(CommandError(format!(
+                        "failed to attach program {}: {}",
+                        name, e
+                    )))
+                }
             }
         }
 
         // dump all the generated events on stdout
-        tokio::spawn(async move {
-            while let Some((name, events)) = loader.events.next().await {
-                for event in events {
+        for name in loader
+            .perf_map_names()
+            .into_iter()
+            .chain(loader.ring_buf_names())
+        {
+            let mut events = loader.raw_events(&name).map_err(|e| {
+                CommandError(format!("failed to read the events of {}: {}", name, e))
+            })?;
+            tokio::spawn(async move {
+                while let Some(event) = events.next().await {
                     println!("-- Event: {} --", name);
                     hexdump(&event);
                 }
-            }
-
-            // If the program doesn't have any maps and therefore doesn't fire any events, we still
-            // need to keep `loader` alive here so that BPF programs are not dropped. The future
-            // below will never complete, meaning that the programs will keep running until Ctrl-C
-            future::pending::<()>().await;
-        });
+            });
+        }
 
-        // quit on SIGINT
+        // quit on SIGINT. `loader` is kept alive until then so that the BPF
+        // programs keep running even if they don't fire any events.
         let _ = signal::ctrl_c().await;
+        drop(loader);
         println!("exiting");
         Ok(())
     })
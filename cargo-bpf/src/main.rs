@@ -172,8 +172,11 @@ fn main() {
                     .subcommand(
                         SubCommand::with_name("bindgen")
                             .about("Generates rust bindings from C headers")
-                            .arg(Arg::with_name("HEADER").required(true).help(
-                                "The C header file to generate bindings for",
+                            .arg(Arg::with_name("BTF").long("btf").value_name("FILE").min_values(0).max_values(1).require_equals(true).help(
+                                "Takes the kernel types from the BTF of the running kernel or of FILE instead of the kernel headers. The header can include them from \"vmlinux.h\"",
+                            ))
+                            .arg(Arg::with_name("HEADER").required_unless("BTF").help(
+                                "The C header file to generate bindings for. With --btf, all the kernel types when not given",
                             ))
                             .arg(Arg::with_name("BINDGEN_ARGS").required(false).multiple(true).help(
                                 "Extra arguments passed to bindgen",
@@ -196,10 +199,10 @@ fn main() {
                                 "Binds XDP programs to the given interface"
                             ))
                             .arg(Arg::with_name("UPROBE_PATH").value_name("PATH").short("u").long("uprobe-path").help(
-                                "Attach uprobes to the given library/binary"
+                                "Attach uprobes and USDT probes to the given library/binary"
                             ))
                             .arg(Arg::with_name("PID").value_name("PID").short("p").long("pid").help(
-                                "Attach uprobes to the given PID"
+                                "Attach uprobes and USDT probes to the given PID"
                             ))
                             .arg(Arg::with_name("PROGRAM").required(true).help(
                                 "Loads the specified eBPF program and outputs all the events generated",
@@ -221,12 +224,21 @@ fn main() {
         }
     }
     if let Some(m) = matches.subcommand_matches("bindgen") {
-        let header = m.value_of("HEADER").map(PathBuf::from).unwrap();
+        let header = m.value_of("HEADER").map(PathBuf::from);
+        let btf = if m.is_present("BTF") {
+            Some(PathBuf::from(
+                m.value_of("BTF").unwrap_or(cargo_bpf::bindgen::VMLINUX_BTF),
+            ))
+        } else {
+            None
+        };
         let extra_args = m
             .values_of("BINDGEN_ARGS")
             .map(|i| i.collect())
             .unwrap_or_else(Vec::new);
-        if let Err(e) = cargo_bpf::bindgen::cmd_bindgen(&header, &extra_args[..]) {
+        if let Err(e) =
+            cargo_bpf::bindgen::cmd_bindgen(header.as_deref(), btf.as_deref(), &extra_args[..])
+        {
             clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
         }
     }
@@ -1,10 +1,8 @@
 use futures::stream::StreamExt;
 use libc::pid_t;
-use std::boxed::Box;
 use std::collections::HashMap;
 use std::env;
 use std::process;
-use std::ptr;
 use std::sync::{Arc, Mutex};
 use tokio;
 use tokio::runtime::Runtime;
@@ -23,9 +21,8 @@ struct AllocSize {
 
 type Acc = Arc<Mutex<HashMap<i32, AllocSize>>>;
 
-fn handle_malloc_event(acc: Acc, loaded: &Loaded, event: Box<[u8]>) {
+fn handle_malloc_event(acc: Acc, loaded: &Loaded, mev: MallocEvent) {
     let mut acc = acc.lock().unwrap();
-    let mev = unsafe { ptr::read(event.as_ptr() as *const MallocEvent) };
     if let Some(alloc_size) = acc.get_mut(&mev.stackid) {
         (*alloc_size).size += mev.size;
         (*alloc_size).count += 1;
@@ -45,16 +42,11 @@ fn handle_malloc_event(acc: Acc, loaded: &Loaded, event: Box<[u8]>) {
 }
 
 fn start_perf_event_handler(mut loaded: Loaded, acc: Acc) {
+    // MallocEvent only holds integers, any sample is a valid value
+    let mut events = unsafe { loaded.events::<MallocEvent>("malloc_event") }.unwrap();
     tokio::spawn(async move {
-        while let Some((name, events)) = loaded.events.next().await {
-            match name.as_str() {
-                "malloc_event" => {
-                    for event in events {
-                        handle_malloc_event(acc.clone(), &loaded, event);
-                    }
-                }
-                _ => {}
-            }
+        while let Some(event) = events.next().await {
+            handle_malloc_event(acc.clone(), &loaded, event);
         }
     });
 }
@@ -79,10 +71,14 @@ fn main() {
     let _ = Runtime::new().unwrap().block_on(async {
         let mut loaded = Loader::load(probe_code()).expect("error loading BPF program");
 
-        for prb in loaded.uprobes_mut() {
-            prb.attach_uprobe(Some(&prb.name()), 0, "libc", Some(pid))
-                .expect(&format!("error attaching uprobe program {}", prb.name()));
-        }
+        let links: Vec<_> = loaded
+            .uprobes_mut()
+            .map(|prb| {
+                prb.attach_uprobe(Some(&prb.name()), 0, "libc", Some(pid))
+                    .expect(&format!("error attaching uprobe program {}", prb.name()))
+            })
+            .collect();
+        loaded.links.extend(links);
         start_perf_event_handler(loaded, acc.clone());
 
         println!("Attaching to malloc in PID {}, Hit Ctrl-C to quit", pid);
@@ -4,7 +4,6 @@ use redbpf::load::{Loaded, Loader};
 use std::collections::HashMap;
 use std::env;
 use std::process;
-use std::ptr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio;
@@ -38,28 +37,24 @@ fn start_reporter(counts: Counts) {
 
 fn start_perf_event_handler(mut loaded: Loaded, counts: Counts) {
     let counts = counts.clone();
+    // VFSEvent only holds integers, any sample is a valid value
+    let mut events = unsafe { loaded.events::<VFSEvent>("pid") }.unwrap();
     tokio::spawn(async move {
-        while let Some((name, events)) = loaded.events.next().await {
-            for event in events {
-                match name.as_str() {
-                    "pid" => {
-                        let vev = unsafe { ptr::read(event.as_ptr() as *const VFSEvent) };
-                        let latency = vev.latency / 1000_0000;
-                        let range = if latency < 1 {
-                            UNDER_ONE
-                        } else if 1 <= latency && latency < 10 {
-                            ONE_TO_TEN
-                        } else if 10 <= latency && latency < 100 {
-                            TEN_TO_HUNDRED
-                        } else {
-                            OVER_HUNDRED
-                        };
-                        let mut counts = counts.lock().unwrap();
-                        *counts.get_mut(range).unwrap() += 1;
-                    }
-                    _ => panic!("unexpected event"),
-                }
-            }
+        // keep the probes attached while events are processed
+        let _loaded = loaded;
+        while let Some(vev) = events.next().await {
+            let latency = vev.latency / 1000_0000;
+            let range = if latency < 1 {
+                UNDER_ONE
+            } else if 1 <= latency && latency < 10 {
+                ONE_TO_TEN
+            } else if 10 <= latency && latency < 100 {
+                TEN_TO_HUNDRED
+            } else {
+                OVER_HUNDRED
+            };
+            let mut counts = counts.lock().unwrap();
+            *counts.get_mut(range).unwrap() += 1;
         }
     });
 }
@@ -82,10 +77,14 @@ fn main() {
     ));
     let _ = Runtime::new().unwrap().block_on(async {
         let mut loaded = Loader::load(probe_code()).expect("error loading BPF program");
-        for kp in loaded.kprobes_mut() {
-            kp.attach_kprobe(&kp.name(), 0)
-                .expect(&format!("error attaching kprobe program {}", kp.name()));
-        }
+        let links: Vec<_> = loaded
+            .kprobes_mut()
+            .map(|kp| {
+                kp.attach_kprobe(&kp.name(), 0)
+                    .expect(&format!("error attaching kprobe program {}", kp.name()))
+            })
+            .collect();
+        loaded.links.extend(links);
 
         start_perf_event_handler(loaded, counts.clone());
         start_reporter(counts.clone());
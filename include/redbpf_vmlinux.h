@@ -0,0 +1,24 @@
+/* Used instead of redbpf_helpers.h when the kernel types are taken from BTF */
+#include "vmlinux.h"
+#include "bpf_helpers.h"
+
+/* macros of the kernel headers, which aren't part of the BTF */
+#define ETH_ALEN 6
+#define ETH_HLEN 14
+#define ETH_ZLEN 60
+#define ETH_DATA_LEN 1500
+#define ETH_FRAME_LEN 1514
+
+#define ETH_P_ALL 0x0003
+#define ETH_P_IP 0x0800
+#define ETH_P_ARP 0x0806
+#define ETH_P_8021Q 0x8100
+#define ETH_P_IPV6 0x86DD
+#define ETH_P_8021AD 0x88A8
+
+#define AF_UNSPEC 0
+#define AF_UNIX 1
+#define AF_INET 2
+#define AF_INET6 10
+#define AF_NETLINK 16
+#define AF_PACKET 17
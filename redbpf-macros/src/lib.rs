@@ -51,12 +51,12 @@ use proc_macro::{Diagnostic, Level};
 use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
 use quote::quote;
 use std::str;
-use syn::parse::{Parse, ParseStream};
+use syn::parse::{Parse, ParseStream, Parser};
 use syn::punctuated::Punctuated;
 use syn::token::Comma;
 use syn::{
-    parse_macro_input, parse_quote, parse_str, Expr, ExprLit, File, ItemFn, ItemStatic, Lit, Meta,
-    Result,
+    parse_macro_input, parse_quote, parse_str, Expr, ExprLit, File, FnArg, ItemFn, ItemStatic, Lit,
+    MetaNameValue, PatType, Result,
 };
 
 fn inline_string_literal(e: &Expr) -> (TokenStream2, TokenStream2) {
@@ -165,6 +165,11 @@ pub fn impl_network_buffer_array(_: TokenStream) -> TokenStream {
 /// require strict naming conventions use `#[map(link_section = "foo")]`
 /// which place the map into a section called `foo`.
 ///
+/// Maps can be pinned to the BPF filesystem with `#[map(pin = "/sys/fs/bpf/foo")]`.
+/// When the program is loaded, the map pinned at that path is reused if there
+/// is one, otherwise the map is created and pinned there. This way the
+/// contents of the map survive restarts of the user-space program.
+///
 /// **NOTE:** The `#[map("foo")` (which uses link section `maps/foo`) has
 /// been deprecated in favor of `#[map]` or `#[map(link_section = "maps/foo")]`
 ///
@@ -180,26 +185,39 @@ pub fn impl_network_buffer_array(_: TokenStream) -> TokenStream {
 /// #[map(link_section = "dns_queries")]
 /// static mut queries: PerfMap<Query> = PerfMap::with_max_entries(1024);
 ///
+/// // Will be reused across restarts
+/// #[map(pin = "/sys/fs/bpf/totals")]
+/// static mut totals: HashMap<u32, u64> = HashMap::with_max_entries(1024);
+///
 /// struct Query {
 /// // ...
 /// }
 /// ```
 #[proc_macro_attribute]
 pub fn map(attrs: TokenStream, item: TokenStream) -> TokenStream {
-    let section_name = if attrs.is_empty() {
+    let ident = {
         let item = item.clone();
         let item = parse_macro_input!(item as ItemStatic);
-        format!("maps/{}", item.ident.to_string())
-    } else {
-        match syn::parse::<Meta>(attrs.clone()) {
-            // First try #[map(section_name = "..")]
-            Ok(Meta::NameValue(mnv)) => {
-                if !mnv.path.is_ident("link_section") {
-                    panic!("expected #[map(link_section = \"...\")]");
-                }
-                match mnv.lit {
-                    Lit::Str(lit_str) => lit_str.value(),
-                    _ => panic!("expected #[map(link_section = \"...\")]"),
+        item.ident
+    };
+    let mut section_name = None;
+    let mut pin = None;
+    if !attrs.is_empty() {
+        match Punctuated::<MetaNameValue, Comma>::parse_terminated.parse(attrs.clone()) {
+            // First try #[map(link_section = "..", pin = "..")]
+            Ok(args) => {
+                for arg in args {
+                    let value = match arg.lit {
+                        Lit::Str(lit_str) => lit_str.value(),
+                        _ => panic!("expected #[map(link_section = \"...\", pin = \"...\")]"),
+                    };
+                    if arg.path.is_ident("link_section") {
+                        section_name = Some(value);
+                    } else if arg.path.is_ident("pin") {
+                        pin = Some(value);
+                    } else {
+                        panic!("expected #[map(link_section = \"...\", pin = \"...\")]");
+                    }
                 }
             }
             // Fallback to deprecated #[map("..")]
@@ -210,20 +228,36 @@ pub fn map(attrs: TokenStream, item: TokenStream) -> TokenStream {
                     #[cfg(RUSTC_IS_NIGHTLY)]
                     Diagnostic::new(Level::Warning, "`#[map(\"..\")` has been deprecated in favor of `#[map]` or `#[map(link_section = \"..\")]`")
                         .emit();
-                    format!("maps/{}", s.value())
+                    section_name = Some(format!("maps/{}", s.value()));
                 }
                 _ => panic!("expected #[map(\"...\")]"),
             },
         }
-    };
+    }
+    let section_name = section_name.unwrap_or_else(|| format!("maps/{}", ident.to_string()));
 
     let item = TokenStream2::from(item);
-    let tokens = quote! {
+    let mut tokens = quote! {
         #[no_mangle]
         #[link_section = #section_name]
         #item
     };
 
+    if let Some(path) = pin {
+        if !section_name.starts_with("maps/") {
+            panic!("pinned maps must be placed in a `maps/` section");
+        }
+        let pin_section = format!("map_pin/{}", &section_name["maps/".len()..]);
+        let pin_ident = Ident::new(&format!("_pin_{}", ident), Span::call_site());
+        let (pin_ty, pin_path) = inline_bytes(path.into_bytes());
+        tokens.extend(quote! {
+            #[no_mangle]
+            #[allow(non_upper_case_globals)]
+            #[link_section = #pin_section]
+            pub static #pin_ident: #pin_ty = #pin_path;
+        });
+    }
+
     tokens.into()
 }
 
@@ -261,6 +295,20 @@ fn wrap_kprobe(item: ItemFn) -> ItemFn {
     }
 }
 
+fn wrap_syscall(item: ItemFn) -> ItemFn {
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    parse_quote! {
+        fn #outer_ident(ctx: *mut c_void) -> i32 {
+            let regs = ::redbpf_probes::registers::SyscallRegisters::from(ctx);
+            let _ = #ident(regs);
+            return 0;
+
+            #item
+        }
+    }
+}
+
 /// Attribute macro that must be used to define [`kprobes`](https://www.kernel.org/doc/Documentation/kprobes.txt).
 ///
 /// # Example
@@ -299,6 +347,56 @@ pub fn kretprobe(attrs: TokenStream, item: TokenStream) -> TokenStream {
     probe_impl("kretprobe", attrs, wrapper, name)
 }
 
+/// Attribute macro that must be used to define probes on the entry of system
+/// calls.
+///
+/// The attribute takes the name of the system call, like `"execve"`. The
+/// kernel function implementing it depends on the architecture and is looked
+/// up when the program is attached. The program gets the arguments of the
+/// system call through
+/// [`SyscallRegisters`](../redbpf_probes/registers/struct.SyscallRegisters.html).
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// #[syscall("execve")]
+/// fn execve_enter(regs: SyscallRegisters) {
+///     // the first argument of execve() is the path of the program
+///     let filename = regs.parm1();
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn syscall(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let wrapper = wrap_syscall(item);
+    probe_impl("syscall", attrs, wrapper, name)
+}
+
+/// Attribute macro that must be used to define probes on the exit of system
+/// calls.
+///
+/// Works like [`syscall`](attr.syscall.html), but the program runs when the
+/// system call returns.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// #[syscall_ret("execve")]
+/// fn execve_exit(regs: SyscallRegisters) {
+///     let ret = regs.rc();
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn syscall_ret(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let wrapper = wrap_syscall(item);
+    probe_impl("syscall_ret", attrs, wrapper, name)
+}
+
 /// Attribute macro that must be used to define [`uprobes`](https://www.kernel.org/doc/Documentation/trace/uprobetracer.txt).
 ///
 /// # Example
@@ -337,6 +435,276 @@ pub fn uretprobe(attrs: TokenStream, item: TokenStream) -> TokenStream {
     probe_impl("uretprobe", attrs, wrapper, name)
 }
 
+/// Attribute macro that must be used to define [`USDT`](../redbpf_probes/usdt/index.html)
+/// programs.
+///
+/// The attribute takes the probe to attach to in the form `"provider:name"`,
+/// as found in the `.note.stapsdt` section of the binary. The program gets
+/// the arguments of the probe through
+/// [`UsdtContext`](../redbpf_probes/usdt/struct.UsdtContext.html).
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::usdt::prelude::*;
+///
+/// #[usdt("postgresql:query__start")]
+/// fn query_start(ctx: UsdtContext) {
+///     // the first argument is the query string
+///     let query = ctx.arg(0);
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn usdt(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = if attrs.is_empty() {
+        item.sig.ident.to_string()
+    } else {
+        match parse_macro_input!(attrs as Expr) {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(s), ..
+            }) => s.value(),
+            _ => panic!("expected string literal"),
+        }
+    };
+
+    // each program reads where the arguments of the location that fired are
+    // found from its own map, which redbpf fills in when attaching
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let specs_section = format!("maps/usdt/{}", name);
+    let wrapper = parse_quote! {
+        fn #outer_ident(ctx: *mut c_void) -> i32 {
+            #[link_section = #specs_section]
+            static mut SPECS: ::redbpf_probes::maps::HashMap<u64, ::redbpf_probes::usdt::UsdtSpec> =
+                ::redbpf_probes::maps::HashMap::with_max_entries(1024);
+
+            let ctx = ::redbpf_probes::usdt::UsdtContext::new(ctx, unsafe { &mut SPECS });
+            let _ = #ident(ctx);
+            return 0;
+
+            #item
+        }
+    };
+    probe_impl("usdt", TokenStream::new(), wrapper, name)
+}
+
+/// Attribute macro that must be used to define [`tracepoint`
+/// programs](https://www.kernel.org/doc/Documentation/trace/tracepoints.txt).
+///
+/// The attribute takes the tracepoint to attach to in the form
+/// `"category:name"`, as found in `/sys/kernel/debug/tracing/events`.
+///
+/// See also the [`tracepoint` API provided by
+/// `redbpf-probes`](https://ingraind.org/api/redbpf_probes/tracepoint/index.html).
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::tracepoint::prelude::*;
+///
+/// #[repr(C)]
+/// struct SchedProcessExec {
+///     filename: u32,
+///     pid: i32,
+///     old_pid: i32,
+/// }
+///
+/// #[tracepoint("sched:sched_process_exec")]
+/// fn sched_process_exec(ctx: TracePointContext<SchedProcessExec>) {
+///     // this is executed when a process calls exec()
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn tracepoint(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let name = match syn::parse::<Expr>(attrs.clone()) {
+        Ok(Expr::Lit(ExprLit {
+            lit: Lit::Str(s), ..
+        })) if s.value().contains(':') => s.value(),
+        _ => panic!("expected #[tracepoint(\"category:name\")]"),
+    };
+    let item = parse_macro_input!(item as ItemFn);
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let wrapper = parse_quote! {
+        fn #outer_ident(ctx: *mut c_void) -> i32 {
+            let ctx = ::redbpf_probes::tracepoint::TracePointContext::from(ctx);
+            let _ = #ident(ctx);
+            return 0;
+
+            #item
+        }
+    };
+    probe_impl("tracepoint", attrs, wrapper, name)
+}
+
+/// Attribute macro that must be used to define raw tracepoint programs.
+///
+/// The attribute takes the name of the tracepoint without its category, as
+/// in `#[raw_tracepoint("sched_switch")]`, and defaults to the name of the
+/// program. The program gets the arguments of the tracepoint through a
+/// `RawTracePointContext`.
+///
+/// See also the [`raw_tracepoint` API provided by
+/// `redbpf-probes`](https://ingraind.org/api/redbpf_probes/raw_tracepoint/index.html).
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::raw_tracepoint::prelude::*;
+///
+/// #[raw_tracepoint("sched_switch")]
+/// fn sched_switch(ctx: RawTracePointContext) {
+///     // this is executed on every context switch
+///     let next = ctx.arg(2);
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn raw_tracepoint(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let wrapper = parse_quote! {
+        fn #outer_ident(ctx: *mut c_void) -> i32 {
+            let ctx = ::redbpf_probes::raw_tracepoint::RawTracePointContext::from(ctx);
+            let _ = #ident(ctx);
+            return 0;
+
+            #item
+        }
+    };
+    probe_impl("raw_tracepoint", attrs, wrapper, name)
+}
+
+/// Attribute macro that must be used to define perf event programs.
+///
+/// The program runs every time the perf event it's attached to overflows,
+/// and gets the registers of the interrupted task through a
+/// `PerfEventContext`. Events are opened and the program attached from user
+/// space, for example with `redbpf::PerfEvent::attach_cpu_clock()`.
+///
+/// See also the [`perf_event` API provided by
+/// `redbpf-probes`](https://ingraind.org/api/redbpf_probes/perf_event/index.html).
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::perf_event::prelude::*;
+///
+/// #[perf_event]
+/// fn sample(ctx: PerfEventContext) {
+///     // this is executed every time the event overflows
+///     let ip = ctx.regs().ip();
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn perf_event(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let wrapper = parse_quote! {
+        fn #outer_ident(ctx: *mut c_void) -> i32 {
+            let ctx = ::redbpf_probes::perf_event::PerfEventContext::from(ctx);
+            let _ = #ident(ctx);
+            return 0;
+
+            #item
+        }
+    };
+    probe_impl("perf_event", attrs, wrapper, name)
+}
+
+fn wrap_tracing(item: ItemFn) -> ItemFn {
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    // the kernel passes the arguments, followed by the return value for
+    // fexit programs, as an array of u64
+    let args = item
+        .sig
+        .inputs
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| match arg {
+            FnArg::Typed(PatType { ty, .. }) => quote! { *(args.add(#i) as *const #ty) },
+            FnArg::Receiver(_) => panic!("unexpected self argument"),
+        });
+    parse_quote! {
+        fn #outer_ident(ctx: *mut c_void) -> i32 {
+            let args = ctx as *const u64;
+            let _ = unsafe { #ident(#(#args),*) };
+            return 0;
+
+            #item
+        }
+    }
+}
+
+/// Attribute macro that must be used to define `fentry` programs.
+///
+/// `fentry` programs run on entry to a kernel function, like `kprobes`, but
+/// are attached through BTF so they are a lot cheaper to run and receive the
+/// arguments of the function with their types. Requires a kernel built with
+/// `CONFIG_DEBUG_INFO_BTF`.
+///
+/// The attribute takes the name of the kernel function, which defaults to
+/// the name of the program. The program takes the arguments of the kernel
+/// function in the same order.
+///
+/// See also the [`fentry` API provided by
+/// `redbpf-probes`](https://ingraind.org/api/redbpf_probes/fentry/index.html).
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::fentry::prelude::*;
+///
+/// #[fentry("vfs_read")]
+/// fn vfs_read_enter(file: *const file, buf: *const c_char, count: size_t, pos: *const loff_t) {
+///     // this is executed when vfs_read() is invoked
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn fentry(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let wrapper = wrap_tracing(item);
+    probe_impl("fentry", attrs, wrapper, name)
+}
+
+/// Attribute macro that must be used to define `fexit` programs.
+///
+/// `fexit` programs run when a kernel function returns, like `kretprobes`,
+/// but are attached through BTF so they are a lot cheaper to run and receive
+/// both the arguments and the return value of the function with their types.
+/// Requires a kernel built with `CONFIG_DEBUG_INFO_BTF`.
+///
+/// The attribute takes the name of the kernel function, which defaults to
+/// the name of the program. The program takes the arguments of the kernel
+/// function in the same order, followed by its return value.
+///
+/// See also the [`fentry` API provided by
+/// `redbpf-probes`](https://ingraind.org/api/redbpf_probes/fentry/index.html).
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::fentry::prelude::*;
+///
+/// #[fexit("vfs_read")]
+/// fn vfs_read_exit(
+///     file: *const file,
+///     buf: *const c_char,
+///     count: size_t,
+///     pos: *const loff_t,
+///     ret: ssize_t,
+/// ) {
+///     // this is executed when vfs_read() returns
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn fexit(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let wrapper = wrap_tracing(item);
+    probe_impl("fexit", attrs, wrapper, name)
+}
+
 /// Attribute macro that must be used to define [`XDP` probes](https://www.iovisor.org/technology/xdp).
 ///
 /// See also the [`XDP` API provided by
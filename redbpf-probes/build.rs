@@ -6,16 +6,18 @@
 // copied, modified, or distributed except according to those terms.
 
 use quote::quote;
+use std::collections::HashMap;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use syn::visit::Visit;
 use syn::{
     self, parse_str, punctuated::Punctuated, token::Comma, AngleBracketedGenericArguments,
     ForeignItemStatic, GenericArgument, Ident, PathArguments::*, Type,
 };
 
+use bpf_sys::headers::prefix_kernel_headers;
 use cargo_bpf_lib::bindgen as bpf_bindgen;
 
 fn create_module(path: PathBuf, name: &str, bindings: &str) -> io::Result<()> {
@@ -46,6 +48,29 @@ fn rerun_if_changed_dir(dir: &str) {
         .for_each(|path| println!("cargo:rerun-if-changed={}", path.to_string_lossy()));
 }
 
+// The kernel types are taken from the headers, unless KERNEL_BTF names a BTF
+// file to take them from. The BTF of the running kernel is used when no
+// headers are installed.
+fn kernel_btf() -> Option<PathBuf> {
+    println!("cargo:rerun-if-env-changed=KERNEL_BTF");
+    if let Some(path) = env::var_os("KERNEL_BTF") {
+        return Some(path.into());
+    }
+
+    let vmlinux = PathBuf::from(bpf_bindgen::VMLINUX_BTF);
+    Some(vmlinux).filter(|path| prefix_kernel_headers(&[]).is_none() && path.exists())
+}
+
+fn bindings_builder(btf: &Option<PathBuf>, out_dir: &Path) -> bpf_bindgen::Builder {
+    if let Some(btf) = btf {
+        bpf_bindgen::btf_builder(btf, out_dir)
+            .expect("failed to read the kernel BTF")
+            .header("./include/redbpf_vmlinux.h")
+    } else {
+        bpf_bindgen::builder().header("./include/redbpf_helpers.h")
+    }
+}
+
 fn main() {
     rerun_if_changed_dir("include");
 
@@ -68,7 +93,8 @@ fn main() {
     ];
     let xdp_vars = ["ETH_.*", "IPPROTO_.*", "SOCK_.*", "SK_FL_.*", "AF_.*"];
 
-    let mut builder = bpf_bindgen::builder().header("./include/redbpf_helpers.h");
+    let btf = kernel_btf();
+    let mut builder = bindings_builder(&btf, &out_dir);
 
     for ty in types.iter().chain(xdp_types.iter()) {
         builder = builder.whitelist_type(ty);
@@ -101,17 +127,18 @@ fn main() {
     bindings.push_str(&accessors);
     create_module(out_dir.join("gen_bindings.rs"), "gen_bindings", &bindings).unwrap();
 
-    let bindings = bpf_bindgen::builder()
-        .header("./include/redbpf_helpers.h")
+    let bindings = bindings_builder(&btf, &out_dir)
         .whitelist_var("bpf_.*")
         .generate()
         .expect("Unable to generate bindings!");
-    let helpers = gen_helpers(&bindings.to_string());
+    let header = fs::read_to_string("./include/bpf_helper_defs.h").unwrap();
+    let helpers = gen_helpers(&bindings.to_string(), &header);
     create_module(out_dir.join("gen_helpers.rs"), "gen_helpers", &helpers).unwrap();
 }
 
 struct RewriteBpfHelpers {
     helpers: Vec<String>,
+    ids: HashMap<String, usize>,
 }
 
 impl Visit<'_> for RewriteBpfHelpers {
@@ -134,7 +161,10 @@ impl Visit<'_> for RewriteBpfHelpers {
                 }
                 .to_string();
                 ty_s = ty_s.replace("fn (", &format!("fn {} (", ident_str));
-                let call_idx = self.helpers.len() + 1;
+                let call_idx = *self
+                    .ids
+                    .get(&ident_str)
+                    .unwrap_or_else(|| panic!("no helper id found for {}", ident_str));
                 let args: Punctuated<Ident, Comma> = match fn_ty {
                     GenericArgument::Type(Type::BareFn(f)) => f
                         .inputs
@@ -161,10 +191,11 @@ impl Visit<'_> for RewriteBpfHelpers {
     }
 }
 
-fn gen_helpers(helpers: &str) -> String {
+fn gen_helpers(helpers: &str, header: &str) -> String {
     let tree: syn::File = parse_str(&helpers).unwrap();
     let mut tx = RewriteBpfHelpers {
         helpers: Vec::new(),
+        ids: bpf_bindgen::helper_ids(header),
     };
     tx.visit_file(&tree);
     let mut out = String::new();
@@ -0,0 +1,56 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+BTF tracing programs.
+
+`fentry` and `fexit` programs are hooks on the entry and exit of a kernel
+function, like kprobes and kretprobes. They are attached through the BTF id
+of the function, which the loader finds in `/sys/kernel/btf/vmlinux`, so they
+need a kernel built with `CONFIG_DEBUG_INFO_BTF`. In exchange they are a lot
+cheaper to run than kprobes and receive the arguments of the function with
+their types. `fexit` programs also receive the return value, after the
+arguments.
+
+# Example
+
+Measure how long `vfs_read` takes:
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::fentry::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map]
+static mut START: HashMap<u64, u64> = HashMap::with_max_entries(10240);
+
+#[fentry("vfs_read")]
+fn vfs_read_enter(file: *const file, buf: *const c_char, count: size_t, pos: *const loff_t) {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    unsafe { START.set(&pid_tgid, &bpf_ktime_get_ns()) };
+}
+
+#[fexit("vfs_read")]
+fn vfs_read_exit(
+    file: *const file,
+    buf: *const c_char,
+    count: size_t,
+    pos: *const loff_t,
+    ret: ssize_t,
+) {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    if let Some(start) = unsafe { START.get(&pid_tgid) } {
+        let latency = bpf_ktime_get_ns() - *start;
+        // do something with the latency and the bytes read
+        // ...
+    }
+}
+```
+ */
+pub mod prelude;
@@ -12,6 +12,13 @@ KProbes are hooks on the entry (kprobe) or exit (kretprobe) of a kernel function
 For an overview of KProbes and how they work, see
 <https://www.kernel.org/doc/Documentation/kprobes.txt>.
 
+System calls are implemented by kernel functions named differently on each
+architecture, like `__x64_sys_execve` on x86_64 and `__arm64_sys_execve` on
+aarch64. Probes defined with `#[syscall]` and `#[syscall_ret]` take the name
+of the system call instead, and the function is looked up when the program is
+attached. They get the arguments of the system call through
+[`SyscallRegisters`](../registers/struct.SyscallRegisters.html).
+
 # Example
 
 Do something when `execve` is called.
@@ -23,9 +30,10 @@ use redbpf_probes::kprobe::prelude::*;
 
 program!(0xFFFFFFFE, "GPL");
 
-#[kprobe("__arm64_sys_execve")]
-pub fn enter_execve(regs: Registers) {
-    // do something here
+#[syscall("execve")]
+pub fn enter_execve(regs: SyscallRegisters) {
+    // do something with the path of the program
+    let filename = regs.parm1();
     // ...
 }
 ```
@@ -14,7 +14,7 @@
 //! use redbpf_probes::kprobe::prelude::*;
 //! ```
 pub use cty::*;
-pub use redbpf_macros::{kprobe, kretprobe, map, program};
+pub use redbpf_macros::{kprobe, kretprobe, map, program, syscall, syscall_ret};
 pub use crate::bindings::*;
 pub use crate::helpers::*;
 pub use crate::maps::*;
@@ -15,94 +15,226 @@ kernel and user-space code.
 use core::convert::TryInto;
 use core::default::Default;
 use core::marker::PhantomData;
-use core::mem;
+use core::mem::{self, MaybeUninit};
+use core::ops::{Deref, DerefMut};
 use cty::*;
 
 use crate::bindings::*;
 use crate::helpers::*;
 
-/// Hash table map.
-///
-/// High level API for BPF_MAP_TYPE_HASH maps.
-#[repr(transparent)]
-pub struct HashMap<K, V> {
-    def: bpf_map_def,
-    _k: PhantomData<K>,
-    _v: PhantomData<V>,
-}
-
-impl<K, V> HashMap<K, V> {
-    /// Creates a map with the specified maximum number of elements.
-    pub const fn with_max_entries(max_entries: u32) -> Self {
-        Self {
-            def: bpf_map_def {
-                type_: bpf_map_type_BPF_MAP_TYPE_HASH,
-                key_size: mem::size_of::<K>() as u32,
-                value_size: mem::size_of::<V>() as u32,
-                max_entries,
-                map_flags: 0,
-            },
-            _k: PhantomData,
-            _v: PhantomData,
+macro_rules! define_hashmap {
+    ($(#[$attr:meta])* $name:ident, $map_type:expr) => {
+        $(#[$attr])*
+        #[repr(C)]
+        pub struct $name<K, V> {
+            def: bpf_map_def,
+            // zero-sized, they record the key and value types in the BTF
+            _k: [K; 0],
+            _v: [V; 0],
         }
-    }
 
-    /// Returns a reference to the value corresponding to the key.
-    #[inline]
-    pub fn get(&mut self, key: &K) -> Option<&V> {
-        unsafe {
-            let value = bpf_map_lookup_elem(
-                &mut self.def as *mut _ as *mut c_void,
-                key as *const _ as *const c_void,
-            );
-            if value.is_null() {
-                None
-            } else {
-                Some(&*(value as *const V))
+        impl<K, V> $name<K, V> {
+            /// Creates a map with the specified maximum number of elements.
+            pub const fn with_max_entries(max_entries: u32) -> Self {
+                Self {
+                    def: bpf_map_def {
+                        type_: $map_type,
+                        key_size: mem::size_of::<K>() as u32,
+                        value_size: mem::size_of::<V>() as u32,
+                        max_entries,
+                        map_flags: 0,
+                    },
+                    _k: [],
+                    _v: [],
+                }
             }
-        }
-    }
 
-    #[inline]
-    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        unsafe {
-            let value = bpf_map_lookup_elem(
-                &mut self.def as *mut _ as *mut c_void,
-                key as *const _ as *const c_void,
-            );
-            if value.is_null() {
-                None
-            } else {
-                Some(&mut *(value as *mut V))
+            /// Returns a reference to the value corresponding to the key.
+            #[inline]
+            pub fn get(&mut self, key: &K) -> Option<&V> {
+                unsafe {
+                    let value = bpf_map_lookup_elem(
+                        &mut self.def as *mut _ as *mut c_void,
+                        key as *const _ as *const c_void,
+                    );
+                    if value.is_null() {
+                        None
+                    } else {
+                        Some(&*(value as *const V))
+                    }
+                }
+            }
+
+            #[inline]
+            pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+                unsafe {
+                    let value = bpf_map_lookup_elem(
+                        &mut self.def as *mut _ as *mut c_void,
+                        key as *const _ as *const c_void,
+                    );
+                    if value.is_null() {
+                        None
+                    } else {
+                        Some(&mut *(value as *mut V))
+                    }
+                }
+            }
+
+            /// Set the `value` in the map for `key`
+            #[inline]
+            pub fn set(&mut self, key: &K, value: &V) {
+                unsafe {
+                    bpf_map_update_elem(
+                        &mut self.def as *mut _ as *mut c_void,
+                        key as *const _ as *const c_void,
+                        value as *const _ as *const c_void,
+                        BPF_ANY.into(),
+                    );
+                }
+            }
+
+            /// Delete the entry indexed by `key`
+            #[inline]
+            pub fn delete(&mut self, key: &K) {
+                unsafe {
+                    bpf_map_delete_elem(
+                        &mut self.def as *mut _ as *mut c_void,
+                        key as *const _ as *const c_void,
+                    );
+                }
             }
         }
-    }
+    };
+}
 
-    /// Set the `value` in the map for `key`
-    #[inline]
-    pub fn set(&mut self, key: &K, value: &V) {
-        unsafe {
-            bpf_map_update_elem(
-                &mut self.def as *mut _ as *mut c_void,
-                key as *const _ as *const c_void,
-                value as *const _ as *const c_void,
-                BPF_ANY.into(),
-            );
+define_hashmap!(
+    /// Hash table map.
+    ///
+    /// High level API for BPF_MAP_TYPE_HASH maps.
+    HashMap,
+    bpf_map_type_BPF_MAP_TYPE_HASH
+);
+
+define_hashmap!(
+    /// Per-CPU hash table map.
+    ///
+    /// High level API for BPF_MAP_TYPE_PERCPU_HASH maps. Each CPU has its own
+    /// copy of the values, and programs only access the copy of the CPU they
+    /// run on, so values can be updated without racing with other CPUs.
+    /// User-space reads the values of all the CPUs at once.
+    PerCpuHashMap,
+    bpf_map_type_BPF_MAP_TYPE_PERCPU_HASH
+);
+
+define_hashmap!(
+    /// LRU hash table map.
+    ///
+    /// High level API for BPF_MAP_TYPE_LRU_HASH maps. When the map is full,
+    /// inserting a new entry evicts the least recently used entries instead
+    /// of failing.
+    LruHashMap,
+    bpf_map_type_BPF_MAP_TYPE_LRU_HASH
+);
+
+define_hashmap!(
+    /// LRU per-CPU hash table map.
+    ///
+    /// High level API for BPF_MAP_TYPE_LRU_PERCPU_HASH maps. Like
+    /// `PerCpuHashMap`, but evicts the least recently used entries when the
+    /// map is full.
+    LruPerCpuHashMap,
+    bpf_map_type_BPF_MAP_TYPE_LRU_PERCPU_HASH
+);
+
+macro_rules! define_array {
+    ($(#[$attr:meta])* $name:ident, $map_type:expr) => {
+        $(#[$attr])*
+        #[repr(C)]
+        pub struct $name<T> {
+            def: bpf_map_def,
+            // zero-sized, they record the key and value types in the BTF
+            _k: [u32; 0],
+            _v: [T; 0],
         }
-    }
 
-    /// Delete the entry indexed by `key`
-    #[inline]
-    pub fn delete(&mut self, key: &K) {
-        unsafe {
-            bpf_map_delete_elem(
-                &mut self.def as *mut _ as *mut c_void,
-                key as *const _ as *const c_void,
-            );
+        impl<T> $name<T> {
+            /// Creates an array with the specified number of elements.
+            pub const fn with_max_entries(max_entries: u32) -> Self {
+                Self {
+                    def: bpf_map_def {
+                        type_: $map_type,
+                        key_size: mem::size_of::<u32>() as u32,
+                        value_size: mem::size_of::<T>() as u32,
+                        max_entries,
+                        map_flags: 0,
+                    },
+                    _k: [],
+                    _v: [],
+                }
+            }
+
+            /// Returns a reference to the element at `index`.
+            ///
+            /// Returns `None` if `index` is out of bounds.
+            #[inline]
+            pub fn get(&mut self, index: u32) -> Option<&T> {
+                self.get_mut(index).map(|value| &*value)
+            }
+
+            /// Returns a mutable reference to the element at `index`.
+            ///
+            /// Returns `None` if `index` is out of bounds.
+            #[inline]
+            pub fn get_mut(&mut self, index: u32) -> Option<&mut T> {
+                unsafe {
+                    let value = bpf_map_lookup_elem(
+                        &mut self.def as *mut _ as *mut c_void,
+                        &index as *const _ as *const c_void,
+                    );
+                    if value.is_null() {
+                        None
+                    } else {
+                        Some(&mut *(value as *mut T))
+                    }
+                }
+            }
+
+            /// Set the element at `index` to `value`.
+            #[inline]
+            pub fn set(&mut self, index: u32, value: &T) {
+                unsafe {
+                    bpf_map_update_elem(
+                        &mut self.def as *mut _ as *mut c_void,
+                        &index as *const _ as *const c_void,
+                        value as *const _ as *const c_void,
+                        BPF_ANY.into(),
+                    );
+                }
+            }
         }
-    }
+    };
 }
 
+define_array!(
+    /// Array map.
+    ///
+    /// High level API for BPF_MAP_TYPE_ARRAY maps. The elements of the array are
+    /// preallocated and zero-initialized, and can't be deleted.
+    Array,
+    bpf_map_type_BPF_MAP_TYPE_ARRAY
+);
+
+define_array!(
+    /// Per-CPU array map.
+    ///
+    /// High level API for BPF_MAP_TYPE_PERCPU_ARRAY maps. Each CPU has its own
+    /// copy of the array, and programs only access the copy of the CPU they run
+    /// on. Since programs can't be preempted, this makes per-CPU arrays a good
+    /// place for scratch space larger than the 512 bytes of the BPF stack.
+    PerCpuArray,
+    bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY
+);
+
 /// Flags that can be passed to `PerfMap::insert_with_flags`.
 #[derive(Debug, Copy, Clone)]
 pub struct PerfMapFlags {
@@ -212,6 +344,185 @@ impl<T> PerfMap<T> {
     }
 }
 
+// Not defined by kernel headers older than 5.8.
+const BPF_MAP_TYPE_RINGBUF: u32 = 27;
+
+/// Don't notify user-space of the new data. See `RingBuf::output_with_flags`.
+pub const BPF_RB_NO_WAKEUP: u64 = 1;
+/// Always notify user-space of the new data. See `RingBuf::output_with_flags`.
+pub const BPF_RB_FORCE_WAKEUP: u64 = 2;
+
+/// Ring buffer map.
+///
+/// A buffer shared by all the CPUs that allows eBPF programs to send events
+/// to user-space in the order they were produced. This is a wrapper for
+/// `BPF_MAP_TYPE_RINGBUF`, which requires Linux 5.8 or newer.
+///
+/// Events can either be copied into the buffer with `output`, or written in
+/// place with `reserve`.
+///
+/// To read the events from user-space, see
+/// [`redbpf::RingBufReader`](../../redbpf/struct.RingBufReader.html).
+#[repr(transparent)]
+pub struct RingBuf<T> {
+    def: bpf_map_def,
+    _event: PhantomData<T>,
+}
+
+impl<T> RingBuf<T> {
+    /// Creates a ring buffer of `size` bytes.
+    ///
+    /// `size` must be a power of two and a multiple of the page size.
+    pub const fn with_size(size: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: BPF_MAP_TYPE_RINGBUF,
+                key_size: 0,
+                value_size: 0,
+                max_entries: size,
+                map_flags: 0,
+            },
+            _event: PhantomData,
+        }
+    }
+
+    /// Copy `data` into the ring buffer.
+    ///
+    /// Fails if there isn't enough space left in the buffer.
+    #[inline]
+    pub fn output(&mut self, data: &T) -> Result<(), i32> {
+        self.output_with_flags(data, 0)
+    }
+
+    /// Copy `data` into the ring buffer.
+    ///
+    /// `flags` can be `BPF_RB_NO_WAKEUP` or `BPF_RB_FORCE_WAKEUP` to control
+    /// whether user-space is notified of the new data.
+    #[inline]
+    pub fn output_with_flags(&mut self, data: &T, flags: u64) -> Result<(), i32> {
+        let ret = unsafe {
+            bpf_ringbuf_output(
+                &mut self.def as *mut _ as *mut c_void,
+                data as *const _ as *mut c_void,
+                mem::size_of::<T>() as u64,
+                flags,
+            )
+        };
+        if ret < 0 {
+            return Err(ret);
+        }
+
+        Ok(())
+    }
+
+    /// Reserve space for an event in the ring buffer.
+    ///
+    /// The event can be written in place through the returned entry, which
+    /// must then be submitted with `RingBufEntry::submit`. Entries that are
+    /// dropped without being submitted are discarded.
+    ///
+    /// Returns `None` if there isn't enough space left in the buffer.
+    #[inline]
+    pub fn reserve(&mut self) -> Option<RingBufEntry<'_, T>> {
+        let data = unsafe {
+            bpf_ringbuf_reserve(
+                &mut self.def as *mut _ as *mut c_void,
+                mem::size_of::<T>() as u64,
+                0,
+            )
+        };
+        if data.is_null() {
+            return None;
+        }
+
+        Some(RingBufEntry {
+            data: unsafe { &mut *(data as *mut MaybeUninit<T>) },
+        })
+    }
+}
+
+/// An event reserved in a `RingBuf`.
+///
+/// The contents of the entry are not initialized when the entry is reserved,
+/// so the entry dereferences to a `MaybeUninit<T>`. The event can be written
+/// as a whole with `write`, or field by field through `as_mut_ptr`.
+pub struct RingBufEntry<'a, T> {
+    data: &'a mut MaybeUninit<T>,
+}
+
+impl<T> RingBufEntry<'_, T> {
+    /// Write `value` to the entry.
+    #[inline]
+    pub fn write(&mut self, value: T) {
+        unsafe { self.data.as_mut_ptr().write(value) }
+    }
+
+    /// Returns a pointer to the event in the ring buffer.
+    #[inline]
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self.data.as_mut_ptr()
+    }
+
+    /// Make the event available to user-space.
+    #[inline]
+    pub fn submit(self) {
+        self.submit_with_flags(0)
+    }
+
+    /// Make the event available to user-space.
+    ///
+    /// See `RingBuf::output_with_flags` for the meaning of `flags`.
+    #[inline]
+    pub fn submit_with_flags(self, flags: u64) {
+        unsafe { bpf_ringbuf_submit(self.as_ptr(), flags) };
+        mem::forget(self);
+    }
+
+    /// Release the space reserved for the event without sending it.
+    #[inline]
+    pub fn discard(self) {
+        self.discard_with_flags(0)
+    }
+
+    /// Release the space reserved for the event without sending it.
+    ///
+    /// See `RingBuf::output_with_flags` for the meaning of `flags`.
+    #[inline]
+    pub fn discard_with_flags(self, flags: u64) {
+        unsafe { bpf_ringbuf_discard(self.as_ptr(), flags) };
+        mem::forget(self);
+    }
+
+    #[inline]
+    fn as_ptr(&self) -> *mut c_void {
+        self.data.as_ptr() as *mut c_void
+    }
+}
+
+impl<T> Deref for RingBufEntry<'_, T> {
+    type Target = MaybeUninit<T>;
+
+    #[inline]
+    fn deref(&self) -> &MaybeUninit<T> {
+        self.data
+    }
+}
+
+impl<T> DerefMut for RingBufEntry<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut MaybeUninit<T> {
+        self.data
+    }
+}
+
+impl<T> Drop for RingBufEntry<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        // the verifier rejects programs that don't release reserved entries
+        unsafe { bpf_ringbuf_discard(self.as_ptr(), 0) };
+    }
+}
+
 // TODO Use PERF_MAX_STACK_DEPTH
 const BPF_MAX_STACK_DEPTH: usize = 127;
 
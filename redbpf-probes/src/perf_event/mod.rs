@@ -0,0 +1,97 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+Perf event programs.
+
+Perf event programs run every time a perf event overflows, for example on
+every tick of a CPU clock sampling at a given frequency. They get the
+registers of the task that was interrupted, so they can record what the
+CPUs are running. Together with a `StackTrace` map this is the basis of a
+sampling profiler.
+
+The events are opened and the programs attached from user space, see
+`redbpf::PerfEvent`.
+
+# Example
+
+Count the stacks the CPUs are sampled in:
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::perf_event::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map]
+static mut STACKS: StackTrace = StackTrace::with_max_entries(10240);
+
+#[map]
+static mut COUNTS: HashMap<i32, u64> = HashMap::with_max_entries(10240);
+
+#[perf_event]
+fn sample(ctx: PerfEventContext) {
+    let stack_id = match unsafe { STACKS.stack_id(ctx.regs().ctx, 0) } {
+        Ok(id) => id,
+        Err(_) => return,
+    };
+    unsafe {
+        let count = COUNTS.get(&stack_id).copied().unwrap_or(0);
+        COUNTS.set(&stack_id, &(count + 1));
+    }
+}
+```
+ */
+pub mod prelude;
+
+use crate::bindings::*;
+use crate::registers::Registers;
+use cty::*;
+
+/// Context object provided to perf event programs.
+#[derive(Clone, Copy)]
+pub struct PerfEventContext {
+    pub ctx: *mut bpf_perf_event_data,
+}
+
+impl From<*mut c_void> for PerfEventContext {
+    #[inline]
+    fn from(ctx: *mut c_void) -> PerfEventContext {
+        PerfEventContext {
+            ctx: ctx as *mut bpf_perf_event_data,
+        }
+    }
+}
+
+impl PerfEventContext {
+    /// Returns the raw context passed by the kernel.
+    #[inline]
+    pub fn inner(&self) -> *mut bpf_perf_event_data {
+        self.ctx
+    }
+
+    /// Returns the registers of the task the event interrupted.
+    #[inline]
+    pub fn regs(&self) -> Registers {
+        // the registers come first, and on aarch64 the user registers are
+        // the first member of `pt_regs`
+        Registers::from(self.ctx as *mut c_void)
+    }
+
+    /// Returns the sample period of the event.
+    #[inline]
+    pub fn sample_period(&self) -> u64 {
+        unsafe { (*self.ctx).sample_period }
+    }
+
+    /// Returns the address associated with the event, if any.
+    #[inline]
+    pub fn addr(&self) -> u64 {
+        unsafe { (*self.ctx).addr }
+    }
+}
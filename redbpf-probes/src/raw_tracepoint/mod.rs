@@ -0,0 +1,79 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+Raw kernel tracepoints.
+
+Raw tracepoints hook the same places as [tracepoints](../tracepoint/index.html),
+but the kernel doesn't copy the arguments into a record first. Programs get
+the arguments of the tracepoint as passed to its `TP_PROTO`, as an array of
+`u64`, which makes them a lot cheaper to run on hot paths like the scheduler.
+Pointer arguments point to kernel memory, so they must be read with
+`bpf_probe_read`.
+
+Raw tracepoints are named without their category, `sched_switch` rather than
+`sched:sched_switch`.
+
+# Example
+
+Count system calls by number:
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::raw_tracepoint::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map]
+static mut SYSCALLS: HashMap<u64, u64> = HashMap::with_max_entries(1024);
+
+// TP_PROTO(struct pt_regs *regs, long id)
+#[raw_tracepoint("sys_enter")]
+fn sys_enter(ctx: RawTracePointContext) {
+    let id = ctx.arg(1);
+    unsafe {
+        let count = SYSCALLS.get(&id).copied().unwrap_or(0);
+        SYSCALLS.set(&id, &(count + 1));
+    }
+}
+```
+ */
+pub mod prelude;
+
+use cty::*;
+
+/// Context object provided to raw tracepoint programs.
+#[derive(Clone, Copy)]
+pub struct RawTracePointContext {
+    pub ctx: *mut c_void,
+}
+
+impl From<*mut c_void> for RawTracePointContext {
+    #[inline]
+    fn from(ctx: *mut c_void) -> RawTracePointContext {
+        RawTracePointContext { ctx }
+    }
+}
+
+impl RawTracePointContext {
+    /// Returns the raw context passed by the kernel.
+    #[inline]
+    pub fn inner(&self) -> *mut c_void {
+        self.ctx
+    }
+
+    /// Returns the `n`th argument of the tracepoint.
+    ///
+    /// Arguments are numbered from 0 in the order of the `TP_PROTO` of the
+    /// tracepoint. Reading past the last argument is rejected by the
+    /// verifier.
+    #[inline]
+    pub fn arg(&self, n: usize) -> u64 {
+        unsafe { *(self.ctx as *const u64).add(n) }
+    }
+}
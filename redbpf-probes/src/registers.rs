@@ -8,6 +8,7 @@
 //! Utilities to work with registers in KProbes and UProbes
 
 use crate::bindings::*;
+use crate::helpers::bpf_probe_read;
 use cty::*;
 
 #[derive(Copy, Clone)]
@@ -102,6 +103,20 @@ impl Registers {
         }
     }
 
+    /// Sixth parameter to the function
+    #[inline]
+    pub fn parm6(&self) -> u64 {
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            (*self.ctx).r9
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            (*self.ctx).__bindgen_anon_1.user_regs.regs[5]
+        }
+    }
+
     /// Procedure link pointer (return to this IP)
     #[inline]
     pub fn ret(&self) -> u64 {
@@ -172,3 +187,144 @@ impl Registers {
         }
     }
 }
+
+/// Registers of `#[syscall]` and `#[syscall_ret]` probes
+///
+/// Since Linux 4.17 on x86_64 and 4.19 on aarch64, the kernel functions
+/// implementing system calls take a pointer to the registers of the calling
+/// process rather than the arguments of the call. `SyscallRegisters` follows
+/// that pointer, so the arguments can be read as the process passed them.
+/// On older kernels the arguments are read from the registers of the
+/// function, as the loader tells which convention the kernel follows.
+///
+/// The arguments are only available in `#[syscall]` probes, as the registers
+/// holding the pointer are overwritten by the time the system call returns.
+/// `#[syscall_ret]` probes can read the return value with
+/// [`rc`](SyscallRegisters::rc).
+#[derive(Copy, Clone)]
+pub struct SyscallRegisters {
+    regs: Registers,
+}
+
+impl From<*mut c_void> for SyscallRegisters {
+    #[inline]
+    fn from(ptr: *mut c_void) -> SyscallRegisters {
+        SyscallRegisters {
+            regs: Registers::from(ptr),
+        }
+    }
+}
+
+impl SyscallRegisters {
+    /// Registers of the kernel function implementing the system call
+    #[inline]
+    pub fn regs(&self) -> Registers {
+        self.regs
+    }
+
+    // Returns the registers of the calling process, or `None` if the kernel
+    // passes the arguments of system calls directly.
+    #[inline]
+    fn user_regs(&self) -> Option<*const pt_regs> {
+        // the loader relocates the address of this variable to 1 when system
+        // calls are implemented by wrappers, and to 2 when they aren't
+        #[link_section = "kernel/syscall_wrappers"]
+        static SYSCALL_WRAPPERS: u8 = 0;
+
+        if &SYSCALL_WRAPPERS as *const u8 as usize == 2 {
+            None
+        } else {
+            Some(self.regs.parm1() as *const pt_regs)
+        }
+    }
+
+    /// First argument of the system call
+    #[inline]
+    pub fn parm1(&self) -> Option<u64> {
+        let regs = match self.user_regs() {
+            Some(regs) => regs,
+            None => return Some(self.regs.parm1()),
+        };
+        #[cfg(target_arch = "x86_64")]
+        let reg = unsafe { &(*regs).di as *const u64 };
+        #[cfg(target_arch = "aarch64")]
+        let reg = unsafe { &(*regs).__bindgen_anon_1.user_regs.regs[0] as *const u64 };
+        unsafe { bpf_probe_read(reg) }.ok()
+    }
+
+    /// Second argument of the system call
+    #[inline]
+    pub fn parm2(&self) -> Option<u64> {
+        let regs = match self.user_regs() {
+            Some(regs) => regs,
+            None => return Some(self.regs.parm2()),
+        };
+        #[cfg(target_arch = "x86_64")]
+        let reg = unsafe { &(*regs).si as *const u64 };
+        #[cfg(target_arch = "aarch64")]
+        let reg = unsafe { &(*regs).__bindgen_anon_1.user_regs.regs[1] as *const u64 };
+        unsafe { bpf_probe_read(reg) }.ok()
+    }
+
+    /// Third argument of the system call
+    #[inline]
+    pub fn parm3(&self) -> Option<u64> {
+        let regs = match self.user_regs() {
+            Some(regs) => regs,
+            None => return Some(self.regs.parm3()),
+        };
+        #[cfg(target_arch = "x86_64")]
+        let reg = unsafe { &(*regs).dx as *const u64 };
+        #[cfg(target_arch = "aarch64")]
+        let reg = unsafe { &(*regs).__bindgen_anon_1.user_regs.regs[2] as *const u64 };
+        unsafe { bpf_probe_read(reg) }.ok()
+    }
+
+    /// Fourth argument of the system call
+    #[inline]
+    pub fn parm4(&self) -> Option<u64> {
+        let regs = match self.user_regs() {
+            Some(regs) => regs,
+            None => return Some(self.regs.parm4()),
+        };
+        #[cfg(target_arch = "x86_64")]
+        let reg = unsafe { &(*regs).r10 as *const u64 };
+        #[cfg(target_arch = "aarch64")]
+        let reg = unsafe { &(*regs).__bindgen_anon_1.user_regs.regs[3] as *const u64 };
+        unsafe { bpf_probe_read(reg) }.ok()
+    }
+
+    /// Fifth argument of the system call
+    #[inline]
+    pub fn parm5(&self) -> Option<u64> {
+        let regs = match self.user_regs() {
+            Some(regs) => regs,
+            None => return Some(self.regs.parm5()),
+        };
+        #[cfg(target_arch = "x86_64")]
+        let reg = unsafe { &(*regs).r8 as *const u64 };
+        #[cfg(target_arch = "aarch64")]
+        let reg = unsafe { &(*regs).__bindgen_anon_1.user_regs.regs[4] as *const u64 };
+        unsafe { bpf_probe_read(reg) }.ok()
+    }
+
+    /// Sixth argument of the system call
+    #[inline]
+    pub fn parm6(&self) -> Option<u64> {
+        let regs = match self.user_regs() {
+            Some(regs) => regs,
+            None => return Some(self.regs.parm6()),
+        };
+        #[cfg(target_arch = "x86_64")]
+        let reg = unsafe { &(*regs).r9 as *const u64 };
+        #[cfg(target_arch = "aarch64")]
+        let reg = unsafe { &(*regs).__bindgen_anon_1.user_regs.regs[5] as *const u64 };
+        unsafe { bpf_probe_read(reg) }.ok()
+    }
+
+    /// Return value of the system call
+    #[inline]
+    pub fn rc(&self) -> u64 {
+        self.regs.rc()
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+Kernel tracepoints.
+
+Tracepoints are static hooks placed in the kernel source. Unlike kprobes,
+they are part of the kernel's stable interface, so programs attached to
+them don't break when internal functions are renamed or inlined. The list
+of available tracepoints can be found in
+`/sys/kernel/debug/tracing/events/<category>/<name>`, and the `format` file
+inside each directory describes the fields of the tracepoint record.
+
+# Example
+
+Do something when a process calls `exec()`:
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::tracepoint::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+// see /sys/kernel/debug/tracing/events/sched/sched_process_exec/format
+#[repr(C)]
+pub struct SchedProcessExec {
+    filename: u32,
+    pid: i32,
+    old_pid: i32,
+}
+
+#[tracepoint("sched:sched_process_exec")]
+fn sched_process_exec(ctx: TracePointContext<SchedProcessExec>) {
+    let pid = ctx.args().pid;
+    // do something here
+    // ...
+}
+```
+ */
+pub mod prelude;
+
+use core::marker::PhantomData;
+use cty::*;
+
+/// The fields common to all tracepoint records.
+///
+/// These are the `common_*` fields listed at the top of every tracepoint
+/// `format` file.
+#[repr(C)]
+pub struct TracePointCommon {
+    pub common_type: u16,
+    pub common_flags: u8,
+    pub common_preempt_count: u8,
+    pub common_pid: i32,
+}
+
+#[repr(C)]
+struct TracePointRecord<T> {
+    common: TracePointCommon,
+    args: T,
+}
+
+/// Context object provided to tracepoint programs.
+///
+/// `T` must be a `#[repr(C)]` type matching the layout of the tracepoint
+/// specific fields, that is all the fields listed in the `format` file after
+/// the `common_*` ones.
+pub struct TracePointContext<T> {
+    pub ctx: *mut c_void,
+    _args: PhantomData<T>,
+}
+
+impl<T> Clone for TracePointContext<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for TracePointContext<T> {}
+
+impl<T> From<*mut c_void> for TracePointContext<T> {
+    #[inline]
+    fn from(ctx: *mut c_void) -> TracePointContext<T> {
+        TracePointContext {
+            ctx,
+            _args: PhantomData,
+        }
+    }
+}
+
+impl<T> TracePointContext<T> {
+    /// Returns the raw context passed by the kernel.
+    #[inline]
+    pub fn inner(&self) -> *mut c_void {
+        self.ctx
+    }
+
+    /// Returns the fields common to all tracepoints.
+    #[inline]
+    pub fn common(&self) -> &TracePointCommon {
+        unsafe { &(*(self.ctx as *const TracePointRecord<T>)).common }
+    }
+
+    /// Returns the tracepoint specific fields.
+    #[inline]
+    pub fn args(&self) -> &T {
+        unsafe { &(*(self.ctx as *const TracePointRecord<T>)).args }
+    }
+
+    /// Resolves a `__data_loc` field.
+    ///
+    /// Variable length fields such as strings are declared as `__data_loc` in
+    /// the `format` file. Their value encodes the offset of the data from the
+    /// start of the record in the lower 16 bits and its length in the upper 16
+    /// bits. This method returns a pointer to the data and its length.
+    #[inline]
+    pub fn data_loc(&self, loc: u32) -> (*const u8, usize) {
+        let offset = (loc & 0xFFFF) as usize;
+        let len = (loc >> 16) as usize;
+        unsafe { ((self.ctx as *const u8).add(offset), len) }
+    }
+}
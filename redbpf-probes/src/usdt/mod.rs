@@ -0,0 +1,170 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+User statically defined tracepoints.
+
+USDT probes are tracepoints compiled into user-space programs and libraries,
+usually through the `DTRACE_PROBE` macros of `<sys/sdt.h>`. Runtimes like
+postgres, python, node and the JVM define them at points of interest, like the
+start of a query or a function call, and they stay in the same place across
+versions unlike the internal symbols uprobes attach to.
+
+Probes are named `provider:name`, like `python:function__entry`, and their
+arguments are described in the `.note.stapsdt` section of the binary. When the
+program is attached, `redbpf` decodes where each argument is found, and
+[`UsdtContext::arg`](struct.UsdtContext.html#method.arg) reads it.
+
+# Example
+
+Count the functions called by python:
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::usdt::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map]
+static mut CALLS: HashMap<u64, u64> = HashMap::with_max_entries(10240);
+
+// the arguments are the file name, the function name and the line number
+#[usdt("python:function__entry")]
+fn function_entry(ctx: UsdtContext) {
+    let function = match ctx.arg(1) {
+        Some(function) => function,
+        None => return,
+    };
+    unsafe {
+        let count = CALLS.get(&function).copied().unwrap_or(0);
+        CALLS.set(&function, &(count + 1));
+    }
+}
+```
+ */
+pub mod prelude;
+
+use crate::helpers::bpf_probe_read;
+use crate::maps::HashMap;
+use crate::registers::Registers;
+use core::ptr;
+use cty::*;
+
+// The layout of the specs must match the one `redbpf` writes when attaching
+const USDT_MAX_ARGS: usize = 12;
+const USDT_ARG_CONSTANT: u32 = 0;
+const USDT_ARG_REGISTER: u32 = 1;
+const USDT_ARG_MEMORY: u32 = 2;
+
+#[doc(hidden)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct UsdtArgSpec {
+    value: u64,
+    kind: u32,
+    register: u16,
+    signed: u8,
+    shift: u8,
+}
+
+/// Where the arguments of a location of a USDT probe are found.
+///
+/// Each `#[usdt]` program has a map of specs, keyed by the address of the
+/// locations it's attached to, that's filled in by `redbpf`.
+#[doc(hidden)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct UsdtSpec {
+    args: [UsdtArgSpec; USDT_MAX_ARGS],
+    count: u16,
+}
+
+/// Context object provided to USDT programs.
+#[derive(Clone, Copy)]
+pub struct UsdtContext {
+    regs: Registers,
+    spec: *const UsdtSpec,
+}
+
+impl UsdtContext {
+    #[doc(hidden)]
+    #[inline]
+    pub fn new(ctx: *mut c_void, specs: &mut HashMap<u64, UsdtSpec>) -> UsdtContext {
+        let regs = Registers::from(ctx);
+        // locations whose address can't be known in advance are stored at 0
+        let spec = match specs.get(&regs.ip()) {
+            Some(spec) => spec as *const UsdtSpec,
+            None => specs
+                .get(&0)
+                .map_or(ptr::null(), |spec| spec as *const UsdtSpec),
+        };
+
+        UsdtContext { regs, spec }
+    }
+
+    /// Returns the raw context passed by the kernel.
+    #[inline]
+    pub fn inner(&self) -> *mut c_void {
+        self.regs.ctx as *mut c_void
+    }
+
+    /// Returns the registers of the process at the probe.
+    #[inline]
+    pub fn regs(&self) -> Registers {
+        self.regs
+    }
+
+    /// Returns the number of arguments of the probe.
+    #[inline]
+    pub fn arg_count(&self) -> usize {
+        unsafe { self.spec.as_ref() }.map_or(0, |spec| spec.count as usize)
+    }
+
+    /// Returns the `n`th argument of the probe.
+    ///
+    /// Arguments are numbered from 0. Each argument is read from the
+    /// constant, register or memory its spec points to, with the size given
+    /// by the spec. Signed arguments are sign extended, so they can be cast
+    /// to `i64`. Pointers point to memory of the traced process.
+    ///
+    /// Returns `None` if the probe has no `n`th argument, or if it can't be
+    /// read.
+    #[inline]
+    pub fn arg(&self, n: usize) -> Option<u64> {
+        let spec = unsafe { self.spec.as_ref() }?;
+        if n >= USDT_MAX_ARGS || n >= spec.count as usize {
+            return None;
+        }
+
+        let arg = &spec.args[n];
+        let value = match arg.kind {
+            USDT_ARG_CONSTANT => arg.value,
+            USDT_ARG_REGISTER => self.register(arg.register)?,
+            USDT_ARG_MEMORY => {
+                let address = self.register(arg.register)?.wrapping_add(arg.value);
+                unsafe { bpf_probe_read(address as *const u64) }.ok()?
+            }
+            _ => return None,
+        };
+
+        // values are read as 64 bit words, only the lower bytes hold the
+        // argument
+        let value = value << arg.shift;
+        Some(if arg.signed != 0 {
+            ((value as i64) >> arg.shift) as u64
+        } else {
+            value >> arg.shift
+        })
+    }
+
+    #[inline]
+    fn register(&self, offset: u16) -> Option<u64> {
+        let register = unsafe { (self.regs.ctx as *const u8).add(offset as usize) };
+        unsafe { bpf_probe_read(register as *const u64) }.ok()
+    }
+}
@@ -16,6 +16,8 @@ pub use cty::*;
 pub use redbpf_macros::{map, program, xdp};
 pub use crate::bindings::*;
 pub use crate::helpers::*;
-pub use crate::maps::{HashMap, PerfMapFlags};
+pub use crate::maps::{
+    Array, HashMap, LruHashMap, LruPerCpuHashMap, PerCpuArray, PerCpuHashMap, PerfMapFlags, RingBuf,
+};
 pub use crate::net::*;
 pub use crate::xdp::*;
\ No newline at end of file
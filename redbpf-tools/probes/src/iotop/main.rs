@@ -15,7 +15,7 @@ static mut start: HashMap<*const request, u64> = HashMap::with_max_entries(10240
 static mut processes: HashMap<*const request, Process> = HashMap::with_max_entries(10240);
 
 #[map("counts")]
-static mut counts: HashMap<CounterKey, Counter> = HashMap::with_max_entries(10240);
+static mut counts: PerCpuHashMap<CounterKey, Counter> = PerCpuHashMap::with_max_entries(10240);
 
 #[kprobe]
 fn blk_account_io_start(regs: Registers) {
@@ -17,7 +17,7 @@ const TCP_FLAG_SYN: u16 = 0x0002u16.to_be();
 static mut sequence: HashMap<u8, PortSequence> = HashMap::with_max_entries(1);
 
 #[map("knocks")]
-static mut knocks: HashMap<u32, Knock> = HashMap::with_max_entries(1024);
+static mut knocks: LruHashMap<u32, Knock> = LruHashMap::with_max_entries(1024);
 
 #[map("knock_attempts")]
 static mut knock_attempts: PerfMap<KnockAttempt> = PerfMap::with_max_entries(1024);
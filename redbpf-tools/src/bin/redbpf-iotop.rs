@@ -4,7 +4,7 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-use redbpf::{load::Loader, HashMap as BPFHashMap};
+use redbpf::{load::Loader, PerCpuHashMap};
 use std::collections::HashMap;
 use std::ffi::CStr;
 use std::fs::File;
@@ -31,15 +31,19 @@ fn main() {
         let mut loader = Loader::load(probe_code()).expect("error loading probe");
 
         // attach the kprobes
-        for kprobe in loader.kprobes_mut() {
-            kprobe
-                .attach_kprobe(&kprobe.name(), 0)
-                .expect(&format!("error attaching program {}", kprobe.name()));
-        }
+        let links: Vec<_> = loader
+            .kprobes_mut()
+            .map(|kprobe| {
+                kprobe
+                    .attach_kprobe(&kprobe.name(), 0)
+                    .expect(&format!("error attaching program {}", kprobe.name()))
+            })
+            .collect();
+        loader.links.extend(links);
 
         tokio::spawn(async move {
             let counts =
-                BPFHashMap::<CounterKey, Counter>::new(loader.map("counts").unwrap()).unwrap();
+                PerCpuHashMap::<CounterKey, Counter>::new(loader.map("counts").unwrap()).unwrap();
             let disks = parse_diskstats().unwrap();
 
             loop {
@@ -50,7 +54,22 @@ fn main() {
                     "PID", "COMM", "D", "MAJ", "MIN", "DISK", "I/O", "Kbytes", "AVGms"
                 );
 
-                let mut items: Vec<(CounterKey, Counter)> = counts.iter().collect();
+                let mut items: Vec<(CounterKey, Counter)> = counts
+                    .iter()
+                    .map(|(k, values)| {
+                        let zero = Counter {
+                            bytes: 0,
+                            us: 0,
+                            io: 0,
+                        };
+                        let v = values.merge(zero, |acc, v| Counter {
+                            bytes: acc.bytes + v.bytes,
+                            us: acc.us + v.us,
+                            io: acc.io + v.io,
+                        });
+                        (k, v)
+                    })
+                    .collect();
                 items.sort_unstable_by(|(_, av), (_, bv)| av.bytes.cmp(&bv.bytes));
 
                 for (k, v) in items.iter().rev() {
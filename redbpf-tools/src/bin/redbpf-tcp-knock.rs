@@ -10,7 +10,6 @@ use redbpf::{load::Loader, xdp, HashMap};
 use std::env;
 use std::net::Ipv4Addr;
 use std::process;
-use std::ptr;
 use tokio;
 use tokio::runtime::Runtime;
 use tokio::signal;
@@ -50,50 +49,50 @@ fn main() {
         let seq_map = HashMap::<u8, PortSequence>::new(loader.map("sequence").unwrap()).unwrap();
         seq_map.set(0u8, sequence);
 
+        // process perf events sent by the XDP program
+        // the events only hold integers, any sample is a valid value
+        let mut knocks = unsafe { loader.events::<KnockAttempt>("knock_attempts") }.unwrap();
         tokio::spawn(async move {
-            // process perf events sent by the XDP program
-            while let Some((name, events)) = loader.events.next().await {
-                for event in events {
-                    match name.as_str() {
-                        "knock_attempts" => {
-                            let knock = unsafe { ptr::read(event.as_ptr() as *const KnockAttempt) };
-                            let seq = &knock.sequence;
-                            println!(
-                                "Received knock from {} sequence {}",
-                                Ipv4Addr::from(knock.source_ip),
-                                seq.ports[..seq.len]
-                                    .iter()
-                                    .enumerate()
-                                    .map(|(i, port)| {
-                                        if i == seq.len - 1 {
-                                            format!("*{}", port)
-                                        } else {
-                                            format!("{}", port)
-                                        }
-                                    })
-                                    .collect::<Vec<String>>()
-                                    .join(" ")
-                            )
-                        }
-                        "connections" => {
-                            let conn = unsafe { ptr::read(event.as_ptr() as *const Connection) };
-                            println!(
-                                "{} access from {:?}",
-                                if conn.allowed == 1 {
-                                    "Allowed"
-                                } else {
-                                    "Blocked"
-                                },
-                                Ipv4Addr::from(conn.source_ip)
-                            );
-                        }
-                        _ => panic!("unexpected event"),
-                    }
-                }
+            while let Some(knock) = knocks.next().await {
+                let seq = &knock.sequence;
+                println!(
+                    "Received knock from {} sequence {}",
+                    Ipv4Addr::from(knock.source_ip),
+                    seq.ports[..seq.len]
+                        .iter()
+                        .enumerate()
+                        .map(|(i, port)| {
+                            if i == seq.len - 1 {
+                                format!("*{}", port)
+                            } else {
+                                format!("{}", port)
+                            }
+                        })
+                        .collect::<Vec<String>>()
+                        .join(" ")
+                )
             }
         });
 
-        signal::ctrl_c().await
+        let mut connections = unsafe { loader.events::<Connection>("connections") }.unwrap();
+        tokio::spawn(async move {
+            while let Some(conn) = connections.next().await {
+                println!(
+                    "{} access from {:?}",
+                    if conn.allowed == 1 {
+                        "Allowed"
+                    } else {
+                        "Blocked"
+                    },
+                    Ipv4Addr::from(conn.source_ip)
+                );
+            }
+        });
+
+        // `loader` is kept alive until here so that the XDP program stays attached
+        let ret = signal::ctrl_c().await;
+        drop(loader);
+        ret
     });
 }
 
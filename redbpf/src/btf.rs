@@ -0,0 +1,851 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # BTF
+//!
+//! cargo-bpf compiles probes with debug info, from which llvm generates the
+//! BTF type information of the programs and maps in the `.BTF` and `.BTF.ext`
+//! sections. The types are loaded in the kernel with the programs and maps,
+//! so that the kernel and `bpftool` can pretty print the maps and annotate
+//! the programs with their source lines.
+//!
+//! llvm generates BTF for C, so the object files need a few fixes before the
+//! kernel accepts them:
+//!
+//! * the sizes of the data sections and the offsets of their variables are
+//!   only known once the object is linked, and are left to the loader
+//! * Rust type names like `HashMap<u32, u64>` aren't valid C identifiers
+//! * Rust pointer types have names, which the kernel rejects
+//! * rustc doesn't record the names of function arguments
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::str;
+
+use bpf_sys::btf::{
+    self, BtfType, BTF_KIND_ARRAY, BTF_KIND_CONST, BTF_KIND_DATASEC, BTF_KIND_ENUM, BTF_KIND_FUNC,
+    BTF_KIND_FUNC_PROTO, BTF_KIND_INT, BTF_KIND_PTR, BTF_KIND_RESTRICT, BTF_KIND_STRUCT,
+    BTF_KIND_TYPEDEF, BTF_KIND_UNION, BTF_KIND_VAR, BTF_KIND_VOLATILE, BTF_MAGIC, BTF_TYPE_SIZE,
+};
+use bpf_sys::{bpf_insn, bpf_map_def, bpf_prog_type};
+use byteorder::{ByteOrder, NativeEndian};
+use libc::{c_int, getrlimit, rlimit, setrlimit, EPERM, RLIMIT_MEMLOCK, RLIM_INFINITY};
+
+use crate::fd::OwnedFd;
+use crate::link::bpf;
+use crate::{Error, Result};
+
+const BPF_MAP_CREATE: c_int = 0;
+const BPF_PROG_LOAD: c_int = 5;
+// not defined by the bcc headers bpf-sys is built with
+const BPF_BTF_LOAD: c_int = 18;
+
+const BPF_OBJ_NAME_LEN: usize = 16;
+
+const KERNEL_BTF_PATH: &str = "/sys/kernel/btf/vmlinux";
+
+lazy_static! {
+    static ref KERNEL_TYPES: Option<Types> = fs::read(KERNEL_BTF_PATH)
+        .ok()
+        .and_then(|btf| Types::parse(&btf).ok());
+}
+
+#[repr(C)]
+struct BtfLoadAttr {
+    btf: u64,
+    btf_log_buf: u64,
+    btf_size: u32,
+    btf_log_size: u32,
+    btf_log_level: u32,
+}
+
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+    inner_map_fd: u32,
+    numa_node: u32,
+    map_name: [u8; BPF_OBJ_NAME_LEN],
+    map_ifindex: u32,
+    btf_fd: u32,
+    btf_key_type_id: u32,
+    btf_value_type_id: u32,
+}
+
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; BPF_OBJ_NAME_LEN],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+    prog_btf_fd: u32,
+    func_info_rec_size: u32,
+    func_info: u64,
+    func_info_cnt: u32,
+    line_info_rec_size: u32,
+    line_info: u64,
+    line_info_cnt: u32,
+    attach_btf_id: u32,
+}
+
+/// The `.BTF.ext` records of one section, either func info or line info.
+#[derive(Debug, Clone)]
+struct ExtInfo {
+    rec_size: u32,
+    count: u32,
+    // the records, with their instruction offsets already converted from
+    // bytes to instructions
+    data: Vec<u8>,
+}
+
+/// The BTF types of a map.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MapBtf {
+    pub fd: RawFd,
+    pub key_type_id: u32,
+    pub value_type_id: u32,
+}
+
+/// The BTF func and line info of a program.
+pub(crate) struct ProgramBtf {
+    fd: OwnedFd,
+    func_info: ExtInfo,
+    line_info: Option<ExtInfo>,
+}
+
+/// The types and strings of a `.BTF` section, or of the kernel.
+pub(crate) struct Types {
+    types: Vec<BtfType>,
+    type_data: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+/// The BTF of a module.
+pub(crate) struct Btf {
+    // set once the types are loaded in the kernel
+    fd: Option<OwnedFd>,
+    types: Types,
+    func_info: HashMap<String, ExtInfo>,
+    line_info: HashMap<String, ExtInfo>,
+}
+
+impl Btf {
+    /// Parses the `.BTF` and `.BTF.ext` sections.
+    pub(crate) fn parse(btf: &[u8], ext: Option<&[u8]>) -> Result<Btf> {
+        let types = Types::parse(btf)?;
+        let (func_info, line_info) = match ext {
+            Some(ext) => types.parse_ext(ext)?,
+            None => (HashMap::new(), HashMap::new()),
+        };
+
+        Ok(Btf {
+            fd: None,
+            types,
+            func_info,
+            line_info,
+        })
+    }
+
+    /// Loads the types in the kernel.
+    ///
+    /// `section_sizes` are the sizes of the sections of the object file, and
+    /// `symbols` the offsets of its global variables in their sections.
+    pub(crate) fn load(
+        &mut self,
+        section_sizes: &HashMap<String, u32>,
+        symbols: &HashMap<String, u32>,
+    ) -> Result<()> {
+        self.fd = Some(load_btf(&self.types.fixup(section_sizes, symbols)?)?);
+        Ok(())
+    }
+
+    /// Returns the BTF of the map defined in `section`.
+    ///
+    /// The key and value types are recorded by the `_k` and `_v` zero sized
+    /// array fields of the map types of `redbpf-probes`.
+    pub(crate) fn map(&self, section: &str) -> Option<MapBtf> {
+        let types = &self.types;
+        let datasec = types
+            .types
+            .iter()
+            .find(|t| t.kind == BTF_KIND_DATASEC && types.name(t.name_off) == Some(section))?;
+        if datasec.vlen == 0 {
+            return None;
+        }
+        let var = types.get(types.u32_at(datasec.offset + BTF_TYPE_SIZE).ok()?)?;
+        if var.kind != BTF_KIND_VAR {
+            return None;
+        }
+        let def = types.get(types.resolve(var.size_or_type)?)?;
+        if def.kind != BTF_KIND_STRUCT {
+            return None;
+        }
+
+        let mut key_type_id = None;
+        let mut value_type_id = None;
+        for i in 0..def.vlen {
+            let member = def.offset + BTF_TYPE_SIZE + i * 12;
+            let array = types.get(types.u32_at(member + 4).ok()?)?;
+            if array.kind != BTF_KIND_ARRAY {
+                continue;
+            }
+            let elem_type = types.u32_at(array.offset + BTF_TYPE_SIZE).ok()?;
+            match types.name(types.u32_at(member).ok()?) {
+                Some("_k") => key_type_id = Some(elem_type),
+                Some("_v") => value_type_id = Some(elem_type),
+                _ => (),
+            }
+        }
+
+        Some(MapBtf {
+            fd: self.fd.as_ref()?.as_raw_fd(),
+            key_type_id: key_type_id?,
+            value_type_id: value_type_id?,
+        })
+    }
+
+    /// Returns the func and line info of the program defined in `section`.
+    pub(crate) fn program(&self, section: &str) -> Result<Option<ProgramBtf>> {
+        let (fd, func_info) = match (&self.fd, self.func_info.get(section)) {
+            (Some(fd), Some(info)) => (fd, info.clone()),
+            _ => return Ok(None),
+        };
+        let fd = fd.try_clone().map_err(|error| Error::Program {
+            op: "clone BTF",
+            program: section.to_string(),
+            error,
+        })?;
+
+        Ok(Some(ProgramBtf {
+            fd,
+            func_info,
+            line_info: self.line_info.get(section).cloned(),
+        }))
+    }
+}
+
+type ExtInfos = HashMap<String, ExtInfo>;
+
+impl Types {
+    fn parse(btf: &[u8]) -> Result<Types> {
+        let (type_data, strings) = btf::sections(btf).map_err(Error::Btf)?;
+        Ok(Types {
+            types: btf::parse_types(type_data).map_err(Error::Btf)?,
+            type_data: type_data.to_vec(),
+            strings: strings.to_vec(),
+        })
+    }
+
+    /// Returns the types of the running kernel, if it was built with BTF.
+    ///
+    /// They're parsed once and shared by all the modules loaded afterwards.
+    pub(crate) fn kernel() -> Option<&'static Types> {
+        KERNEL_TYPES.as_ref()
+    }
+
+    /// Returns the byte offset of a field in the layout of these types.
+    ///
+    /// `access` is the name of a struct or union followed by the fields to
+    /// go through to get to the field, separated by dots, like
+    /// `sock.__sk_common.skc_family`. Fields are matched by name. The
+    /// `__bindgen_anon_N` fields of bindgen correspond to the N-th anonymous
+    /// member, and fields that aren't found are also looked for in the
+    /// anonymous members, where they can move between kernel versions.
+    /// Bitfields can't be read as a whole field, so they aren't found.
+    pub(crate) fn field_offset(&self, access: &str) -> Result<u32> {
+        let mut path = access.split('.');
+        let type_name = path.next().unwrap_or("");
+        let fields = path.collect::<Vec<_>>();
+
+        // kernels can define several types with the same name
+        self.types
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| {
+                (t.kind == BTF_KIND_STRUCT || t.kind == BTF_KIND_UNION)
+                    && self.name(t.name_off) == Some(type_name)
+            })
+            .find_map(|(i, _)| self.member_offset(i as u32 + 1, &fields))
+            .map(|bits| bits / 8)
+            .ok_or_else(|| {
+                Error::Reloc(format!("field `{}' not found in BTF or a bitfield", access))
+            })
+    }
+
+    /// Returns the id of the function `name`.
+    pub(crate) fn func_id(&self, name: &str) -> Option<u32> {
+        self.types
+            .iter()
+            .position(|t| t.kind == BTF_KIND_FUNC && self.name(t.name_off) == Some(name))
+            .map(|i| i as u32 + 1)
+    }
+
+    // Returns the offset in bits of the field at `path` inside the type `id`.
+    fn member_offset(&self, id: u32, path: &[&str]) -> Option<u32> {
+        let (field, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return Some(0),
+        };
+        let (offset, member_type, bitfield) = self.find_member(self.resolve(id)?, field)?;
+        if bitfield {
+            return None;
+        }
+        Some(offset + self.member_offset(member_type, rest)?)
+    }
+
+    // Returns the offset in bits and the type of the member `name` of the
+    // struct or union `id`, and whether it's a bitfield.
+    fn find_member(&self, id: u32, name: &str) -> Option<(u32, u32, bool)> {
+        let ty = self.get(id)?;
+        if ty.kind != BTF_KIND_STRUCT && ty.kind != BTF_KIND_UNION {
+            return None;
+        }
+
+        let anon_index = name
+            .strip_prefix("__bindgen_anon_")
+            .and_then(|n| n.parse::<usize>().ok());
+        let mut anon_count = 0;
+        let mut anon_members = Vec::new();
+        for i in 0..ty.vlen {
+            let member = ty.offset + BTF_TYPE_SIZE + i * 12;
+            let name_off = self.u32_at(member).ok()?;
+            let member_type = self.u32_at(member + 4).ok()?;
+            let mut offset = self.u32_at(member + 8).ok()?;
+            let mut bitfield = false;
+            if ty.kind_flag {
+                // the high bits hold the size of bitfields
+                bitfield = offset >> 24 != 0;
+                offset &= 0xff_ffff;
+            }
+
+            if name_off == 0 && !bitfield {
+                anon_count += 1;
+                if anon_index == Some(anon_count) {
+                    return Some((offset, member_type, false));
+                }
+                anon_members.push((offset, member_type));
+            } else if self.name(name_off) == Some(name) {
+                let bitfield = bitfield || offset % 8 != 0 || self.is_int_bitfield(member_type);
+                return Some((offset, member_type, bitfield));
+            }
+        }
+
+        anon_members.into_iter().find_map(|(offset, member_type)| {
+            let (inner, ty, bitfield) = self.find_member(self.resolve(member_type)?, name)?;
+            Some((offset + inner, ty, bitfield))
+        })
+    }
+
+    // Without the kind flag, the size and offset of bitfields are encoded in
+    // the int type of the member.
+    fn is_int_bitfield(&self, id: u32) -> bool {
+        let ty = match self.resolve(id).and_then(|id| self.get(id)) {
+            Some(ty) if ty.kind == BTF_KIND_INT => ty,
+            _ => return false,
+        };
+        match self.u32_at(ty.offset + BTF_TYPE_SIZE) {
+            Ok(encoding) => encoding >> 16 & 0xff != 0 || encoding & 0xff != ty.size_or_type * 8,
+            Err(_) => false,
+        }
+    }
+
+    // Parses the func and line info of the `.BTF.ext` section.
+    fn parse_ext(&self, ext: &[u8]) -> Result<(ExtInfos, ExtInfos)> {
+        if ext.len() < 24 || NativeEndian::read_u16(ext) != BTF_MAGIC {
+            return Err(Error::Btf("invalid .BTF.ext header".to_string()));
+        }
+        let hdr_len = read_u32(ext, 4)? as usize;
+        let func_info_off = hdr_len + read_u32(ext, 8)? as usize;
+        let func_info_len = read_u32(ext, 12)? as usize;
+        let line_info_off = hdr_len + read_u32(ext, 16)? as usize;
+        let line_info_len = read_u32(ext, 20)? as usize;
+
+        Ok((
+            self.parse_ext_info(slice(ext, func_info_off, func_info_len)?)?,
+            self.parse_ext_info(slice(ext, line_info_off, line_info_len)?)?,
+        ))
+    }
+
+    // Parses the func or line info records of all the sections.
+    fn parse_ext_info(&self, data: &[u8]) -> Result<ExtInfos> {
+        let mut infos = HashMap::new();
+        if data.is_empty() {
+            return Ok(infos);
+        }
+
+        let rec_size = read_u32(data, 0)?;
+        if rec_size < 8 {
+            return Err(Error::Btf(format!("invalid record size {}", rec_size)));
+        }
+        let mut offset = 4;
+        while offset < data.len() {
+            let section = self
+                .name(read_u32(data, offset)?)
+                .ok_or_else(|| Error::Btf("invalid section name".to_string()))?;
+            let count = read_u32(data, offset + 4)?;
+            offset += 8;
+
+            let len = count
+                .checked_mul(rec_size)
+                .ok_or_else(|| Error::Btf(format!("{}: too many records", section)))?
+                as usize;
+            let mut records = slice(data, offset, len)?.to_vec();
+            // the kernel expects instruction offsets, llvm emits byte offsets
+            for record in records.chunks_mut(rec_size as usize) {
+                let insn_off = NativeEndian::read_u32(record) / mem::size_of::<bpf_insn>() as u32;
+                NativeEndian::write_u32(record, insn_off);
+            }
+            offset += len;
+
+            infos.insert(
+                section.to_string(),
+                ExtInfo {
+                    rec_size,
+                    count,
+                    data: records,
+                },
+            );
+        }
+
+        Ok(infos)
+    }
+
+    // Returns the .BTF section, fixed so that the kernel accepts it.
+    fn fixup(
+        &self,
+        section_sizes: &HashMap<String, u32>,
+        symbols: &HashMap<String, u32>,
+    ) -> Result<Vec<u8>> {
+        let mut type_data = self.type_data.clone();
+        let mut strings = self.strings.clone();
+        let mut arg_names = HashMap::new();
+
+        for ty in self.types.iter() {
+            let name = self.name(ty.name_off).unwrap_or("");
+            match ty.kind {
+                BTF_KIND_DATASEC => {
+                    if let Some(size) = section_sizes.get(name) {
+                        NativeEndian::write_u32(&mut type_data[ty.offset + 8..], *size);
+                    }
+                    for i in 0..ty.vlen {
+                        let var_secinfo = ty.offset + BTF_TYPE_SIZE + i * 12;
+                        let var = self.get(self.u32_at(var_secinfo)?);
+                        let offset = var
+                            .and_then(|var| self.name(var.name_off))
+                            .and_then(|name| symbols.get(name));
+                        if let Some(offset) = offset {
+                            NativeEndian::write_u32(&mut type_data[var_secinfo + 4..], *offset);
+                        }
+                    }
+                    sanitize_name(&mut strings, ty.name_off, true);
+                }
+                BTF_KIND_PTR | BTF_KIND_ARRAY | BTF_KIND_VOLATILE | BTF_KIND_CONST
+                | BTF_KIND_RESTRICT | BTF_KIND_FUNC_PROTO => {
+                    NativeEndian::write_u32(&mut type_data[ty.offset..], 0);
+                }
+                _ => sanitize_name(&mut strings, ty.name_off, false),
+            }
+
+            let members = ty.offset + BTF_TYPE_SIZE;
+            match ty.kind {
+                BTF_KIND_STRUCT | BTF_KIND_UNION => {
+                    for i in 0..ty.vlen {
+                        sanitize_name(&mut strings, self.u32_at(members + i * 12)?, false);
+                    }
+                }
+                BTF_KIND_ENUM => {
+                    for i in 0..ty.vlen {
+                        sanitize_name(&mut strings, self.u32_at(members + i * 8)?, false);
+                    }
+                }
+                BTF_KIND_FUNC_PROTO => {
+                    for i in 0..ty.vlen {
+                        let param = members + i * 8;
+                        let name_off = self.u32_at(param)?;
+                        // the last parameter of variadic functions has no name
+                        // nor type
+                        if name_off == 0 && self.u32_at(param + 4)? != 0 {
+                            let name_off = *arg_names.entry(i).or_insert_with(|| {
+                                let name_off = strings.len() as u32;
+                                strings.extend_from_slice(format!("arg{}\0", i).as_bytes());
+                                name_off
+                            });
+                            NativeEndian::write_u32(&mut type_data[param..], name_off);
+                        } else {
+                            sanitize_name(&mut strings, name_off, false);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let mut data = vec![0u8; 24];
+        NativeEndian::write_u16(&mut data[0..], BTF_MAGIC);
+        data[2] = 1;
+        NativeEndian::write_u32(&mut data[4..], 24);
+        NativeEndian::write_u32(&mut data[8..], 0);
+        NativeEndian::write_u32(&mut data[12..], type_data.len() as u32);
+        NativeEndian::write_u32(&mut data[16..], type_data.len() as u32);
+        NativeEndian::write_u32(&mut data[20..], strings.len() as u32);
+        data.extend_from_slice(&type_data);
+        data.extend_from_slice(&strings);
+        Ok(data)
+    }
+
+    fn get(&self, id: u32) -> Option<&BtfType> {
+        if id == 0 {
+            return None;
+        }
+        self.types.get(id as usize - 1)
+    }
+
+    // Skips typedefs and qualifiers.
+    fn resolve(&self, mut id: u32) -> Option<u32> {
+        loop {
+            match self.get(id)?.kind {
+                BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE | BTF_KIND_CONST | BTF_KIND_RESTRICT => {
+                    id = self.get(id)?.size_or_type
+                }
+                _ => return Some(id),
+            }
+        }
+    }
+
+    fn name(&self, offset: u32) -> Option<&str> {
+        let bytes = self.strings.get(offset as usize..)?;
+        let name = CStr::from_bytes_with_nul(&bytes[..=bytes.iter().position(|b| *b == 0)?]);
+        name.ok()?.to_str().ok()
+    }
+
+    fn u32_at(&self, offset: usize) -> Result<u32> {
+        read_u32(&self.type_data, offset)
+    }
+}
+
+impl ProgramBtf {
+    pub(crate) fn try_clone(&self) -> io::Result<ProgramBtf> {
+        Ok(ProgramBtf {
+            fd: self.fd.try_clone()?,
+            func_info: self.func_info.clone(),
+            line_info: self.line_info.clone(),
+        })
+    }
+}
+
+/// Creates a map with the key and value types in `btf`.
+pub(crate) fn create_map(name: &str, config: &bpf_map_def, btf: &MapBtf) -> io::Result<OwnedFd> {
+    let mut attr = MapCreateAttr {
+        map_type: config.type_,
+        key_size: config.key_size,
+        value_size: config.value_size,
+        max_entries: config.max_entries,
+        map_flags: config.map_flags,
+        inner_map_fd: 0,
+        numa_node: 0,
+        map_name: obj_name(name),
+        map_ifindex: 0,
+        btf_fd: btf.fd as u32,
+        btf_key_type_id: btf.key_type_id,
+        btf_value_type_id: btf.value_type_id,
+    };
+    bpf_retry_rlimit(BPF_MAP_CREATE, &mut attr).map(OwnedFd::new)
+}
+
+/// The kernel function a tracing program attaches to.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AttachTarget {
+    pub attach_type: u32,
+    pub btf_id: u32,
+}
+
+/// Loads a program with the func and line info in `btf`, if any.
+///
+/// Tracing programs must pass the kernel function they attach to in
+/// `target`. The verifier log is written to `log_buf`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn load_program(
+    prog_type: bpf_prog_type,
+    name: &str,
+    code: &[bpf_insn],
+    license: &CStr,
+    kernel_version: u32,
+    log_level: u32,
+    log_buf: &mut [u8],
+    btf: Option<&ProgramBtf>,
+    target: Option<AttachTarget>,
+) -> io::Result<OwnedFd> {
+    let mut attr: ProgLoadAttr = unsafe { mem::zeroed() };
+    attr.prog_type = prog_type;
+    attr.insn_cnt = code.len() as u32;
+    attr.insns = code.as_ptr() as u64;
+    attr.license = license.as_ptr() as u64;
+    attr.kern_version = kernel_version;
+    attr.prog_name = obj_name(name);
+    if log_level > 0 && !log_buf.is_empty() {
+        attr.log_level = log_level;
+        attr.log_buf = log_buf.as_mut_ptr() as u64;
+        attr.log_size = log_buf.len() as u32;
+    }
+    if let Some(btf) = btf {
+        attr.prog_btf_fd = btf.fd.as_raw_fd() as u32;
+        attr.func_info_rec_size = btf.func_info.rec_size;
+        attr.func_info = btf.func_info.data.as_ptr() as u64;
+        attr.func_info_cnt = btf.func_info.count;
+        if let Some(line_info) = &btf.line_info {
+            attr.line_info_rec_size = line_info.rec_size;
+            attr.line_info = line_info.data.as_ptr() as u64;
+            attr.line_info_cnt = line_info.count;
+        }
+    }
+    if let Some(target) = target {
+        attr.expected_attach_type = target.attach_type;
+        attr.attach_btf_id = target.btf_id;
+    }
+
+    let error = match bpf_retry_rlimit(BPF_PROG_LOAD, &mut attr) {
+        Ok(fd) => return Ok(OwnedFd::new(fd)),
+        Err(error) => error,
+    };
+    // like bcc, load rejected programs again with a log, so that the error
+    // can tell why the verifier rejected them
+    if attr.log_level == 0 && !log_buf.is_empty() {
+        attr.log_level = 1;
+        attr.log_buf = log_buf.as_mut_ptr() as u64;
+        attr.log_size = log_buf.len() as u32;
+        if let Ok(fd) = bpf_retry_rlimit(BPF_PROG_LOAD, &mut attr) {
+            return Ok(OwnedFd::new(fd));
+        }
+    }
+    Err(error)
+}
+
+fn load_btf(data: &[u8]) -> Result<OwnedFd> {
+    let mut log = vec![0u8; 64 * 1024];
+    // zeroed so that the padding is zero too, as the kernel requires
+    let mut attr: BtfLoadAttr = unsafe { mem::zeroed() };
+    attr.btf = data.as_ptr() as u64;
+    attr.btf_log_buf = log.as_mut_ptr() as u64;
+    attr.btf_size = data.len() as u32;
+    attr.btf_log_size = log.len() as u32;
+    attr.btf_log_level = 1;
+    bpf_retry_rlimit(BPF_BTF_LOAD, &mut attr)
+        .map(OwnedFd::new)
+        .map_err(|error| {
+            let len = log.iter().position(|b| *b == 0).unwrap_or(log.len());
+            Error::Btf(format!(
+                "the kernel rejected the BTF: {}\n{}",
+                error,
+                String::from_utf8_lossy(&log[..len])
+            ))
+        })
+}
+
+// Like bcc, raises RLIMIT_MEMLOCK and retries when the kernel fails to charge
+// the memory of the object.
+fn bpf_retry_rlimit<T>(cmd: c_int, attr: &mut T) -> io::Result<c_int> {
+    match bpf(cmd, attr) {
+        Err(e) if e.raw_os_error() == Some(EPERM) => {
+            let mut rl = rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            unsafe {
+                if getrlimit(RLIMIT_MEMLOCK, &mut rl) == 0 {
+                    rl.rlim_max = RLIM_INFINITY;
+                    rl.rlim_cur = rl.rlim_max;
+                    if setrlimit(RLIMIT_MEMLOCK, &rl) == 0 {
+                        return bpf(cmd, attr);
+                    }
+                }
+            }
+            Err(e)
+        }
+        ret => ret,
+    }
+}
+
+// Replaces the characters the kernel doesn't accept in the name at `offset`
+// with underscores. Section names can also contain dots.
+fn sanitize_name(strings: &mut [u8], offset: u32, section: bool) {
+    let name = match strings.get_mut(offset as usize..) {
+        Some(name) => name,
+        None => return,
+    };
+    for (i, c) in name.iter_mut().take_while(|c| **c != 0).enumerate() {
+        let valid = c.is_ascii_alphabetic()
+            || *c == b'_'
+            || (i > 0 && c.is_ascii_digit())
+            || (section && *c == b'.');
+        if !valid {
+            *c = b'_';
+        }
+    }
+}
+
+// The kernel only accepts alphanumeric characters, underscores and dots in
+// object names, so `sched:sched_process_exec` becomes
+// `sched_sched_pro`.
+fn obj_name(name: &str) -> [u8; BPF_OBJ_NAME_LEN] {
+    let mut obj_name = [0u8; BPF_OBJ_NAME_LEN];
+    for (c, b) in obj_name[..BPF_OBJ_NAME_LEN - 1]
+        .iter_mut()
+        .zip(name.bytes())
+    {
+        *c = if b.is_ascii_alphanumeric() || b == b'_' || b == b'.' {
+            b
+        } else {
+            b'_'
+        };
+    }
+    obj_name
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    data.get(offset..offset + len)
+        .ok_or_else(|| Error::Btf("section truncated".to_string()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    slice(data, offset, 4).map(NativeEndian::read_u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bpf_sys::btf::test_support::Builder;
+
+    #[test]
+    fn test_parse_types() {
+        let mut b = Builder::new();
+        let int = b.int("int", 4);
+        let ptr = b.add("", BTF_KIND_PTR, 0, int, &[]);
+        let array = b.add("", BTF_KIND_ARRAY, 0, 0, &[int, int, 4]);
+        let task = b.structure(
+            "task",
+            24,
+            false,
+            &[("pid", int, 0), ("ptr", ptr, 64), ("arr", array, 128)],
+        );
+        let typedef = b.add("task_t", BTF_KIND_TYPEDEF, 0, task, &[]);
+        let param = b.name("t");
+        let proto = b.add("", BTF_KIND_FUNC_PROTO, 1, int, &[param, ptr]);
+        let func = b.add("do_exit", BTF_KIND_FUNC, 0, proto, &[]);
+
+        let types = Types::parse(&b.build()).unwrap();
+        assert_eq!(types.types.len(), 7);
+        let kinds: Vec<u32> = types.types.iter().map(|t| t.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                BTF_KIND_INT,
+                BTF_KIND_PTR,
+                BTF_KIND_ARRAY,
+                BTF_KIND_STRUCT,
+                BTF_KIND_TYPEDEF,
+                BTF_KIND_FUNC_PROTO,
+                BTF_KIND_FUNC
+            ]
+        );
+        let ty = types.get(task).unwrap();
+        assert_eq!(types.name(ty.name_off), Some("task"));
+        assert_eq!(ty.vlen, 3);
+        assert_eq!(ty.size_or_type, 24);
+        assert_eq!(types.get(ptr).unwrap().size_or_type, int);
+        assert_eq!(types.resolve(typedef), Some(task));
+        assert_eq!(types.func_id("do_exit"), Some(func));
+        assert_eq!(types.func_id("task"), None);
+        assert!(types.get(0).is_none());
+        assert!(types.get(8).is_none());
+    }
+
+    #[test]
+    fn test_field_offset() {
+        let mut b = Builder::new();
+        let int = b.int("int", 4);
+        let long = b.int("long", 8);
+        let pid_t = b.add("pid_t", BTF_KIND_TYPEDEF, 0, int, &[]);
+        let inner = b.structure("", 8, false, &[("a", int, 0), ("b", int, 32)]);
+        let c = b.name("c");
+        let union = b.add("", BTF_KIND_UNION, 2, 8, &[0, inner, 0, c, long, 0]);
+        let flags = b.structure(
+            "flags",
+            4,
+            true,
+            &[("x", int, 3 << 24), ("y", int, 5 << 24 | 3)],
+        );
+        let bits = b.add("", BTF_KIND_INT, 0, 4, &[1 << 16 | 3]);
+        let task = b.structure(
+            "task",
+            40,
+            false,
+            &[
+                ("pid", pid_t, 0),
+                ("", union, 64),
+                ("flags", flags, 128),
+                ("", inner, 192),
+                ("old", bits, 256),
+                ("odd", int, 260),
+            ],
+        );
+        let task_t = b.add("task_t", BTF_KIND_TYPEDEF, 0, task, &[]);
+        b.structure("parent", 8, false, &[("task", task_t, 0)]);
+        b.structure("task", 4, false, &[("tgid", int, 0)]);
+
+        let types = Types::parse(&b.build()).unwrap();
+        assert_eq!(types.field_offset("task.pid").unwrap(), 0);
+        assert_eq!(types.field_offset("task.a").unwrap(), 8);
+        assert_eq!(types.field_offset("task.c").unwrap(), 8);
+        assert_eq!(types.field_offset("task.__bindgen_anon_1.c").unwrap(), 8);
+        assert_eq!(types.field_offset("task.__bindgen_anon_2.b").unwrap(), 28);
+        assert_eq!(types.field_offset("task.flags").unwrap(), 16);
+        assert_eq!(types.field_offset("task.tgid").unwrap(), 0);
+        assert!(types.field_offset("task.flags.x").is_err());
+        assert!(types.field_offset("task.flags.y").is_err());
+        assert!(types.field_offset("task.old").is_err());
+        assert!(types.field_offset("task.odd").is_err());
+        assert!(types.field_offset("task.missing").is_err());
+        assert_eq!(types.field_offset("parent.task.c").unwrap(), 8);
+        assert!(types.field_offset("task_t.pid").is_err());
+    }
+
+    #[test]
+    fn test_obj_name() {
+        assert_eq!(&obj_name("foo"), b"foo\0\0\0\0\0\0\0\0\0\0\0\0\0");
+        assert_eq!(&obj_name("sched:sched_process_exec"), b"sched_sched_pro\0");
+        assert_eq!(&obj_name("usdt/a:b"), b"usdt_a_b\0\0\0\0\0\0\0\0");
+        assert_eq!(&obj_name(".rodata.str1.1"), b".rodata.str1.1\0\0");
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        let mut strings = b"\0HashMap<u32, u64>\0.data.rel\0*mut u8\x000abc\0".to_vec();
+        sanitize_name(&mut strings, 1, false);
+        sanitize_name(&mut strings, 19, true);
+        sanitize_name(&mut strings, 29, false);
+        sanitize_name(&mut strings, 37, false);
+        sanitize_name(&mut strings, 100, false);
+        assert_eq!(
+            &strings[..],
+            &b"\0HashMap_u32__u64_\0.data.rel\0_mut_u8\0_abc\0"[..]
+        );
+    }
+}
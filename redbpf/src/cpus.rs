@@ -10,6 +10,7 @@ use std::io::Error;
 use std::str::FromStr;
 
 const SYS_CPU_ONLINE: &str = "/sys/devices/system/cpu/online";
+const SYS_CPU_POSSIBLE: &str = "/sys/devices/system/cpu/possible";
 
 pub type CpuId = i32;
 
@@ -27,6 +28,15 @@ pub fn get_online() -> Result<Vec<CpuId>, Error> {
     Ok(list_from_string(&cpus.trim()))
 }
 
+/// Returns a list of possible CPU IDs.
+///
+/// Per-CPU maps hold one value for each possible CPU, including the CPUs
+/// that are offline. Errors are handled like in `get_online()`.
+pub fn get_possible() -> Result<Vec<CpuId>, Error> {
+    let cpus = unsafe { String::from_utf8_unchecked(read(SYS_CPU_POSSIBLE)?) };
+    Ok(list_from_string(cpus.trim()))
+}
+
 fn list_from_string(cpus: &str) -> Vec<CpuId> {
     let cpu_list = cpus.split(',').flat_map(|group| {
         let mut split = group.split('-');
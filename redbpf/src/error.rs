@@ -5,25 +5,140 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::fmt::{self, Display};
+use std::io;
+
 #[derive(Debug)]
 pub enum Error {
     StringConversion,
-    BPF,
-    Map,
+    /// The `op` operation on `program` failed with `error`.
+    Program {
+        op: &'static str,
+        program: String,
+        error: io::Error,
+    },
+    /// The verifier rejected `program`. `log` holds the verifier output.
+    Verifier {
+        program: String,
+        log: String,
+        error: io::Error,
+    },
+    /// The `op` operation on `map` failed with `error`.
+    Map {
+        op: &'static str,
+        map: String,
+        error: io::Error,
+    },
+    /// The key or value type used to access `map` doesn't match its definition,
+    /// or the map pinned for `map` has a different definition.
+    MapTypeMismatch {
+        map: String,
+    },
     Section(String),
     Parse(::goblin::error::Error),
     KernelRelease(String),
-    IO(::std::io::Error),
+    IO(io::Error),
     Uname,
-    Reloc,
+    Reloc(String),
+    /// The BTF of the module is invalid, or the kernel rejected it.
+    Btf(String),
     LibraryNotFound(String),
     SymbolNotFound(String),
+    /// A pattern passed to `KProbe::attach_kprobe_matching()` isn't a valid
+    /// regular expression.
+    InvalidPattern(String),
+    /// A USDT probe can't be attached to, for example because the arguments
+    /// of one of its locations can't be decoded.
+    Usdt(String),
     ProgramAlreadyLoaded,
-    ProgramNotLoaded
+    ProgramNotLoaded,
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
+impl Error {
+    /// Creates an `Error::Program` from the last OS error.
+    pub(crate) fn program(op: &'static str, program: &str) -> Error {
+        // read before allocating, which can overwrite errno
+        let error = io::Error::last_os_error();
+        Error::Program {
+            op,
+            program: program.to_string(),
+            error,
+        }
+    }
+
+    /// Creates an `Error::Map` from the last OS error.
+    pub(crate) fn map(op: &'static str, map: &str) -> Error {
+        let error = io::Error::last_os_error();
+        Error::Map {
+            op,
+            map: map.to_string(),
+            error,
+        }
+    }
+
+    /// Returns the OS error that caused this error, if any.
+    pub fn os_error(&self) -> Option<&io::Error> {
+        use Error::*;
+        match self {
+            Program { error, .. } | Verifier { error, .. } | Map { error, .. } | IO(error) => {
+                Some(error)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            StringConversion => write!(f, "string contains an interior nul byte"),
+            Program { op, program, error } => {
+                write!(f, "program `{}': {} failed: {}", program, op, error)
+            }
+            Verifier {
+                program,
+                log,
+                error,
+            } => write!(
+                f,
+                "the verifier rejected program `{}': {}\n{}",
+                program, error, log
+            ),
+            Map { op, map, error } => write!(f, "map `{}': {} failed: {}", map, op, error),
+            MapTypeMismatch { map } => write!(
+                f,
+                "map `{}': key or value size doesn't match the map definition",
+                map
+            ),
+            Section(s) => write!(f, "invalid section: {}", s),
+            Parse(e) => write!(f, "failed to parse ELF: {}", e),
+            KernelRelease(r) => write!(f, "invalid kernel release: {}", r),
+            IO(e) => write!(f, "{}", e),
+            Uname => write!(f, "uname failed"),
+            Reloc(s) => write!(f, "relocation failed: {}", s),
+            Btf(s) => write!(f, "invalid BTF: {}", s),
+            LibraryNotFound(l) => write!(f, "library not found: {}", l),
+            SymbolNotFound(s) => write!(f, "symbol not found: {}", s),
+            InvalidPattern(e) => write!(f, "invalid pattern: {}", e),
+            Usdt(s) => write!(f, "can't attach to USDT probe: {}", s),
+            ProgramAlreadyLoaded => write!(f, "the program is already loaded"),
+            ProgramNotLoaded => write!(f, "the program is not loaded"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(e) => Some(e),
+            e => e.os_error().map(|e| e as _),
+        }
+    }
+}
+
 impl From<::goblin::error::Error> for Error {
     fn from(e: ::goblin::error::Error) -> Error {
         Error::Parse(e)
@@ -36,8 +151,8 @@ impl From<::std::ffi::NulError> for Error {
     }
 }
 
-impl From<::std::io::Error> for Error {
-    fn from(e: ::std::io::Error) -> Error {
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
         Error::IO(e)
     }
 }
@@ -0,0 +1,61 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use libc::{close, fcntl, F_DUPFD_CLOEXEC};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+
+/// An owned file descriptor.
+///
+/// The descriptor is closed when the `OwnedFd` is dropped.
+#[derive(Debug)]
+pub(crate) struct OwnedFd(RawFd);
+
+impl OwnedFd {
+    /// Takes ownership of `fd`.
+    ///
+    /// `fd` must be a valid descriptor that isn't owned by anything else.
+    pub(crate) fn new(fd: RawFd) -> OwnedFd {
+        OwnedFd(fd)
+    }
+
+    /// Duplicates the descriptor.
+    ///
+    /// The new descriptor refers to the same kernel object and has
+    /// `FD_CLOEXEC` set.
+    pub(crate) fn try_clone(&self) -> io::Result<OwnedFd> {
+        let fd = unsafe { fcntl(self.0, F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(OwnedFd(fd))
+        }
+    }
+}
+
+impl AsRawFd for OwnedFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl IntoRawFd for OwnedFd {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.0;
+        mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for OwnedFd {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.0);
+        }
+    }
+}
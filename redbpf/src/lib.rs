@@ -31,11 +31,16 @@ use redbpf::load::Loader;
 let mut loader = Loader::load_file("iotop.elf").expect("error loading probe");
 
 // attach all the kprobes defined in iotop.elf
-for kprobe in loader.kprobes_mut() {
-    kprobe
-        .attach_kprobe(&kprobe.name(), 0)
-        .expect(&format!("error attaching program {}", kprobe.name()));
-}
+let links: Vec<_> = loader
+    .kprobes_mut()
+    .map(|kprobe| {
+        kprobe
+            .attach_kprobe(&kprobe.name(), 0)
+            .expect(&format!("error attaching program {}", kprobe.name()))
+    })
+    .collect();
+// the kprobes stay attached until `loader` is dropped
+loader.links.extend(links);
 ```
 */
 #![deny(clippy::all)]
@@ -44,11 +49,16 @@ for kprobe in loader.kprobes_mut() {
 #[macro_use]
 extern crate lazy_static;
 
+mod btf;
 pub mod cpus;
 mod error;
+mod fd;
 #[cfg(feature = "load")]
 pub mod load;
+mod link;
 mod perf;
+mod perf_reader;
+mod ringbuf;
 mod symbols;
 pub mod sys;
 pub mod xdp;
@@ -61,17 +71,29 @@ use bpf_sys::{
 use goblin::elf::{reloc::RelocSection, section_header as hdr, Elf, SectionHeader, Sym};
 
 use libc::pid_t;
+use regex::Regex;
 use std::collections::HashMap as RSHashMap;
 use std::ffi::CString;
 use std::fs;
 use std::io;
+use std::iter::Sum;
 use std::marker::PhantomData;
 use std::mem;
 use std::mem::MaybeUninit;
-use std::os::unix::io::RawFd;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
 
 pub use crate::error::{Error, Result};
+pub use crate::link::Link;
 pub use crate::perf::*;
+pub use crate::perf_reader::*;
+pub use crate::ringbuf::RingBufReader;
+use crate::btf::{AttachTarget, Btf, MapBtf, ProgramBtf, Types};
+use crate::cpus::CpuId;
+use crate::fd::OwnedFd;
 use crate::symbols::*;
 use crate::uname::get_kernel_internal_version;
 
@@ -99,19 +121,70 @@ pub enum Program {
     URetProbe(UProbe),
     SocketFilter(SocketFilter),
     TracePoint(TracePoint),
+    RawTracePoint(RawTracePoint),
+    PerfEvent(PerfEvent),
     XDP(XDP),
+    FEntry(FEntry),
+    FExit(FExit),
+    Usdt(Usdt),
 }
 
 struct ProgramData {
     pub name: String,
     code: Vec<bpf_insn>,
-    fd: Option<RawFd>,
+    fd: Option<OwnedFd>,
+    verifier_log: Option<String>,
+    btf: Option<ProgramBtf>,
+}
+
+// The values the kernel struct accessors generated by cargo-bpf are relocated
+// to when a field isn't in the kernel BTF, and when the kernel has no BTF.
+const FIELD_NOT_FOUND: u32 = 0xffff_fff8;
+const FIELD_BUILD_OFFSET: u32 = 0xffff_fff0;
+// The values the `SyscallRegisters` of probes are relocated to, telling
+// whether system calls take the registers of the caller or their arguments.
+const SYSCALL_WRAPPERS: u32 = 1;
+const NO_SYSCALL_WRAPPERS: u32 = 2;
+
+const DEFAULT_VERIFIER_LOG_SIZE: usize = 16 * 65535;
+// the kernel rejects smaller log buffers when logging is enabled
+const MIN_VERIFIER_LOG_SIZE: usize = 128;
+
+/// Verifier log settings used when loading a [Program](enum.Program.html).
+///
+/// When a program is rejected, the output of the verifier is returned in
+/// [Error::Verifier](enum.Error.html#variant.Verifier) regardless of the
+/// configured `level`. A `level` greater than 0 also makes the log of programs
+/// that load successfully available through `Program::verifier_log()`.
+#[derive(Debug, Clone, Copy)]
+pub struct VerifierLogConfig {
+    /// The verbosity of the log, as passed to the `BPF_PROG_LOAD` command.
+    pub level: u32,
+    /// The size in bytes of the buffer the verifier writes the log to.
+    ///
+    /// Logs that don't fit are truncated. The size can't exceed `u32::MAX`,
+    /// and must be at least 128 bytes when `level` is greater than 0.
+    pub size: usize,
+}
+
+impl Default for VerifierLogConfig {
+    fn default() -> Self {
+        VerifierLogConfig {
+            level: 0,
+            size: DEFAULT_VERIFIER_LOG_SIZE,
+        }
+    }
 }
 
 /// Type to work with `kprobes` or `kretprobes`.
+///
+/// Programs defined with `#[syscall]` or `#[syscall_ret]` are kprobes too,
+/// named after the system call they trace rather than after a kernel
+/// function.
 pub struct KProbe {
     common: ProgramData,
     attach_type: bpf_probe_attach_type,
+    syscall: bool,
 }
 
 /// Type to work with `uprobes` or `uretprobes`.
@@ -125,9 +198,37 @@ pub struct SocketFilter {
     common: ProgramData,
 }
 
+/// Type to work with `tracepoints`.
 pub struct TracePoint {
     common: ProgramData,
 }
+
+/// Type to work with raw `tracepoints`.
+pub struct RawTracePoint {
+    common: ProgramData,
+}
+
+/// Type to work with `perf_event` programs.
+pub struct PerfEvent {
+    common: ProgramData,
+}
+
+/// Type to work with `fentry` programs.
+pub struct FEntry {
+    common: ProgramData,
+}
+
+/// Type to work with `fexit` programs.
+pub struct FExit {
+    common: ProgramData,
+}
+
+/// Type to work with USDT probes.
+pub struct Usdt {
+    common: ProgramData,
+    specs: Option<Map>,
+}
+
 /// Type to work with `XDP` programs.
 pub struct XDP {
     common: ProgramData,
@@ -137,7 +238,7 @@ pub struct XDP {
 pub struct Map {
     pub name: String,
     pub kind: u32,
-    fd: RawFd,
+    fd: OwnedFd,
     config: bpf_map_def,
     section_data: bool,
 }
@@ -148,6 +249,64 @@ pub struct HashMap<'a, K: Clone, V: Clone> {
     _v: PhantomData<V>,
 }
 
+/// Array map.
+///
+/// To use this from eBPF code, see
+/// [`redbpf_probes::maps::Array`](../../redbpf_probes/maps/struct.Array.html).
+pub struct Array<'a, T: Clone> {
+    base: &'a Map,
+    _element: PhantomData<T>,
+}
+
+/// Per-CPU array map.
+///
+/// Each element of the array holds one value for each possible CPU.
+///
+/// To use this from eBPF code, see
+/// [`redbpf_probes::maps::PerCpuArray`](../../redbpf_probes/maps/struct.PerCpuArray.html).
+pub struct PerCpuArray<'a, T: Clone> {
+    base: &'a Map,
+    cpu_count: usize,
+    _element: PhantomData<T>,
+}
+
+/// Per-CPU hash table map.
+///
+/// Each entry of the map holds one value for each possible CPU.
+///
+/// To use this from eBPF code, see
+/// [`redbpf_probes::maps::PerCpuHashMap`](../../redbpf_probes/maps/struct.PerCpuHashMap.html).
+pub struct PerCpuHashMap<'a, K: Clone, V: Clone> {
+    base: &'a Map,
+    cpu_count: usize,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+/// LRU hash table map.
+///
+/// LRU maps are accessed like regular hash maps from user-space, see
+/// `HashMap`.
+///
+/// To use this from eBPF code, see
+/// [`redbpf_probes::maps::LruHashMap`](../../redbpf_probes/maps/struct.LruHashMap.html).
+pub type LruHashMap<'a, K, V> = HashMap<'a, K, V>;
+
+/// LRU per-CPU hash table map.
+///
+/// LRU maps are accessed like regular hash maps from user-space, see
+/// `PerCpuHashMap`.
+///
+/// To use this from eBPF code, see
+/// [`redbpf_probes::maps::LruPerCpuHashMap`](../../redbpf_probes/maps/struct.LruPerCpuHashMap.html).
+pub type LruPerCpuHashMap<'a, K, V> = PerCpuHashMap<'a, K, V>;
+
+/// The values of an entry of a per-CPU map, one for each possible CPU.
+///
+/// The value of CPU `n` is at position `n`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerCpuValues<T>(Box<[T]>);
+
 pub struct StackTrace<'a> {
     base: &'a Map,
 }
@@ -187,16 +346,30 @@ impl Program {
             name,
             code,
             fd: None,
+            verifier_log: None,
+            btf: None,
         };
 
         Ok(match kind {
             "kprobe" => Program::KProbe(KProbe {
                 common,
                 attach_type: bpf_probe_attach_type_BPF_PROBE_ENTRY,
+                syscall: false,
             }),
             "kretprobe" => Program::KProbe(KProbe {
                 common,
                 attach_type: bpf_probe_attach_type_BPF_PROBE_RETURN,
+                syscall: false,
+            }),
+            "syscall" => Program::KProbe(KProbe {
+                common,
+                attach_type: bpf_probe_attach_type_BPF_PROBE_ENTRY,
+                syscall: true,
+            }),
+            "syscall_ret" => Program::KProbe(KProbe {
+                common,
+                attach_type: bpf_probe_attach_type_BPF_PROBE_RETURN,
+                syscall: true,
             }),
             "uprobe" => Program::UProbe(UProbe {
                 common,
@@ -207,6 +380,14 @@ impl Program {
                 attach_type: bpf_probe_attach_type_BPF_PROBE_RETURN,
             }),
             "tracepoint" => Program::TracePoint(TracePoint { common }),
+            "raw_tracepoint" => Program::RawTracePoint(RawTracePoint { common }),
+            "perf_event" => Program::PerfEvent(PerfEvent { common }),
+            "fentry" => Program::FEntry(FEntry { common }),
+            "fexit" => Program::FExit(FExit { common }),
+            "usdt" => Program::Usdt(Usdt {
+                common,
+                specs: None,
+            }),
             "socketfilter" => Program::SocketFilter(SocketFilter { common }),
             "xdp" => Program::XDP(XDP {
                 common,
@@ -220,15 +401,46 @@ impl Program {
         use Program::*;
 
         match self {
-            KProbe(_) | KRetProbe(_) | UProbe(_) | URetProbe(_) => {
+            KProbe(_) | KRetProbe(_) | UProbe(_) | URetProbe(_) | Usdt(_) => {
                 bpf_sys::bpf_prog_type_BPF_PROG_TYPE_KPROBE
             }
             XDP(_) => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_XDP,
             SocketFilter(_) => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SOCKET_FILTER,
             TracePoint(_) => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_TRACEPOINT,
+            RawTracePoint(_) => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_RAW_TRACEPOINT,
+            PerfEvent(_) => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_PERF_EVENT,
+            FEntry(_) | FExit(_) => link::BPF_PROG_TYPE_TRACING,
         }
     }
 
+    // Resolves the kernel function tracing programs attach to. Its BTF id
+    // must be passed when loading the program.
+    fn attach_target(&self) -> Result<Option<AttachTarget>> {
+        use Program::*;
+
+        let attach_type = match self {
+            FEntry(_) => link::BPF_TRACE_FENTRY,
+            FExit(_) => link::BPF_TRACE_FEXIT,
+            _ => return Ok(None),
+        };
+        let types = Types::kernel().ok_or_else(|| Error::Program {
+            op: "load",
+            program: self.name().to_string(),
+            error: io::Error::new(
+                io::ErrorKind::NotFound,
+                "the kernel has no BTF, it must be built with CONFIG_DEBUG_INFO_BTF",
+            ),
+        })?;
+        let btf_id = types
+            .func_id(self.name())
+            .ok_or_else(|| Error::SymbolNotFound(self.name().to_string()))?;
+
+        Ok(Some(AttachTarget {
+            attach_type,
+            btf_id,
+        }))
+    }
+
     fn data(&self) -> &ProgramData {
         use Program::*;
 
@@ -238,6 +450,11 @@ impl Program {
             XDP(p) => &p.common,
             SocketFilter(p) => &p.common,
             TracePoint(p) => &p.common,
+            RawTracePoint(p) => &p.common,
+            PerfEvent(p) => &p.common,
+            FEntry(p) => &p.common,
+            FExit(p) => &p.common,
+            Usdt(p) => &p.common,
         }
     }
 
@@ -250,6 +467,11 @@ impl Program {
             XDP(p) => &mut p.common,
             SocketFilter(p) => &mut p.common,
             TracePoint(p) => &mut p.common,
+            RawTracePoint(p) => &mut p.common,
+            PerfEvent(p) => &mut p.common,
+            FEntry(p) => &mut p.common,
+            FExit(p) => &mut p.common,
+            Usdt(p) => &mut p.common,
         }
     }
 
@@ -257,8 +479,158 @@ impl Program {
         &self.data().name
     }
 
-    pub fn fd(&self) -> &Option<RawFd> {
-        &self.data().fd
+    /// Returns the file descriptor of the program, if it's loaded.
+    ///
+    /// The descriptor is owned by the program and is closed when the program
+    /// is dropped.
+    pub fn fd(&self) -> Option<RawFd> {
+        self.data().fd.as_ref().map(|fd| fd.as_raw_fd())
+    }
+
+    /// Creates a new `Program` referring to the same kernel object.
+    ///
+    /// The file descriptor of a loaded program is duplicated, so the program
+    /// stays loaded until all the copies are dropped. XDP programs cloned
+    /// this way don't detach from the interfaces the original was attached to
+    /// when dropped.
+    pub fn try_clone(&self) -> Result<Program> {
+        use Program::*;
+
+        Ok(match self {
+            KProbe(p) => KProbe(p.try_clone()?),
+            KRetProbe(p) => KRetProbe(p.try_clone()?),
+            UProbe(p) => UProbe(p.try_clone()?),
+            URetProbe(p) => URetProbe(p.try_clone()?),
+            SocketFilter(p) => SocketFilter(crate::SocketFilter {
+                common: p.common.try_clone()?,
+            }),
+            TracePoint(p) => TracePoint(crate::TracePoint {
+                common: p.common.try_clone()?,
+            }),
+            RawTracePoint(p) => RawTracePoint(crate::RawTracePoint {
+                common: p.common.try_clone()?,
+            }),
+            PerfEvent(p) => PerfEvent(crate::PerfEvent {
+                common: p.common.try_clone()?,
+            }),
+            XDP(p) => XDP(crate::XDP {
+                common: p.common.try_clone()?,
+                interfaces: Vec::new(),
+            }),
+            FEntry(p) => FEntry(crate::FEntry {
+                common: p.common.try_clone()?,
+            }),
+            FExit(p) => FExit(crate::FExit {
+                common: p.common.try_clone()?,
+            }),
+            Usdt(p) => Usdt(crate::Usdt {
+                common: p.common.try_clone()?,
+                specs: p.specs.as_ref().map(Map::try_clone).transpose()?,
+            }),
+        })
+    }
+
+    /// Pins the program to `path`.
+    ///
+    /// `path` must be on a BPF filesystem, usually mounted at `/sys/fs/bpf`.
+    /// The program stays loaded while it's pinned, even after all its file
+    /// descriptors are closed. Remove the file at `path` to unpin it. To keep
+    /// the program attached too, see `Link::pin()`.
+    pub fn pin<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let fd = self.data().raw_fd()?;
+        let cpath = CString::new(path.as_ref().as_os_str().as_bytes())?;
+        if unsafe { bpf_sys::bpf_obj_pin(fd, cpath.as_ptr()) } < 0 {
+            return Err(Error::program("pin", self.name()));
+        }
+
+        Ok(())
+    }
+
+    /// Opens the program pinned at `path`.
+    ///
+    /// The name and type of the program are read from the kernel. Note that
+    /// the kernel only keeps the first 15 bytes of program names, and doesn't
+    /// tell kprobes, kretprobes and USDT programs apart, so those are returned
+    /// as `Program::KProbe`. Only the program types that can be loaded from an
+    /// ELF file are supported, except for `fentry` and `fexit` programs that
+    /// can't be told apart either.
+    pub fn from_pinned<P: AsRef<Path>>(path: P) -> Result<Program> {
+        let path = path.as_ref();
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { bpf_sys::bpf_obj_get(cpath.as_ptr()) };
+        if fd < 0 {
+            return Err(Error::program("open pinned", &path.to_string_lossy()));
+        }
+        let fd = OwnedFd::new(fd);
+
+        let mut info: bpf_sys::bpf_prog_info = unsafe { mem::zeroed() };
+        let mut info_len = mem::size_of_val(&info) as u32;
+        if unsafe {
+            bpf_sys::bpf_obj_get_info(
+                fd.as_raw_fd(),
+                &mut info as *mut _ as *mut _,
+                &mut info_len,
+            )
+        } < 0
+        {
+            return Err(Error::program("get info", &path.to_string_lossy()));
+        }
+
+        let name: Vec<u8> = info
+            .name
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8)
+            .collect();
+        let common = ProgramData {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            code: Vec::new(),
+            fd: Some(fd),
+            verifier_log: None,
+            btf: None,
+        };
+
+        Ok(match info.type_ {
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_KPROBE => Program::KProbe(KProbe {
+                common,
+                attach_type: bpf_probe_attach_type_BPF_PROBE_ENTRY,
+                syscall: false,
+            }),
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_TRACEPOINT => {
+                Program::TracePoint(TracePoint { common })
+            }
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_RAW_TRACEPOINT => {
+                Program::RawTracePoint(RawTracePoint { common })
+            }
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_PERF_EVENT => {
+                Program::PerfEvent(PerfEvent { common })
+            }
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SOCKET_FILTER => {
+                Program::SocketFilter(SocketFilter { common })
+            }
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_XDP => Program::XDP(XDP {
+                common,
+                interfaces: Vec::new(),
+            }),
+            kind => {
+                return Err(Error::Program {
+                    op: "open pinned",
+                    program: path.to_string_lossy().into_owned(),
+                    error: io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unsupported program type {}", kind),
+                    ),
+                })
+            }
+        })
+    }
+
+    /// Returns the output of the verifier for the last successful load.
+    ///
+    /// The log is only collected if the program was loaded with
+    /// `load_with_log` and a log level greater than 0.
+    pub fn verifier_log(&self) -> Option<&str> {
+        self.data().verifier_log.as_deref()
     }
 
     /// Load the BPF program.
@@ -276,55 +648,228 @@ impl Program {
     /// }
     /// ```
     pub fn load(&mut self, kernel_version: u32, license: String) -> Result<()> {
+        self.load_with_log(kernel_version, license, VerifierLogConfig::default())
+    }
+
+    /// Load the BPF program using the given verifier log settings.
+    ///
+    /// If the verifier rejects the program, its output is returned in
+    /// `Error::Verifier`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use redbpf::{Error, Module, VerifierLogConfig};
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let log_config = VerifierLogConfig { level: 2, ..Default::default() };
+    /// for program in module.programs.iter_mut() {
+    ///     match program.load_with_log(module.version, module.license.clone(), log_config) {
+    ///         Err(Error::Verifier { program, log, .. }) => eprintln!("{} rejected:\n{}", program, log),
+    ///         _ => (),
+    ///     }
+    /// }
+    /// ```
+    pub fn load_with_log(
+        &mut self,
+        kernel_version: u32,
+        license: String,
+        log_config: VerifierLogConfig,
+    ) -> Result<()> {
         if self.data().fd.is_some() {
             return Err(Error::ProgramAlreadyLoaded);
         }
+        if log_config.size > u32::MAX as usize
+            || (log_config.level > 0 && log_config.size < MIN_VERIFIER_LOG_SIZE)
+        {
+            return Err(Error::Program {
+                op: "load",
+                program: self.name().to_string(),
+                error: io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid verifier log size {}", log_config.size),
+                ),
+            });
+        }
         let clicense = CString::new(license)?;
         let cname = CString::new(self.data_mut().name.clone())?;
-        let log_buffer: MutDataPtr =
-            unsafe { libc::malloc(mem::size_of::<i8>() * 16 * 65535) as MutDataPtr };
-        let buf_size = 64 * 65535_u32;
-
-        let fd = unsafe {
-            bpf_sys::bcc_prog_load(
+        let mut log_buffer = vec![0u8; log_config.size];
+        let target = self.attach_target()?;
+
+        // kernels that don't support BTF, or that reject the func and line
+        // info, still get the program without it
+        let data = self.data();
+        let fd = data.btf.as_ref().and_then(|btf| {
+            btf::load_program(
+                self.to_prog_type(),
+                &data.name,
+                &data.code,
+                &clicense,
+                kernel_version,
+                log_config.level,
+                &mut log_buffer,
+                Some(btf),
+                target,
+            )
+            .ok()
+        });
+
+        let fd = match fd {
+            Some(fd) => fd,
+            // bcc can't pass the BTF id of the function tracing programs
+            // attach to
+            None if target.is_some() => btf::load_program(
                 self.to_prog_type(),
-                cname.as_ptr() as DataPtr,
-                self.data_mut().code.as_ptr(),
-                (self.data_mut().code.len() * mem::size_of::<bpf_insn>()) as i32,
-                clicense.as_ptr() as DataPtr,
-                kernel_version as u32,
-                0_i32,
-                log_buffer,
-                buf_size,
+                &data.name,
+                &data.code,
+                &clicense,
+                kernel_version,
+                log_config.level,
+                &mut log_buffer,
+                None,
+                target,
             )
+            .map_err(|error| load_error(self.name(), error, &log_buffer))?,
+            None => {
+                let fd = unsafe {
+                    bpf_sys::bcc_prog_load(
+                        self.to_prog_type(),
+                        cname.as_ptr() as DataPtr,
+                        self.data_mut().code.as_ptr(),
+                        (self.data_mut().code.len() * mem::size_of::<bpf_insn>()) as i32,
+                        clicense.as_ptr() as DataPtr,
+                        kernel_version as u32,
+                        log_config.level as i32,
+                        log_buffer.as_mut_ptr() as MutDataPtr,
+                        log_buffer.len() as u32,
+                    )
+                };
+                if fd < 0 {
+                    let error = io::Error::last_os_error();
+                    return Err(load_error(self.name(), error, &log_buffer));
+                }
+                OwnedFd::new(fd)
+            }
         };
 
-        if fd < 0 {
-            Err(Error::BPF)
-        } else {
-            self.data_mut().fd = Some(fd);
-            Ok(())
+        let log = log_from_buffer(&log_buffer);
+        let data = self.data_mut();
+        data.fd = Some(fd);
+        if log_config.level > 0 {
+            data.verifier_log = Some(log);
+        }
+        Ok(())
+    }
+}
+
+impl ProgramData {
+    fn raw_fd(&self) -> Result<RawFd> {
+        self.fd
+            .as_ref()
+            .map(|fd| fd.as_raw_fd())
+            .ok_or(Error::ProgramNotLoaded)
+    }
+
+    fn try_clone(&self) -> Result<ProgramData> {
+        let clone_error = |error: io::Error| Error::Program {
+            op: "clone",
+            program: self.name.clone(),
+            error,
+        };
+        let fd = match &self.fd {
+            Some(fd) => Some(fd.try_clone().map_err(&clone_error)?),
+            None => None,
+        };
+        let btf = match &self.btf {
+            Some(btf) => Some(btf.try_clone().map_err(&clone_error)?),
+            None => None,
+        };
+
+        Ok(ProgramData {
+            name: self.name.clone(),
+            code: self.code.clone(),
+            fd,
+            verifier_log: self.verifier_log.clone(),
+            btf,
+        })
+    }
+
+    // Opens the perf event described by `attr` and runs the program every
+    // time the event fires
+    fn attach_perf_event(
+        &self,
+        attr: &sys::perf::perf_event_attr,
+        pid: pid_t,
+        cpu: CpuId,
+    ) -> Result<Link> {
+        let fd = self.raw_fd()?;
+        let attach_error = |error| Error::Program {
+            op: "attach",
+            program: self.name.clone(),
+            error,
+        };
+        let pfd = perf::perf_event_open(attr, pid, cpu, -1, 0).map_err(&attach_error)?;
+        unsafe {
+            if libc::ioctl(pfd.as_raw_fd(), sys::perf::PERF_EVENT_IOC_SET_BPF, fd) != 0
+                || libc::ioctl(pfd.as_raw_fd(), sys::perf::PERF_EVENT_IOC_ENABLE, 0) != 0
+            {
+                return Err(attach_error(io::Error::last_os_error()));
+            }
         }
+
+        Ok(Link::perf_event(&self.name, pfd.into_raw_fd()))
     }
 }
 
 impl KProbe {
+    fn try_clone(&self) -> Result<KProbe> {
+        Ok(KProbe {
+            common: self.common.try_clone()?,
+            attach_type: self.attach_type,
+            syscall: self.syscall,
+        })
+    }
+
+    /// Attach the program to the function or system call it's named after.
+    ///
+    /// Programs defined with `#[syscall]` or `#[syscall_ret]` are attached
+    /// with [`attach_syscall`](KProbe::attach_syscall), the others are
+    /// attached with [`attach_kprobe`](KProbe::attach_kprobe) at offset 0.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let mut links = Vec::new();
+    /// for kprobe in module.kprobes_mut() {
+    ///     links.push(kprobe.attach().unwrap());
+    /// }
+    /// ```
+    pub fn attach(&mut self) -> Result<Link> {
+        let name = self.name();
+        if self.syscall {
+            self.attach_syscall(&name)
+        } else {
+            self.attach_kprobe(&name, 0)
+        }
+    }
+
     /// Attach the `kprobe` or `kretprobe`.
     ///
     /// Attach the probe to the function `fn_name` inside the kernel. If `offset`
     /// is given, the probe will be attached at that byte offset inside the
-    /// function.
+    /// function. The probe is detached when the returned `Link` is dropped.
     ///
     /// # Example
     /// ```no_run
     /// use redbpf::Module;
     /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let mut links = Vec::new();
     /// for kprobe in module.kprobes_mut() {
-    ///     kprobe.attach_kprobe(&kprobe.name(), 0).unwrap();
+    ///     links.push(kprobe.attach_kprobe(&kprobe.name(), 0).unwrap());
     /// }
     /// ```
-    pub fn attach_kprobe(&mut self, fn_name: &str, offset: u64) -> Result<()> {
-        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+    pub fn attach_kprobe(&mut self, fn_name: &str, offset: u64) -> Result<Link> {
+        let fd = self.common.raw_fd()?;
         let ev_name = CString::new(format!("{}{}", fn_name, self.attach_type)).unwrap();
         let cname = CString::new(fn_name).unwrap();
         let pfd = unsafe {
@@ -339,16 +884,87 @@ impl KProbe {
         };
 
         if pfd < 0 {
-            Err(Error::BPF)
+            Err(Error::program("attach", &self.common.name))
         } else {
-            Ok(())
+            Ok(Link::kprobe(&self.common.name, pfd, ev_name))
         }
     }
 
+    /// Attach the `kprobe` or `kretprobe` to all the functions matching `pattern`.
+    ///
+    /// `pattern` is a regular expression that must match the whole name of
+    /// the function. The functions are taken from
+    /// `/sys/kernel/debug/tracing/available_filter_functions`, or from
+    /// `/proc/kallsyms` if tracefs isn't mounted, leaving out the functions
+    /// that are blacklisted for kprobes.
+    ///
+    /// Attaching to one function failing doesn't stop the others from being
+    /// attached, so the result of each attempt is returned together with the
+    /// name of the function. An error is only returned if `pattern` is
+    /// invalid or the functions can't be listed.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let mut links = Vec::new();
+    /// for kprobe in module.kprobes_mut() {
+    ///     for (function, result) in kprobe.attach_kprobe_matching("tcp_.*").unwrap() {
+    ///         match result {
+    ///             Ok(link) => links.push(link),
+    ///             Err(e) => eprintln!("skipping {}: {}", function, e),
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn attach_kprobe_matching(&mut self, pattern: &str) -> Result<Vec<(String, Result<Link>)>> {
+        let regex = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| Error::InvalidPattern(e.to_string()))?;
+
+        Ok(kernel_functions()?
+            .into_iter()
+            .filter(|function| regex.is_match(function))
+            .map(|function| {
+                let result = self.attach_kprobe(&function, 0);
+                (function, result)
+            })
+            .collect())
+    }
+
+    /// Attach the program to the entry or exit of the system call `syscall`.
+    ///
+    /// System calls are implemented by functions whose name depends on the
+    /// architecture, like `__x64_sys_execve` on x86_64 and
+    /// `__arm64_sys_execve` on aarch64, or on kernels older than 4.17
+    /// `sys_execve` or `SyS_execve`. The function is looked up in
+    /// `/proc/kallsyms` and the program is attached to it with
+    /// [`attach_kprobe`](KProbe::attach_kprobe).
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let mut links = Vec::new();
+    /// for kprobe in module.kprobes_mut() {
+    ///     links.push(kprobe.attach_syscall("execve").unwrap());
+    /// }
+    /// ```
+    pub fn attach_syscall(&mut self, syscall: &str) -> Result<Link> {
+        let function =
+            syscall_function(syscall)?.ok_or_else(|| Error::SymbolNotFound(syscall.to_string()))?;
+        self.attach_kprobe(&function, 0)
+    }
+
     pub fn name(&self) -> String {
         self.common.name.to_string()
     }
 
+    /// Returns `true` if the program was defined with `#[syscall]` or
+    /// `#[syscall_ret]`.
+    pub fn is_syscall(&self) -> bool {
+        self.syscall
+    }
+
     pub fn attach_type_str(&self) -> &'static str {
         match self.attach_type {
             bpf_probe_attach_type_BPF_PROBE_ENTRY => "Kprobe",
@@ -359,6 +975,13 @@ impl KProbe {
 }
 
 impl UProbe {
+    fn try_clone(&self) -> Result<UProbe> {
+        Ok(UProbe {
+            common: self.common.try_clone()?,
+            attach_type: self.attach_type,
+        })
+    }
+
     /// Attach the `uprobe` or `uretprobe`.
     ///
     /// Attach the probe to the function `fn_name` defined in the library or
@@ -382,17 +1005,10 @@ impl UProbe {
         offset: u64,
         target: &str,
         pid: Option<pid_t>,
-    ) -> Result<()> {
-        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+    ) -> Result<Link> {
+        let fd = self.common.raw_fd()?;
 
-        let path = if let Some(pid) = pid {
-            resolve_proc_maps_lib(pid, target).unwrap_or_else(|| target.to_string())
-        } else {
-            match (target.starts_with('/'), LD_SO_CACHE.as_ref()) {
-                (false, Ok(cache)) => cache.resolve(target).unwrap_or(target).to_string(),
-                _ => target.to_owned(),
-            }
-        };
+        let path = resolve_target_path(target, pid);
         let sym_offset = if let Some(fn_name) = fn_name {
             let data = fs::read(&path)?;
             let parser = ElfSymbols::parse(&data)?;
@@ -426,9 +1042,9 @@ impl UProbe {
         };
 
         if pfd < 0 {
-            Err(Error::BPF)
+            Err(Error::program("attach", &self.common.name))
         } else {
-            Ok(())
+            Ok(Link::uprobe(&self.common.name, pfd, ev_name))
         }
     }
 
@@ -438,8 +1054,23 @@ impl UProbe {
 }
 
 impl TracePoint {
-    pub fn attach_trace_point(&mut self, category: &str, name: &str) -> Result<()> {
-        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+    /// Attach the `tracepoint`.
+    ///
+    /// Attach the program to the tracepoint `name` in `category`, as listed
+    /// in `/sys/kernel/debug/tracing/events/<category>/<name>`. The program is
+    /// detached when the returned `Link` is dropped.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let mut links = Vec::new();
+    /// for tp in module.trace_points_mut() {
+    ///     links.push(tp.attach_trace_point("sched", "sched_process_exec").unwrap());
+    /// }
+    /// ```
+    pub fn attach_trace_point(&mut self, category: &str, name: &str) -> Result<Link> {
+        let fd = self.common.raw_fd()?;
         let category = CString::new(category)?;
         let name = CString::new(name)?;
         let res = unsafe {
@@ -451,9 +1082,30 @@ impl TracePoint {
         };
 
         if res < 0 {
-            Err(Error::BPF)
+            Err(Error::program("attach", &self.common.name))
         } else {
-            Ok(())
+            Ok(Link::trace_point(&self.common.name, res, category, name))
+        }
+    }
+
+    /// Attach the `tracepoint` to the tracepoint it was defined for.
+    ///
+    /// Programs defined with `#[tracepoint("category:name")]` are named after
+    /// the tracepoint they target, so they can be attached without specifying
+    /// the category and name again.
+    pub fn attach(&mut self) -> Result<Link> {
+        let (category, name) = self.split_name()?;
+        self.attach_trace_point(&category, &name)
+    }
+
+    fn split_name(&self) -> Result<(String, String)> {
+        let mut parts = self.common.name.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(category), Some(name)) => Ok((category.to_string(), name.to_string())),
+            _ => Err(Error::Section(format!(
+                "invalid tracepoint name: {}",
+                self.common.name
+            ))),
         }
     }
 
@@ -462,30 +1114,35 @@ impl TracePoint {
     }
 }
 
-impl XDP {
-    /// Attach the XDP program.
+impl RawTracePoint {
+    /// Attach the raw `tracepoint`.
     ///
-    /// Attach the XDP program to the given network interface.
+    /// Attach the program to the tracepoint `name`, given without its
+    /// category, like `sched_switch`. The program is detached when the
+    /// returned `Link` is dropped.
     ///
     /// # Example
     /// ```no_run
-    /// # use redbpf::{Module, xdp};
-    /// # let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
-    /// # for uprobe in module.xdps_mut() {
-    /// uprobe.attach_xdp("eth0", xdp::Flags::default()).unwrap();
-    /// # }
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let mut links = Vec::new();
+    /// for tp in module.raw_trace_points_mut() {
+    ///     links.push(tp.attach_raw_trace_point("sched_switch").unwrap());
+    /// }
     /// ```
-    pub fn attach_xdp(&mut self, interface: &str, flags: xdp::Flags) -> Result<()> {
-        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
-        self.interfaces.push(interface.to_string());
-        let ciface = CString::new(interface).unwrap();
-        let res = unsafe { bpf_sys::bpf_attach_xdp(ciface.as_ptr(), fd, flags as u32) };
+    pub fn attach_raw_trace_point(&mut self, name: &str) -> Result<Link> {
+        let fd = self.common.raw_fd()?;
+        let cname = CString::new(name)?;
+        Link::raw_trace_point(&self.common.name, fd, Some(&cname))
+    }
 
-        if res < 0 {
-            Err(Error::BPF)
-        } else {
-            Ok(())
-        }
+    /// Attach the raw `tracepoint` to the tracepoint it was defined for.
+    ///
+    /// Programs defined with `#[raw_tracepoint("name")]` are named after the
+    /// tracepoint they target.
+    pub fn attach(&mut self) -> Result<Link> {
+        let name = self.common.name.clone();
+        self.attach_raw_trace_point(&name)
     }
 
     pub fn name(&self) -> String {
@@ -493,7 +1150,377 @@ impl XDP {
     }
 }
 
-impl Drop for XDP {
+impl PerfEvent {
+    /// Attach the `perf_event` program to a perf event.
+    ///
+    /// The event described by `attr` is opened for `pid` and `cpu` as in
+    /// [`perf_event_open(2)`](http://www.man7.org/linux/man-pages/man2/perf_event_open.2.html),
+    /// and the program runs every time the event overflows. The event is
+    /// closed when the returned `Link` is dropped.
+    pub fn attach_perf_event(
+        &mut self,
+        attr: &sys::perf::perf_event_attr,
+        pid: pid_t,
+        cpu: CpuId,
+    ) -> Result<Link> {
+        self.common.attach_perf_event(attr, pid, cpu)
+    }
+
+    /// Attach the `perf_event` program to a CPU clock on every online CPU.
+    ///
+    /// The program samples what each CPU is running `frequency` times per
+    /// second, which together with a `StackTrace` map is the basis of a CPU
+    /// profiler. One `Link` is returned for each CPU.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let mut links = Vec::new();
+    /// for prog in module.perf_events_mut() {
+    ///     links.extend(prog.attach_cpu_clock(99).unwrap());
+    /// }
+    /// ```
+    pub fn attach_cpu_clock(&mut self, frequency: u64) -> Result<Vec<Link>> {
+        use sys::perf::*;
+
+        let mut attr: perf_event_attr = unsafe { mem::zeroed() };
+        attr.size = mem::size_of::<perf_event_attr>() as u32;
+        attr.type_ = perf_type_id_PERF_TYPE_SOFTWARE;
+        attr.config = perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK as u64;
+        attr.__bindgen_anon_1.sample_freq = frequency;
+        attr.set_freq(1);
+
+        cpus::get_online()?
+            .into_iter()
+            .map(|cpu| self.attach_perf_event(&attr, -1, cpu))
+            .collect()
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+}
+
+impl FEntry {
+    /// Attach the `fentry` program.
+    ///
+    /// The program runs on entry to the kernel function it was defined for,
+    /// whose BTF id was resolved when the program was loaded. It's detached
+    /// when the returned `Link` is dropped.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let mut links = Vec::new();
+    /// for prog in module.fentries_mut() {
+    ///     links.push(prog.attach().unwrap());
+    /// }
+    /// ```
+    pub fn attach(&mut self) -> Result<Link> {
+        let fd = self.common.raw_fd()?;
+        Link::raw_trace_point(&self.common.name, fd, None)
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+}
+
+impl FExit {
+    /// Attach the `fexit` program.
+    ///
+    /// The program runs when the kernel function it was defined for returns,
+    /// whose BTF id was resolved when the program was loaded. It's detached
+    /// when the returned `Link` is dropped.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let mut links = Vec::new();
+    /// for prog in module.fexits_mut() {
+    ///     links.push(prog.attach().unwrap());
+    /// }
+    /// ```
+    pub fn attach(&mut self) -> Result<Link> {
+        let fd = self.common.raw_fd()?;
+        Link::raw_trace_point(&self.common.name, fd, None)
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+}
+
+// Layout of the values of the maps USDT programs read the arguments of the
+// locations they're attached to from. It must match `redbpf_probes::usdt`.
+const USDT_MAX_ARGS: usize = 12;
+const USDT_ARG_CONSTANT: u32 = 0;
+const USDT_ARG_REGISTER: u32 = 1;
+const USDT_ARG_MEMORY: u32 = 2;
+
+const UPROBE_PMU_TYPE: &str = "/sys/bus/event_source/devices/uprobe/type";
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq)]
+struct UsdtArgSpec {
+    value: u64,
+    kind: u32,
+    register: u16,
+    signed: u8,
+    shift: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq)]
+struct UsdtSpec {
+    args: [UsdtArgSpec; USDT_MAX_ARGS],
+    count: u16,
+}
+
+impl UsdtSpec {
+    fn new(arguments: &[UsdtArgument]) -> Option<UsdtSpec> {
+        if arguments.len() > USDT_MAX_ARGS {
+            return None;
+        }
+
+        let mut spec = UsdtSpec {
+            count: arguments.len() as u16,
+            ..Default::default()
+        };
+        for (arg, argument) in spec.args.iter_mut().zip(arguments) {
+            let (kind, register, value) = match argument.location {
+                UsdtArgumentLocation::Constant(value) => (USDT_ARG_CONSTANT, 0, value as u64),
+                UsdtArgumentLocation::Register(register) => (USDT_ARG_REGISTER, register, 0),
+                UsdtArgumentLocation::Memory { register, offset } => {
+                    (USDT_ARG_MEMORY, register, offset as u64)
+                }
+            };
+            *arg = UsdtArgSpec {
+                value,
+                kind,
+                register,
+                signed: argument.signed as u8,
+                // values are read as 64 bit words, the bytes that aren't
+                // part of the argument are shifted out
+                shift: 64 - argument.size * 8,
+            };
+        }
+
+        Some(spec)
+    }
+}
+
+impl Usdt {
+    /// Attach the program to the USDT probe it was defined for.
+    ///
+    /// The program must be named after the probe in the form
+    /// `"provider:name"`, as in `#[usdt("provider:name")]`. See
+    /// [`attach_usdt`](Usdt::attach_usdt) for the meaning of `target` and
+    /// `pid`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let mut links = Vec::new();
+    /// for usdt in module.usdts_mut() {
+    ///     links.extend(usdt.attach("/usr/bin/python3", None).unwrap());
+    /// }
+    /// ```
+    pub fn attach(&mut self, target: &str, pid: Option<pid_t>) -> Result<Vec<Link>> {
+        let mut parts = self.common.name.splitn(2, ':');
+        let (provider, name) = match (parts.next(), parts.next()) {
+            (Some(provider), Some(name)) => (provider.to_string(), name.to_string()),
+            _ => {
+                return Err(Error::Section(format!(
+                    "invalid USDT probe name: {}",
+                    self.common.name
+                )))
+            }
+        };
+        self.attach_usdt(&provider, &name, target, pid)
+    }
+
+    /// Attach the program to the USDT probe `name` of `provider`.
+    ///
+    /// The probe is looked up in the `.note.stapsdt` section of the library
+    /// or binary `target`, and the program is attached to every location
+    /// the probe is defined at. If the probe is guarded by a semaphore, the
+    /// kernel enables it in the processes that load `target` for as long as
+    /// the program is attached, which requires Linux 4.20.
+    ///
+    /// If a `pid` is passed, only the corresponding process is traced.
+    /// Otherwise all the processes that load `target` are traced, but if
+    /// `target` is position independent, like shared libraries and most
+    /// executables, the program can't tell the locations of the probe apart.
+    /// In that case the arguments of all the locations must be found in the
+    /// same place, or an error is returned.
+    ///
+    /// One `Link` is returned for each location of the probe.
+    pub fn attach_usdt(
+        &mut self,
+        provider: &str,
+        name: &str,
+        target: &str,
+        pid: Option<pid_t>,
+    ) -> Result<Vec<Link>> {
+        let probe_name = format!("{}:{}", provider, name);
+        let specs = self.specs.as_ref().ok_or_else(|| {
+            Error::Section(format!("no USDT map for program {}", self.common.name))
+        })?;
+        let specs = HashMap::<u64, UsdtSpec>::new(specs)?;
+
+        let path = resolve_target_path(target, pid);
+        let notes = UsdtNotes::parse(&fs::read(&path)?)?;
+        let mut locations = Vec::new();
+        for probe in notes
+            .probes
+            .iter()
+            .filter(|probe| probe.provider == provider && probe.name == name)
+        {
+            let arguments = probe.decode_arguments().map_err(|spec| {
+                Error::Usdt(format!("{}: unsupported argument `{}'", probe_name, spec))
+            })?;
+            let spec = UsdtSpec::new(&arguments)
+                .ok_or_else(|| Error::Usdt(format!("{}: too many arguments", probe_name)))?;
+            locations.push((probe, spec));
+        }
+        if locations.is_empty() {
+            return Err(Error::SymbolNotFound(probe_name));
+        }
+
+        // the program looks up the spec of each location by its address,
+        // falling back to the spec at address 0 when the address can't be
+        // known in advance, which all the locations, and those of the earlier
+        // attach calls that fell back to it, must then share
+        if notes.position_independent && pid.is_none() {
+            let shared = specs.get(0).unwrap_or(locations[0].1);
+            if locations.iter().any(|(_, spec)| *spec != shared) {
+                return Err(Error::Usdt(format!(
+                    "{}: locations with different arguments require a pid",
+                    probe_name
+                )));
+            }
+        }
+        for (probe, spec) in locations.iter() {
+            let address = match (notes.position_independent, pid) {
+                (false, _) => probe.address,
+                (true, Some(pid)) => proc_maps_address(pid, &path, probe.offset)?
+                    .ok_or_else(|| Error::Usdt(format!("{}: {} isn't mapped", probe_name, path)))?,
+                (true, None) => 0,
+            };
+            specs.set(address, *spec);
+        }
+
+        let pmu_type = fs::read_to_string(UPROBE_PMU_TYPE)?;
+        let pmu_type = pmu_type
+            .trim()
+            .parse()
+            .map_err(|_| Error::Usdt(format!("invalid uprobe PMU type: {}", pmu_type)))?;
+        let cpath = CString::new(path)?;
+        locations
+            .iter()
+            .map(|(probe, _)| self.attach_usdt_location(pmu_type, &cpath, probe, pid))
+            .collect()
+    }
+
+    fn attach_usdt_location(
+        &self,
+        pmu_type: u32,
+        path: &CString,
+        probe: &UsdtProbe,
+        pid: Option<pid_t>,
+    ) -> Result<Link> {
+        use sys::perf::*;
+
+        let mut attr: perf_event_attr = unsafe { mem::zeroed() };
+        attr.size = mem::size_of::<perf_event_attr>() as u32;
+        attr.type_ = pmu_type;
+        attr.__bindgen_anon_3.config1 = path.as_ptr() as u64;
+        attr.__bindgen_anon_4.config2 = probe.offset;
+        // the offset of the semaphore in the file goes in the upper half of
+        // the config
+        attr.config = probe.semaphore << 32;
+
+        match pid {
+            Some(pid) => self.common.attach_perf_event(&attr, pid, -1),
+            None => self.common.attach_perf_event(&attr, -1, 0),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+}
+
+impl XDP {
+    /// Attach the XDP program.
+    ///
+    /// Attach the XDP program to the given network interface.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use redbpf::{Module, xdp};
+    /// # let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// # for uprobe in module.xdps_mut() {
+    /// uprobe.attach_xdp("eth0", xdp::Flags::default()).unwrap();
+    /// # }
+    /// ```
+    pub fn attach_xdp(&mut self, interface: &str, flags: xdp::Flags) -> Result<()> {
+        let fd = self.common.raw_fd()?;
+        self.interfaces.push(interface.to_string());
+        let ciface = CString::new(interface).unwrap();
+        let res = unsafe { bpf_sys::bpf_attach_xdp(ciface.as_ptr(), fd, flags as u32) };
+
+        if res < 0 {
+            Err(Error::program("attach", &self.common.name))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Attach the XDP program through a kernel BPF link.
+    ///
+    /// Unlike `attach_xdp()`, the program is detached from `interface` when
+    /// the returned `Link` is dropped rather than when the program is, and
+    /// the link can be pinned with `Link::pin()` so that the program stays
+    /// attached after the process exits. Requires Linux 5.9 or newer.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use redbpf::{Module, xdp};
+    /// # let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// # for prog in module.xdps_mut() {
+    /// let link = prog.attach_xdp_link("eth0", xdp::Flags::default()).unwrap();
+    /// link.pin("/sys/fs/bpf/firewall_eth0").unwrap();
+    /// # }
+    /// ```
+    pub fn attach_xdp_link(&mut self, interface: &str, flags: xdp::Flags) -> Result<Link> {
+        let fd = self.common.raw_fd()?;
+        let ciface = CString::new(interface)?;
+        let ifindex = unsafe { libc::if_nametoindex(ciface.as_ptr()) };
+        if ifindex == 0 {
+            return Err(Error::program("attach", &self.common.name));
+        }
+
+        Link::create(
+            &self.common.name,
+            fd,
+            ifindex as RawFd,
+            link::BPF_XDP,
+            flags as u32,
+        )
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+}
+
+impl Drop for XDP {
     fn drop(&mut self) {
         for interface in self.interfaces.iter() {
             let ciface = CString::new(interface.as_bytes()).unwrap();
@@ -516,17 +1543,17 @@ impl SocketFilter {
     /// }
     /// ```
     pub fn attach_socket_filter(&mut self, interface: &str) -> Result<RawFd> {
-        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let fd = self.common.raw_fd()?;
         let ciface = CString::new(interface).unwrap();
         let sfd = unsafe { bpf_sys::bpf_open_raw_sock(ciface.as_ptr()) };
 
         if sfd < 0 {
-            return Err(Error::IO(io::Error::last_os_error()));
+            return Err(Error::program("open raw socket", &self.common.name));
         }
 
         match unsafe { bpf_sys::bpf_attach_socket(sfd, fd) } {
             0 => Ok(sfd),
-            _ => Err(Error::IO(io::Error::last_os_error())),
+            _ => Err(Error::program("attach", &self.common.name)),
         }
     }
 
@@ -545,6 +1572,19 @@ impl Module {
         let mut programs = RSHashMap::new();
         let mut maps = RSHashMap::new();
 
+        // pin paths are needed when creating the maps, so collect them first
+        let mut pins = RSHashMap::new();
+        for (shndx, shdr) in object.section_headers.iter().enumerate() {
+            if let (Some("map_pin"), Some(name)) = get_split_section_name(&object, &shdr, shndx)? {
+                pins.insert(name, zero::read_str(data(&bytes, &shdr)));
+            }
+        }
+
+        // and so is the BTF. It's optional, so if the kernel doesn't support
+        // it or rejects it the maps and programs are loaded without it.
+        let btf = load_btf(&object, bytes);
+        let mut field_offsets = RSHashMap::new();
+
         let mut license = String::new();
         let mut version = 0u32;
 
@@ -581,16 +1621,67 @@ impl Module {
                     );
                 }
                 (hdr::SHT_PROGBITS, Some("maps"), Some(name)) => {
-                    // Maps are immediately bcc_create_map'd
-                    maps.insert(shndx, Map::load(name, &content)?);
+                    // Maps are immediately bcc_create_map'd, unless they're
+                    // already pinned
+                    let map_btf = btf
+                        .as_ref()
+                        .and_then(|btf| btf.map(&format!("maps/{}", name)));
+                    let map = match pins.get(name) {
+                        Some(path) => Map::load_pinned(name, &content, path, map_btf)?,
+                        None => Map::create(name, &content, map_btf)?,
+                    };
+                    maps.insert(shndx, map);
+                }
+                (_, Some("core_reloc"), Some(access)) => {
+                    // field offsets are taken from the BTF of the running
+                    // kernel. Without it, the accessors use the offsets the
+                    // probe was built with.
+                    let offset = match Types::kernel() {
+                        Some(types) => types.field_offset(access).unwrap_or_else(|e| {
+                            log::debug!("{}", e);
+                            FIELD_NOT_FOUND
+                        }),
+                        None => FIELD_BUILD_OFFSET,
+                    };
+                    field_offsets.insert(shndx, offset);
+                }
+                (_, Some("kernel"), Some("syscall_wrappers")) => {
+                    let wrappers = syscall_wrappers().unwrap_or_else(|e| {
+                        log::warn!("can't tell how system calls take arguments: {}", e);
+                        true
+                    });
+                    let value = if wrappers {
+                        SYSCALL_WRAPPERS
+                    } else {
+                        NO_SYSCALL_WRAPPERS
+                    };
+                    field_offsets.insert(shndx, value);
                 }
                 (hdr::SHT_PROGBITS, Some(kind @ "kprobe"), Some(name))
                 | (hdr::SHT_PROGBITS, Some(kind @ "kretprobe"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "syscall"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "syscall_ret"), Some(name))
                 | (hdr::SHT_PROGBITS, Some(kind @ "uprobe"), Some(name))
                 | (hdr::SHT_PROGBITS, Some(kind @ "uretprobe"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "tracepoint"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "raw_tracepoint"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "perf_event"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "fentry"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "fexit"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "usdt"), Some(name))
                 | (hdr::SHT_PROGBITS, Some(kind @ "xdp"), Some(name))
                 | (hdr::SHT_PROGBITS, Some(kind @ "socketfilter"), Some(name)) => {
-                    programs.insert(shndx, Program::new(kind, name, &content)?);
+                    let mut program = Program::new(kind, name, &content)?;
+                    if let Some(btf) = &btf {
+                        // like the rest of the BTF, the func and line info
+                        // are optional
+                        let section = format!("{}/{}", kind, name);
+                        program.data_mut().btf = btf.program(&section).unwrap_or_else(|e| {
+                            log::warn!("loading {} without BTF: {}", section, e);
+                            None
+                        });
+                    }
+                    programs.insert(shndx, program);
                 }
                 _ => {}
             }
@@ -599,7 +1690,18 @@ impl Module {
         // Rewrite programs with relocation data
         for rel in rels.iter() {
             if programs.contains_key(&rel.target_sec_idx) {
-                rel.apply(&mut programs, &maps, &symtab)?;
+                rel.apply(&mut programs, &maps, &field_offsets, &symtab)?;
+            }
+        }
+
+        // USDT programs read the arguments of each location they're attached
+        // to from their own map, filled in when attaching
+        for program in programs.values_mut() {
+            if let Program::Usdt(usdt) = program {
+                let name = format!("usdt/{}", usdt.common.name);
+                if let Some(map) = maps.values().find(|map| map.name == name) {
+                    usdt.specs = Some(map.try_clone()?);
+                }
             }
         }
 
@@ -696,6 +1798,115 @@ impl Module {
             _ => None,
         })
     }
+
+    pub fn raw_trace_points(&self) -> impl Iterator<Item = &RawTracePoint> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            RawTracePoint(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn raw_trace_points_mut(&mut self) -> impl Iterator<Item = &mut RawTracePoint> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            RawTracePoint(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn perf_events(&self) -> impl Iterator<Item = &PerfEvent> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            PerfEvent(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn perf_events_mut(&mut self) -> impl Iterator<Item = &mut PerfEvent> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            PerfEvent(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn fentries(&self) -> impl Iterator<Item = &FEntry> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            FEntry(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn fentries_mut(&mut self) -> impl Iterator<Item = &mut FEntry> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            FEntry(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn fexits(&self) -> impl Iterator<Item = &FExit> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            FExit(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn fexits_mut(&mut self) -> impl Iterator<Item = &mut FExit> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            FExit(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn usdts(&self) -> impl Iterator<Item = &Usdt> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            Usdt(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn usdts_mut(&mut self) -> impl Iterator<Item = &mut Usdt> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            Usdt(p) => Some(p),
+            _ => None,
+        })
+    }
+}
+
+fn load_btf(object: &Elf<'_>, bytes: &[u8]) -> Option<Btf> {
+    let mut btf = None;
+    let mut ext = None;
+    let mut section_sizes = RSHashMap::new();
+    for shdr in object.section_headers.iter() {
+        let name = object.shdr_strtab.get_unsafe(shdr.sh_name)?;
+        match name {
+            ".BTF" => btf = Some(data(bytes, shdr)),
+            ".BTF.ext" => ext = Some(data(bytes, shdr)),
+            _ => (),
+        }
+        section_sizes.insert(name.to_string(), shdr.sh_size as u32);
+    }
+
+    let symbols = object
+        .syms
+        .iter()
+        .filter(|sym| sym.st_shndx != 0)
+        .filter_map(|sym| {
+            let name = object.strtab.get_unsafe(sym.st_name)?;
+            Some((name.to_string(), sym.st_value as u32))
+        })
+        .collect();
+
+    let mut btf = Btf::parse(btf?, ext).ok()?;
+    btf.load(&section_sizes, &symbols).ok()?;
+    Some(btf)
 }
 
 #[inline]
@@ -723,17 +1934,39 @@ impl RelocationInfo {
         &self,
         programs: &mut RSHashMap<usize, Program>,
         maps: &RSHashMap<usize, Map>,
+        field_offsets: &RSHashMap<usize, u32>,
         symtab: &[Sym],
     ) -> Result<()> {
         // get the program we need to apply relocations to based on the program section index
-        let prog = programs.get_mut(&self.target_sec_idx).ok_or(Error::Reloc)?;
+        let prog = programs.get_mut(&self.target_sec_idx).ok_or_else(|| {
+            Error::Reloc(format!(
+                "no program in section {} for symbol {}",
+                self.target_sec_idx, self.sym_idx
+            ))
+        })?;
         // lookup the symbol we're relocating in the symbol table
         let sym = symtab[self.sym_idx];
-        // get the map referenced by the program based on the symbol section index
-        let map = maps.get(&sym.st_shndx).ok_or(Error::Reloc)?;
-
         // the index of the instruction we need to patch
         let insn_idx = (self.offset / std::mem::size_of::<bpf_insn>() as u64) as usize;
+
+        // field accesses load the offset of the field as a constant, and
+        // `SyscallRegisters` how system calls take arguments
+        if let Some(offset) = field_offsets.get(&sym.st_shndx) {
+            let code = &mut prog.data_mut().code;
+            code[insn_idx].imm = *offset as i32;
+            code[insn_idx + 1].imm = 0;
+            return Ok(());
+        }
+
+        // get the map referenced by the program based on the symbol section index
+        let map = maps.get(&sym.st_shndx).ok_or_else(|| {
+            Error::Reloc(format!(
+                "no map in section {} referenced by program {}",
+                sym.st_shndx,
+                prog.name()
+            ))
+        })?;
+
         let code = &mut prog.data_mut().code;
         if map.section_data {
             code[insn_idx].set_src_reg(bpf_sys::BPF_PSEUDO_MAP_VALUE as u8);
@@ -741,15 +1974,107 @@ impl RelocationInfo {
         } else {
             code[insn_idx].set_src_reg(bpf_sys::BPF_PSEUDO_MAP_FD as u8);
         }
-        code[insn_idx].imm = map.fd;
+        code[insn_idx].imm = map.fd.as_raw_fd();
         Ok(())
     }
 }
 
 impl Map {
     pub fn load(name: &str, code: &[u8]) -> Result<Map> {
+        Map::create(name, code, None)
+    }
+
+    fn create(name: &str, code: &[u8], btf: Option<MapBtf>) -> Result<Map> {
+        let config: bpf_map_def = *zero::read(code);
+        Map::with_map_def(name, config, btf)
+    }
+
+    /// Opens the map pinned at `path`.
+    ///
+    /// The name and definition of the map are read from the kernel. Note that
+    /// the kernel only keeps the first 15 bytes of map names.
+    pub fn from_pinned<P: AsRef<Path>>(path: P) -> Result<Map> {
+        let path = path.as_ref();
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { bpf_sys::bpf_obj_get(cpath.as_ptr()) };
+        if fd < 0 {
+            return Err(Error::map("open pinned", &path.to_string_lossy()));
+        }
+        let fd = OwnedFd::new(fd);
+
+        let mut info: bpf_sys::bpf_map_info = unsafe { mem::zeroed() };
+        let mut info_len = mem::size_of_val(&info) as u32;
+        if unsafe {
+            bpf_sys::bpf_obj_get_info(
+                fd.as_raw_fd(),
+                &mut info as *mut _ as *mut _,
+                &mut info_len,
+            )
+        } < 0
+        {
+            return Err(Error::map("get info", &path.to_string_lossy()));
+        }
+
+        let name: Vec<u8> = info
+            .name
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8)
+            .collect();
+        Ok(Map {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            kind: info.type_,
+            fd,
+            config: bpf_map_def {
+                type_: info.type_,
+                key_size: info.key_size,
+                value_size: info.value_size,
+                max_entries: info.max_entries,
+                map_flags: info.map_flags,
+            },
+            section_data: false,
+        })
+    }
+
+    /// Pins the map to `path`.
+    ///
+    /// `path` must be on a BPF filesystem, usually mounted at `/sys/fs/bpf`.
+    /// The map stays alive while it's pinned, even after all its file
+    /// descriptors are closed. Remove the file at `path` to unpin it.
+    pub fn pin<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let cpath = CString::new(path.as_ref().as_os_str().as_bytes())?;
+        if unsafe { bpf_sys::bpf_obj_pin(self.fd.as_raw_fd(), cpath.as_ptr()) } < 0 {
+            return Err(Error::map("pin", &self.name));
+        }
+
+        Ok(())
+    }
+
+    // Reuses the map pinned at `path` if there's one, otherwise creates the
+    // map and pins it there.
+    fn load_pinned(name: &str, code: &[u8], path: &str, btf: Option<MapBtf>) -> Result<Map> {
+        let mut map = match Map::from_pinned(path) {
+            Ok(map) => map,
+            Err(Error::Map { error, .. }) if error.raw_os_error() == Some(libc::ENOENT) => {
+                let map = Map::create(name, code, btf)?;
+                map.pin(path)?;
+                return Ok(map);
+            }
+            Err(e) => return Err(e),
+        };
         let config: bpf_map_def = *zero::read(code);
-        Map::with_map_def(name, config)
+        if map.config.type_ != config.type_
+            || map.config.key_size != config.key_size
+            || map.config.value_size != config.value_size
+            || map.config.max_entries != config.max_entries
+            || map.config.map_flags != config.map_flags
+        {
+            return Err(Error::MapTypeMismatch {
+                map: name.to_string(),
+            });
+        }
+        map.name = name.to_string();
+        Ok(map)
     }
 
     fn with_section_data(name: &str, data: &[u8], flags: u32) -> Result<Map> {
@@ -762,40 +2087,49 @@ impl Map {
                 max_entries: 1,
                 map_flags: flags,
             },
+            None,
         )?;
         map.section_data = true;
         // for BSS we don't need to copy the data, it's already 0-initialized
         if name != ".bss" {
             unsafe {
                 let ret = bpf_sys::bpf_update_elem(
-                    map.fd,
+                    map.fd.as_raw_fd(),
                     &mut 0 as *mut _ as *mut _,
                     data.as_ptr() as *mut u8 as *mut _,
                     0,
                 );
                 if ret < 0 {
-                    return Err(Error::BPF);
+                    return Err(Error::map("update", name));
                 }
             }
         }
         Ok(map)
     }
 
-    fn with_map_def(name: &str, config: bpf_map_def) -> Result<Map> {
-        let cname = CString::new(name)?;
-        let fd = unsafe {
-            bpf_sys::bcc_create_map(
-                config.type_,
-                cname.as_ptr(),
-                config.key_size as i32,
-                config.value_size as i32,
-                config.max_entries as i32,
-                config.map_flags as i32,
-            )
+    fn with_map_def(name: &str, config: bpf_map_def, btf: Option<MapBtf>) -> Result<Map> {
+        // not all map types support BTF, so fall back to creating the map
+        // without it
+        let fd = match btf.and_then(|btf| btf::create_map(name, &config, &btf).ok()) {
+            Some(fd) => fd,
+            None => {
+                let cname = CString::new(name)?;
+                let fd = unsafe {
+                    bpf_sys::bcc_create_map(
+                        config.type_,
+                        cname.as_ptr(),
+                        config.key_size as i32,
+                        config.value_size as i32,
+                        config.max_entries as i32,
+                        config.map_flags as i32,
+                    )
+                };
+                if fd < 0 {
+                    return Err(Error::map("create", name));
+                }
+                OwnedFd::new(fd)
+            }
         };
-        if fd < 0 {
-            return Err(Error::Map);
-        }
 
         Ok(Map {
             name: name.to_string(),
@@ -805,6 +2139,32 @@ impl Map {
             section_data: false,
         })
     }
+
+    /// Creates a new `Map` referring to the same kernel object.
+    ///
+    /// The file descriptor of the map is duplicated, so the map is only
+    /// released once all the copies are dropped.
+    pub fn try_clone(&self) -> Result<Map> {
+        let fd = self.fd.try_clone().map_err(|error| Error::Map {
+            op: "clone",
+            map: self.name.clone(),
+            error,
+        })?;
+
+        Ok(Map {
+            name: self.name.clone(),
+            kind: self.kind,
+            fd,
+            config: self.config,
+            section_data: self.section_data,
+        })
+    }
+}
+
+impl AsRawFd for Map {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
 }
 
 impl<'base, K: Clone, V: Clone> HashMap<'base, K, V> {
@@ -812,7 +2172,9 @@ impl<'base, K: Clone, V: Clone> HashMap<'base, K, V> {
         if mem::size_of::<K>() != base.config.key_size as usize
             || mem::size_of::<V>() != base.config.value_size as usize
         {
-            return Err(Error::Map);
+            return Err(Error::MapTypeMismatch {
+                map: base.name.clone(),
+            });
         }
 
         Ok(HashMap {
@@ -825,7 +2187,7 @@ impl<'base, K: Clone, V: Clone> HashMap<'base, K, V> {
     pub fn set(&self, mut key: K, mut value: V) {
         unsafe {
             bpf_sys::bpf_update_elem(
-                self.base.fd,
+                self.base.fd.as_raw_fd(),
                 &mut key as *mut _ as *mut _,
                 &mut value as *mut _ as *mut _,
                 0,
@@ -837,7 +2199,7 @@ impl<'base, K: Clone, V: Clone> HashMap<'base, K, V> {
         let mut value = MaybeUninit::zeroed();
         if unsafe {
             bpf_sys::bpf_lookup_elem(
-                self.base.fd,
+                self.base.fd.as_raw_fd(),
                 &mut key as *mut _ as *mut _,
                 &mut value as *mut _ as *mut _,
             )
@@ -850,7 +2212,7 @@ impl<'base, K: Clone, V: Clone> HashMap<'base, K, V> {
 
     pub fn delete(&self, mut key: K) {
         unsafe {
-            bpf_sys::bpf_delete_elem(self.base.fd, &mut key as *mut _ as *mut _);
+            bpf_sys::bpf_delete_elem(self.base.fd.as_raw_fd(), &mut key as *mut _ as *mut _);
         }
     }
 
@@ -867,7 +2229,9 @@ impl<'base> ProgramArray<'base> {
         if mem::size_of::<u32>() != base.config.key_size as usize
             || mem::size_of::<RawFd>() != base.config.value_size as usize
         {
-            return Err(Error::Map);
+            return Err(Error::MapTypeMismatch {
+                map: base.name.clone(),
+            });
         }
 
         Ok(ProgramArray { base })
@@ -878,13 +2242,13 @@ impl<'base> ProgramArray<'base> {
         let mut fd: RawFd = 0;
         if unsafe {
             bpf_sys::bpf_lookup_elem(
-                self.base.fd,
+                self.base.fd.as_raw_fd(),
                 &mut index as *mut _ as *mut _,
                 &mut fd as *mut _ as *mut _,
             )
         } < 0
         {
-            return Err(Error::Map);
+            return Err(Error::map("lookup", &self.base.name));
         }
         Ok(fd)
     }
@@ -910,20 +2274,208 @@ impl<'base> ProgramArray<'base> {
     pub fn set(&mut self, mut index: u32, mut fd: RawFd) -> Result<()> {
         let ret = unsafe {
             bpf_sys::bpf_update_elem(
-                self.base.fd,
+                self.base.fd.as_raw_fd(),
                 &mut index as *mut _ as *mut _,
                 &mut fd as *mut _ as *mut _,
                 0,
             )
         };
         if ret < 0 {
-            return Err(Error::Map);
+            return Err(Error::map("update", &self.base.name));
         }
 
         Ok(())
     }
 }
 
+impl<'base, T: Clone> Array<'base, T> {
+    pub fn new(base: &Map) -> Result<Array<T>> {
+        if mem::size_of::<u32>() != base.config.key_size as usize
+            || mem::size_of::<T>() != base.config.value_size as usize
+        {
+            return Err(Error::MapTypeMismatch {
+                map: base.name.clone(),
+            });
+        }
+
+        Ok(Array {
+            base,
+            _element: PhantomData,
+        })
+    }
+
+    /// Get the value at `index`.
+    pub fn get(&self, mut index: u32) -> Option<T> {
+        let mut value = MaybeUninit::zeroed();
+        if unsafe {
+            bpf_sys::bpf_lookup_elem(
+                self.base.fd.as_raw_fd(),
+                &mut index as *mut _ as *mut _,
+                &mut value as *mut _ as *mut _,
+            )
+        } < 0
+        {
+            return None;
+        }
+        Some(unsafe { value.assume_init() })
+    }
+
+    /// Set the value at `index`.
+    pub fn set(&self, mut index: u32, mut value: T) -> Result<()> {
+        let ret = unsafe {
+            bpf_sys::bpf_update_elem(
+                self.base.fd.as_raw_fd(),
+                &mut index as *mut _ as *mut _,
+                &mut value as *mut _ as *mut _,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::map("update", &self.base.name));
+        }
+
+        Ok(())
+    }
+}
+
+impl<'base, T: Clone> PerCpuArray<'base, T> {
+    pub fn new(base: &Map) -> Result<PerCpuArray<T>> {
+        if mem::size_of::<u32>() != base.config.key_size as usize
+            || mem::size_of::<T>() != base.config.value_size as usize
+        {
+            return Err(Error::MapTypeMismatch {
+                map: base.name.clone(),
+            });
+        }
+
+        Ok(PerCpuArray {
+            base,
+            cpu_count: cpus::get_possible()?.len(),
+            _element: PhantomData,
+        })
+    }
+
+    /// Get the values at `index`, one for each possible CPU.
+    pub fn get(&self, mut index: u32) -> Option<PerCpuValues<T>> {
+        lookup_per_cpu(self.base, &mut index, self.cpu_count)
+    }
+
+    /// Set the values at `index`.
+    ///
+    /// `values` must hold one value for each possible CPU.
+    pub fn set(&self, mut index: u32, values: &PerCpuValues<T>) -> Result<()> {
+        update_per_cpu(self.base, &mut index, values, self.cpu_count)
+    }
+}
+
+impl<'base, K: Clone, V: Clone> PerCpuHashMap<'base, K, V> {
+    pub fn new(base: &Map) -> Result<PerCpuHashMap<K, V>> {
+        if mem::size_of::<K>() != base.config.key_size as usize
+            || mem::size_of::<V>() != base.config.value_size as usize
+        {
+            return Err(Error::MapTypeMismatch {
+                map: base.name.clone(),
+            });
+        }
+
+        Ok(PerCpuHashMap {
+            base,
+            cpu_count: cpus::get_possible()?.len(),
+            _k: PhantomData,
+            _v: PhantomData,
+        })
+    }
+
+    /// Set the values of `key`.
+    ///
+    /// `values` must hold one value for each possible CPU.
+    pub fn set(&self, mut key: K, values: &PerCpuValues<V>) -> Result<()> {
+        update_per_cpu(self.base, &mut key, values, self.cpu_count)
+    }
+
+    /// Get the values of `key`, one for each possible CPU.
+    pub fn get(&self, mut key: K) -> Option<PerCpuValues<V>> {
+        lookup_per_cpu(self.base, &mut key, self.cpu_count)
+    }
+
+    pub fn delete(&self, mut key: K) {
+        unsafe {
+            bpf_sys::bpf_delete_elem(self.base.fd.as_raw_fd(), &mut key as *mut _ as *mut _);
+        }
+    }
+
+    pub fn iter<'a>(&'a self) -> PerCpuMapIter<'a, '_, K, V> {
+        PerCpuMapIter {
+            map: self,
+            key: None,
+        }
+    }
+}
+
+impl<T: Clone> PerCpuValues<T> {
+    /// Creates values for all the possible CPUs, set to `value`.
+    pub fn new(value: T) -> Result<PerCpuValues<T>> {
+        let count = cpus::get_possible()?.len();
+        Ok(PerCpuValues(vec![value; count].into()))
+    }
+}
+
+impl<T> PerCpuValues<T> {
+    /// Returns the sum of the values of all the CPUs.
+    pub fn sum<'a>(&'a self) -> T
+    where
+        T: Sum<&'a T>,
+    {
+        self.0.iter().sum()
+    }
+
+    /// Combines the values of all the CPUs.
+    ///
+    /// `f` is called with the accumulator, starting at `init`, and the value
+    /// of each CPU in turn.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use redbpf::PerCpuValues;
+    /// #[derive(Clone)]
+    /// struct Counter {
+    ///     bytes: u64,
+    ///     io: u64,
+    /// }
+    /// # let values = PerCpuValues::new(Counter { bytes: 0, io: 0 }).unwrap();
+    /// let total = values.merge(Counter { bytes: 0, io: 0 }, |acc, c| Counter {
+    ///     bytes: acc.bytes + c.bytes,
+    ///     io: acc.io + c.io,
+    /// });
+    /// ```
+    pub fn merge<F>(&self, init: T, f: F) -> T
+    where
+        F: FnMut(T, &T) -> T,
+    {
+        self.0.iter().fold(init, f)
+    }
+}
+
+impl<T> From<Vec<T>> for PerCpuValues<T> {
+    fn from(values: Vec<T>) -> PerCpuValues<T> {
+        PerCpuValues(values.into())
+    }
+}
+
+impl<T> Deref for PerCpuValues<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for PerCpuValues<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.0
+    }
+}
+
 pub struct MapIter<'a, 'b, K: Clone, V: Clone> {
     map: &'a HashMap<'b, K, V>,
     key: Option<K>,
@@ -933,42 +2485,71 @@ impl<K: Clone, V: Clone> Iterator for MapIter<'_, '_, K, V> {
     type Item = (K, V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let key = self.key.take();
-        self.key = match key {
-            Some(mut key) => {
-                let mut next_key = MaybeUninit::<K>::zeroed();
-                let ret = unsafe {
-                    bpf_sys::bpf_get_next_key(
-                        self.map.base.fd,
-                        &mut key as *mut _ as *mut _,
-                        &mut next_key as *mut _ as *mut _,
-                    )
-                };
-                if ret < 0 {
-                    None
-                } else {
-                    Some(unsafe { next_key.assume_init() })
-                }
+        loop {
+            self.key = next_key(self.map.base, self.key.take());
+            let key = self.key.as_ref()?.clone();
+            // skip the entries deleted or evicted since the key was read
+            if let Some(value) = self.map.get(key.clone()) {
+                return Some((key, value));
             }
-            None => {
-                let mut key = MaybeUninit::<K>::zeroed();
-                if unsafe {
-                    bpf_sys::bpf_get_first_key(
-                        self.map.base.fd,
-                        &mut key as *mut _ as *mut _,
-                        self.map.base.config.key_size.into(),
-                    )
-                } < 0
-                {
-                    None
-                } else {
-                    Some(unsafe { key.assume_init() })
-                }
+        }
+    }
+}
+
+pub struct PerCpuMapIter<'a, 'b, K: Clone, V: Clone> {
+    map: &'a PerCpuHashMap<'b, K, V>,
+    key: Option<K>,
+}
+
+impl<K: Clone, V: Clone> Iterator for PerCpuMapIter<'_, '_, K, V> {
+    type Item = (K, PerCpuValues<V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.key = next_key(self.map.base, self.key.take());
+            let key = self.key.as_ref()?.clone();
+            // skip the entries deleted since the key was read
+            if let Some(values) = self.map.get(key.clone()) {
+                return Some((key, values));
             }
-        };
+        }
+    }
+}
 
-        let key = self.key.as_ref()?.clone();
-        Some((key.clone(), self.map.get(key).unwrap()))
+// Returns the key that follows `key` in `map`, or the first key if `key` is
+// `None`.
+fn next_key<K>(map: &Map, key: Option<K>) -> Option<K> {
+    match key {
+        Some(mut key) => {
+            let mut next_key = MaybeUninit::<K>::zeroed();
+            let ret = unsafe {
+                bpf_sys::bpf_get_next_key(
+                    map.fd.as_raw_fd(),
+                    &mut key as *mut _ as *mut _,
+                    &mut next_key as *mut _ as *mut _,
+                )
+            };
+            if ret < 0 {
+                None
+            } else {
+                Some(unsafe { next_key.assume_init() })
+            }
+        }
+        None => {
+            let mut key = MaybeUninit::<K>::zeroed();
+            if unsafe {
+                bpf_sys::bpf_get_first_key(
+                    map.fd.as_raw_fd(),
+                    &mut key as *mut _ as *mut _,
+                    map.config.key_size.into(),
+                )
+            } < 0
+            {
+                None
+            } else {
+                Some(unsafe { key.assume_init() })
+            }
+        }
     }
 }
 
@@ -982,7 +2563,7 @@ impl StackTrace<'_> {
             let mut value = MaybeUninit::uninit();
 
             let ret = bpf_sys::bpf_lookup_elem(
-                self.base.fd,
+                self.base.fd.as_raw_fd(),
                 &mut id as *mut libc::c_int as _,
                 value.as_mut_ptr() as *mut _,
             );
@@ -998,19 +2579,92 @@ impl StackTrace<'_> {
     pub fn delete(&mut self, id: libc::c_int) -> Result<()> {
         unsafe {
             let ret = bpf_sys::bpf_delete_elem(
-                self.base.fd,
+                self.base.fd.as_raw_fd(),
                 &id as *const libc::c_int as *mut libc::c_int as _,
             );
 
             if ret == 0 {
                 Ok(())
             } else {
-                Err(Error::Map)
+                Err(Error::map("delete", &self.base.name))
             }
         }
     }
 }
 
+// the kernel copies per-CPU values in and out of 8 byte aligned slots
+#[inline]
+fn per_cpu_value_size<T>() -> usize {
+    (mem::size_of::<T>() + 7) & !7
+}
+
+fn lookup_per_cpu<K, V>(base: &Map, key: &mut K, cpu_count: usize) -> Option<PerCpuValues<V>> {
+    let size = per_cpu_value_size::<V>();
+    let mut buf = vec![0u8; cpu_count * size];
+    if unsafe {
+        bpf_sys::bpf_lookup_elem(
+            base.fd.as_raw_fd(),
+            key as *mut _ as *mut _,
+            buf.as_mut_ptr() as *mut _,
+        )
+    } < 0
+    {
+        return None;
+    }
+
+    Some(unpack_per_cpu(&buf, cpu_count).into())
+}
+
+fn unpack_per_cpu<V>(buf: &[u8], cpu_count: usize) -> Vec<V> {
+    let size = per_cpu_value_size::<V>();
+    assert!(buf.len() >= cpu_count * size);
+    (0..cpu_count)
+        .map(|cpu| unsafe { ptr::read_unaligned(buf[cpu * size..].as_ptr() as *const V) })
+        .collect()
+}
+
+fn pack_per_cpu<V: Clone>(values: &[V]) -> Vec<u8> {
+    let size = per_cpu_value_size::<V>();
+    let mut buf = vec![0u8; values.len() * size];
+    for (cpu, value) in values.iter().enumerate() {
+        unsafe { ptr::write_unaligned(buf[cpu * size..].as_mut_ptr() as *mut V, value.clone()) };
+    }
+    buf
+}
+
+fn update_per_cpu<K, V: Clone>(
+    base: &Map,
+    key: &mut K,
+    values: &PerCpuValues<V>,
+    cpu_count: usize,
+) -> Result<()> {
+    if values.len() != cpu_count {
+        return Err(Error::Map {
+            op: "update",
+            map: base.name.clone(),
+            error: io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected {} values, got {}", cpu_count, values.len()),
+            ),
+        });
+    }
+
+    let mut buf = pack_per_cpu(values);
+    let ret = unsafe {
+        bpf_sys::bpf_update_elem(
+            base.fd.as_raw_fd(),
+            key as *mut _ as *mut _,
+            buf.as_mut_ptr() as *mut _,
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::map("update", &base.name));
+    }
+
+    Ok(())
+}
+
 #[inline]
 fn add_relocation(
     rels: &mut Vec<RelocationInfo>,
@@ -1036,6 +2690,30 @@ fn get_version(bytes: &[u8]) -> u32 {
     }
 }
 
+#[inline]
+fn log_from_buffer(buffer: &[u8]) -> String {
+    let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+// Only the verifier fails with EACCES or EINVAL and a log, other errors come
+// from the program not being loaded at all
+fn load_error(program: &str, error: io::Error, log_buffer: &[u8]) -> Error {
+    let log = log_from_buffer(log_buffer);
+    match error.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EINVAL) if !log.is_empty() => Error::Verifier {
+            program: program.to_string(),
+            log,
+            error,
+        },
+        _ => Error::Program {
+            op: "load",
+            program: program.to_string(),
+            error,
+        },
+    }
+}
+
 #[inline]
 fn data<'d>(bytes: &'d [u8], shdr: &SectionHeader) -> &'d [u8] {
     let offset = shdr.sh_offset as usize;
@@ -1043,3 +2721,37 @@ fn data<'d>(bytes: &'d [u8], shdr: &SectionHeader) -> &'d [u8] {
 
     &bytes[offset..end]
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_per_cpu_values() {
+        assert_eq!(per_cpu_value_size::<u8>(), 8);
+        assert_eq!(per_cpu_value_size::<u64>(), 8);
+        assert_eq!(per_cpu_value_size::<[u32; 3]>(), 16);
+
+        let buf = pack_per_cpu(&[1u32, 2, 0xdead_beef]);
+        assert_eq!(buf.len(), 24);
+        assert_eq!(&buf[..8], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&buf[8..12], &2u32.to_ne_bytes());
+        assert_eq!(&buf[16..20], &0xdead_beefu32.to_ne_bytes());
+        assert_eq!(unpack_per_cpu::<u32>(&buf, 3), vec![1, 2, 0xdead_beef]);
+
+        let values = [[1u16, 2, 3], [4, 5, 6]];
+        let buf = pack_per_cpu(&values);
+        assert_eq!(buf.len(), 16);
+        assert_eq!(unpack_per_cpu::<[u16; 3]>(&buf, 2), values.to_vec());
+    }
+
+    #[test]
+    fn test_log_from_buffer() {
+        assert_eq!(log_from_buffer(b""), "");
+        assert_eq!(log_from_buffer(b"\0\0\0"), "");
+        assert_eq!(log_from_buffer(b"0: (b7) r0 = 0\n\0\0"), "0: (b7) r0 = 0\n");
+        assert_eq!(log_from_buffer(b"truncated"), "truncated");
+        assert_eq!(log_from_buffer(b"a\0b"), "a");
+        assert_eq!(log_from_buffer(b"\xffa\0"), "\u{fffd}a");
+    }
+}
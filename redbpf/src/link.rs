@@ -0,0 +1,279 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+use libc::{c_int, syscall, SYS_bpf, EOPNOTSUPP};
+
+use crate::fd::OwnedFd;
+use crate::{Error, Result};
+
+// not defined by the bcc headers bpf-sys is built with
+const BPF_RAW_TRACEPOINT_OPEN: c_int = 17;
+const BPF_LINK_CREATE: c_int = 28;
+const BPF_LINK_DETACH: c_int = 34;
+pub(crate) const BPF_PROG_TYPE_TRACING: u32 = 26;
+pub(crate) const BPF_TRACE_FENTRY: u32 = 24;
+pub(crate) const BPF_TRACE_FEXIT: u32 = 25;
+pub(crate) const BPF_XDP: u32 = 37;
+
+#[repr(C)]
+struct RawTracePointOpenAttr {
+    name: u64,
+    prog_fd: u32,
+}
+
+#[repr(C)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_fd: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct LinkDetachAttr {
+    link_fd: u32,
+}
+
+pub(crate) fn bpf<T>(cmd: c_int, attr: &mut T) -> io::Result<c_int> {
+    let ret = unsafe { syscall(SYS_bpf, cmd, attr as *mut T, mem::size_of::<T>() as u32) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as c_int)
+    }
+}
+
+enum LinkKind {
+    KProbe { ev_name: CString },
+    UProbe { ev_name: CString },
+    TracePoint { category: CString, name: CString },
+    PerfEvent,
+    // a kernel BPF link, which can be pinned
+    Bpf { fd: OwnedFd },
+}
+
+/// An attached BPF program.
+///
+/// Links are returned by the `attach_*` methods of programs. The program stays
+/// attached for as long as the link is alive and is detached when the link is
+/// dropped or `detach()` is called.
+///
+/// Links backed by a kernel BPF link can also be pinned to the BPF filesystem
+/// with `pin()`, in which case the program stays attached after the link is
+/// dropped, until the pinned file is removed or the link is re-acquired with
+/// `from_pinned()` and detached. Pinned fentry, fexit and raw tracepoint links
+/// can't be detached, only removing their pinned file detaches them.
+#[must_use = "the program is detached when the link is dropped"]
+pub struct Link {
+    program: String,
+    fd: RawFd,
+    kind: LinkKind,
+    attached: bool,
+}
+
+impl Link {
+    pub(crate) fn kprobe(program: &str, pfd: RawFd, ev_name: CString) -> Link {
+        Link::new(program, pfd, LinkKind::KProbe { ev_name })
+    }
+
+    pub(crate) fn uprobe(program: &str, pfd: RawFd, ev_name: CString) -> Link {
+        Link::new(program, pfd, LinkKind::UProbe { ev_name })
+    }
+
+    pub(crate) fn trace_point(program: &str, pfd: RawFd, category: CString, name: CString) -> Link {
+        Link::new(program, pfd, LinkKind::TracePoint { category, name })
+    }
+
+    pub(crate) fn perf_event(program: &str, pfd: RawFd) -> Link {
+        Link::new(program, pfd, LinkKind::PerfEvent)
+    }
+
+    /// Creates a kernel BPF link attaching `prog_fd` to `target_fd`.
+    pub(crate) fn create(
+        program: &str,
+        prog_fd: RawFd,
+        target_fd: RawFd,
+        attach_type: u32,
+        flags: u32,
+    ) -> Result<Link> {
+        let mut attr = LinkCreateAttr {
+            prog_fd: prog_fd as u32,
+            target_fd: target_fd as u32,
+            attach_type,
+            flags,
+        };
+        let fd = bpf(BPF_LINK_CREATE, &mut attr).map_err(|error| Error::Program {
+            op: "attach",
+            program: program.to_string(),
+            error,
+        })?;
+
+        Ok(Link::from_fd(program, OwnedFd::new(fd)))
+    }
+
+    /// Attaches `prog_fd` to the raw tracepoint `name`.
+    ///
+    /// Tracing programs pass no name, they're attached to the kernel
+    /// function they were loaded for.
+    pub(crate) fn raw_trace_point(
+        program: &str,
+        prog_fd: RawFd,
+        name: Option<&CStr>,
+    ) -> Result<Link> {
+        // zeroed so that the padding is zero too, as the kernel requires
+        let mut attr: RawTracePointOpenAttr = unsafe { mem::zeroed() };
+        attr.name = name.map(|name| name.as_ptr() as u64).unwrap_or(0);
+        attr.prog_fd = prog_fd as u32;
+        let fd = bpf(BPF_RAW_TRACEPOINT_OPEN, &mut attr).map_err(|error| Error::Program {
+            op: "attach",
+            program: program.to_string(),
+            error,
+        })?;
+
+        Ok(Link::from_fd(program, OwnedFd::new(fd)))
+    }
+
+    pub(crate) fn from_fd(program: &str, fd: OwnedFd) -> Link {
+        Link::new(program, fd.as_raw_fd(), LinkKind::Bpf { fd })
+    }
+
+    fn new(program: &str, fd: RawFd, kind: LinkKind) -> Link {
+        Link {
+            program: program.to_string(),
+            fd,
+            kind,
+            attached: true,
+        }
+    }
+
+    /// Re-acquires the link pinned at `path`.
+    ///
+    /// The name of the program of the returned link is `path`.
+    pub fn from_pinned<P: AsRef<Path>>(path: P) -> Result<Link> {
+        let path = path.as_ref();
+        let name = path.to_string_lossy();
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { bpf_sys::bpf_obj_get(cpath.as_ptr()) };
+        if fd < 0 {
+            return Err(Error::program("open pinned", &name));
+        }
+
+        Ok(Link::from_fd(&name, OwnedFd::new(fd)))
+    }
+
+    /// Returns the name of the attached program.
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// Returns the fd the program is attached through.
+    ///
+    /// This is either a perf event fd or the fd of a kernel BPF link.
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Pins the link to `path`.
+    ///
+    /// `path` must be on a BPF filesystem, usually mounted at `/sys/fs/bpf`.
+    /// Only links backed by a kernel BPF link can be pinned, other links fail
+    /// with `EOPNOTSUPP`.
+    pub fn pin<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if let LinkKind::Bpf { fd } = &self.kind {
+            let cpath = CString::new(path.as_ref().as_os_str().as_bytes())?;
+            if unsafe { bpf_sys::bpf_obj_pin(fd.as_raw_fd(), cpath.as_ptr()) } < 0 {
+                return Err(Error::program("pin", &self.program));
+            }
+            return Ok(());
+        }
+
+        Err(Error::Program {
+            op: "pin",
+            program: self.program.clone(),
+            error: io::Error::from_raw_os_error(EOPNOTSUPP),
+        })
+    }
+
+    /// Detach the program.
+    ///
+    /// Unlike dropping the link, this reports detach failures. Pinned links
+    /// are detached too, except the links of fentry, fexit and raw
+    /// tracepoint programs: the kernel can't detach them explicitly, so this
+    /// fails with `EOPNOTSUPP` and they stay attached until their pin file is
+    /// removed.
+    pub fn detach(mut self) -> Result<()> {
+        self.detach_mut()
+    }
+
+    fn detach_mut(&mut self) -> Result<()> {
+        if !self.attached {
+            return Ok(());
+        }
+        self.attached = false;
+
+        if let LinkKind::Bpf { fd } = &self.kind {
+            let mut attr = LinkDetachAttr {
+                link_fd: fd.as_raw_fd() as u32,
+            };
+            return match bpf(BPF_LINK_DETACH, &mut attr) {
+                Ok(_) => Ok(()),
+                Err(error) => Err(Error::Program {
+                    op: "detach",
+                    program: self.program.clone(),
+                    error,
+                }),
+            };
+        }
+
+        let mut error = None;
+        if unsafe { bpf_sys::bpf_close_perf_event_fd(self.fd) } < 0 {
+            error = Some(io::Error::last_os_error());
+        }
+        let ret = unsafe {
+            match &self.kind {
+                LinkKind::KProbe { ev_name } => bpf_sys::bpf_detach_kprobe(ev_name.as_ptr()),
+                LinkKind::UProbe { ev_name } => bpf_sys::bpf_detach_uprobe(ev_name.as_ptr()),
+                LinkKind::TracePoint { category, name } => {
+                    bpf_sys::bpf_detach_tracepoint(category.as_ptr(), name.as_ptr())
+                }
+                // closing the event is enough
+                LinkKind::PerfEvent => 0,
+                LinkKind::Bpf { .. } => unreachable!(),
+            }
+        };
+        if ret < 0 && error.is_none() {
+            error = Some(io::Error::last_os_error());
+        }
+
+        match error {
+            Some(error) => Err(Error::Program {
+                op: "detach",
+                program: self.program.clone(),
+                error,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        // kernel BPF links are detached when their fd is closed, unless
+        // they're pinned
+        if let LinkKind::Bpf { .. } = self.kind {
+            return;
+        }
+        let _ = self.detach_mut();
+    }
+}
@@ -5,22 +5,58 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use futures::channel::mpsc;
-use futures::prelude::*;
 use std::convert::AsRef;
+use std::fmt;
 use std::fs;
 use std::io;
+use std::mem;
 use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-use crate::{Program, cpus};
-use crate::load::map_io::PerfMessageStream;
-use crate::{Error, KProbe, Map, Module, PerfMap, SocketFilter, UProbe, XDP};
+use crate::cpus::{self, CpuId};
+use crate::load::map_io::EventStream;
+use crate::ringbuf::BPF_MAP_TYPE_RINGBUF;
+use crate::Program;
+use crate::{
+    Error, FEntry, FExit, KProbe, Link, LostSamplesCount, Map, Module, PerfEvent, PerfMap,
+    PerfReader, RawTracePoint, RingBufReader, SocketFilter, TracePoint, UProbe, Usdt, XDP,
+};
 
 #[derive(Debug)]
 pub enum LoaderError {
     FileError(io::Error),
     ParseError(Error),
     LoadError(String, Error),
+    PerfMapError(String, Error),
+    RingBufError(String, Error),
+    ConfigError(String),
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use LoaderError::*;
+        match self {
+            FileError(e) => write!(f, "failed to read the ELF file: {}", e),
+            ParseError(e) => write!(f, "failed to parse the ELF file: {}", e),
+            LoadError(program, e) => write!(f, "failed to load program `{}': {}", program, e),
+            PerfMapError(map, e) => write!(f, "failed to open perf map `{}': {}", map, e),
+            RingBufError(map, e) => write!(f, "failed to open ring buffer `{}': {}", map, e),
+            ConfigError(e) => write!(f, "invalid loader configuration: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoaderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use LoaderError::*;
+        match self {
+            FileError(e) => Some(e),
+            ParseError(e) | LoadError(_, e) | PerfMapError(_, e) | RingBufError(_, e) => Some(e),
+            ConfigError(_) => None,
+        }
+    }
 }
 
 /// High level API to load bpf programs.
@@ -30,69 +66,422 @@ impl Loader {
     /// Loads the programs included in `data`.
     ///
     /// This will parse `data` with `Module::parse()` and load all the programs
-    /// present in the module.
+    /// present in the module. Use `Loader::builder()` to change the default
+    /// settings.
     pub fn load(data: &[u8]) -> Result<Loaded, LoaderError> {
+        LoaderBuilder::new().load(data)
+    }
+
+    /// Loads the BPF programs included in `file`.
+    ///
+    /// See `load()`.
+    pub fn load_file<P: AsRef<Path>>(file: P) -> Result<Loaded, LoaderError> {
+        LoaderBuilder::new().load_file(file)
+    }
+
+    /// Returns a `LoaderBuilder` to configure how programs and maps are loaded.
+    pub fn builder() -> LoaderBuilder {
+        LoaderBuilder::new()
+    }
+}
+
+const DEFAULT_PAGE_COUNT: usize = 16;
+const DEFAULT_EVENTS_CAPACITY: usize = 1024;
+
+/// Builder to configure how programs and perf maps are loaded.
+///
+/// # Example
+///
+/// ```no_run
+/// use redbpf::load::Loader;
+/// # async {
+/// let loader = Loader::builder()
+///     .page_count(64)
+///     .cpus(&[0, 1])
+///     .perf_maps(&["events"])
+///     .load_file("probe.elf")
+///     .unwrap();
+/// # };
+/// ```
+#[derive(Debug, Clone)]
+pub struct LoaderBuilder {
+    page_count: usize,
+    cpus: Option<Vec<CpuId>>,
+    perf_maps: Option<Vec<String>>,
+    load_programs: bool,
+    events_capacity: usize,
+}
+
+impl Default for LoaderBuilder {
+    fn default() -> Self {
+        LoaderBuilder {
+            page_count: DEFAULT_PAGE_COUNT,
+            cpus: None,
+            perf_maps: None,
+            load_programs: true,
+            events_capacity: DEFAULT_EVENTS_CAPACITY,
+        }
+    }
+}
+
+impl LoaderBuilder {
+    pub fn new() -> LoaderBuilder {
+        LoaderBuilder::default()
+    }
+
+    /// Sets the number of pages of each perf buffer.
+    ///
+    /// One buffer is allocated for each perf map and CPU. `count` must be a
+    /// power of two. The default is 16.
+    pub fn page_count(&mut self, count: usize) -> &mut LoaderBuilder {
+        self.page_count = count;
+        self
+    }
+
+    /// Sets the CPUs perf buffers are opened on.
+    ///
+    /// Events emitted on other CPUs are discarded. The default is all the
+    /// online CPUs.
+    pub fn cpus(&mut self, cpus: &[CpuId]) -> &mut LoaderBuilder {
+        self.cpus = Some(cpus.to_vec());
+        self
+    }
+
+    /// Sets the names of the perf maps to open.
+    ///
+    /// Events are only read from the given maps. The default is to open all
+    /// the perf maps in the module.
+    pub fn perf_maps(&mut self, names: &[&str]) -> &mut LoaderBuilder {
+        self.perf_maps = Some(names.iter().map(|name| name.to_string()).collect());
+        self
+    }
+
+    /// Sets whether programs are loaded in the kernel by `load()`.
+    ///
+    /// When set to `false`, programs must be loaded with `Program::load()`
+    /// before they can be attached. The default is `true`.
+    pub fn load_programs(&mut self, load: bool) -> &mut LoaderBuilder {
+        self.load_programs = load;
+        self
+    }
+
+    /// Sets the number of events buffered by the streams returned by
+    /// `Loaded::events()` and `Loaded::raw_events()`.
+    ///
+    /// The default is 1024.
+    pub fn events_capacity(&mut self, capacity: usize) -> &mut LoaderBuilder {
+        self.events_capacity = capacity;
+        self
+    }
+
+    /// Loads the programs and maps included in `data`.
+    ///
+    /// This will parse `data` with `Module::parse()`, load the programs if
+    /// enabled, open the perf maps on the configured CPUs and map the ring
+    /// buffers.
+    pub fn load(&self, data: &[u8]) -> Result<Loaded, LoaderError> {
+        if !self.page_count.is_power_of_two() {
+            return Err(LoaderError::ConfigError(format!(
+                "page count must be a power of two, got {}",
+                self.page_count
+            )));
+        }
+
         let mut module = Module::parse(&data).map_err(LoaderError::ParseError)?;
-        for program in module.programs.iter_mut() {
-            program
-                .load(module.version, module.license.clone())
-                .map_err(|e| LoaderError::LoadError(program.name().to_string(), e))?;
+        if let Some(names) = &self.perf_maps {
+            for name in names {
+                if !module
+                    .maps
+                    .iter()
+                    .any(|m| &m.name == name && is_perf_map(m))
+                {
+                    return Err(LoaderError::ConfigError(format!(
+                        "perf map not found: {}",
+                        name
+                    )));
+                }
+            }
+        }
+
+        if self.load_programs {
+            for program in module.programs.iter_mut() {
+                program
+                    .load(module.version, module.license.clone())
+                    .map_err(|e| LoaderError::LoadError(program.name().to_string(), e))?;
+            }
         }
 
-        let online_cpus = cpus::get_online().unwrap();
-        let (sender, receiver) = mpsc::unbounded();
-        for m in module.maps.iter_mut().filter(|m| m.kind == 4) {
-            for cpuid in online_cpus.iter() {
+        let perf_maps = module.maps.iter_mut().filter(|m| {
+            is_perf_map(m)
+                && self
+                    .perf_maps
+                    .as_ref()
+                    .map_or(true, |names| names.contains(&m.name))
+        });
+        let mut cpus = self.cpus.clone();
+        let mut perf_buffers = Vec::new();
+        let mut lost_counters = Vec::new();
+        for m in perf_maps {
+            if cpus.is_none() {
+                cpus = Some(
+                    cpus::get_online()
+                        .map_err(|e| LoaderError::PerfMapError(m.name.clone(), Error::IO(e)))?,
+                );
+            }
+            for cpuid in cpus.as_ref().unwrap().iter() {
                 let name = m.name.clone();
-                let map = PerfMap::bind(m, -1, *cpuid, 16, -1, 0).unwrap();
-                let stream = PerfMessageStream::new(name.clone(), map);
-                let mut s = sender.clone();
-                let fut = stream.for_each(move |events| {
-                    s.start_send((name.clone(), events)).unwrap();
-                    future::ready(())
+                let perf_map = PerfMap::bind(m, -1, *cpuid, self.page_count, -1, 0)
+                    .map_err(|e| LoaderError::PerfMapError(name.clone(), e))?;
+                let lost = Arc::new(AtomicU64::new(0));
+                lost_counters.push((name.clone(), *cpuid, lost.clone()));
+                perf_buffers.push(PerfBuffer {
+                    map: name,
+                    perf_map,
+                    lost,
                 });
-                tokio::spawn(fut);
             }
         }
 
+        let mut ring_bufs = Vec::new();
+        for m in module.maps.iter().filter(|m| is_ring_buf(m)) {
+            ring_bufs.push(
+                RingBufReader::new(m).map_err(|e| LoaderError::RingBufError(m.name.clone(), e))?,
+            );
+        }
+
         Ok(Loaded {
+            links: Vec::new(),
             module,
-            events: receiver,
+            perf_buffers,
+            lost_counters,
+            ring_bufs,
+            events_capacity: self.events_capacity,
         })
     }
 
-    /// Loads the BPF programs included in `file`.
+    /// Loads the programs and maps included in `file`.
     ///
     /// See `load()`.
-    pub fn load_file<P: AsRef<Path>>(file: P) -> Result<Loaded, LoaderError> {
-        Loader::load(&fs::read(file).map_err(LoaderError::FileError)?)
+    pub fn load_file<P: AsRef<Path>>(&self, file: P) -> Result<Loaded, LoaderError> {
+        self.load(&fs::read(file).map_err(LoaderError::FileError)?)
     }
 }
 
+fn is_perf_map(map: &Map) -> bool {
+    map.kind == bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERF_EVENT_ARRAY
+}
+
+fn is_ring_buf(map: &Map) -> bool {
+    map.kind == BPF_MAP_TYPE_RINGBUF
+}
+
 /// The `Loaded` object returned by `load()`.
 pub struct Loaded {
+    /// The links to the attached programs.
+    ///
+    /// Links stored here are detached when `Loaded` is dropped, before the
+    /// programs and maps in `module` are released.
+    pub links: Vec<Link>,
     pub module: Module,
-    /// The stream of events emitted by the BPF programs.
+    perf_buffers: Vec<PerfBuffer>,
+    lost_counters: Vec<(String, CpuId, Arc<AtomicU64>)>,
+    ring_bufs: Vec<RingBufReader>,
+    events_capacity: usize,
+}
+
+struct PerfBuffer {
+    map: String,
+    perf_map: PerfMap,
+    lost: Arc<AtomicU64>,
+}
+
+impl Loaded {
+    /// Detaches all the programs in `links`.
+    ///
+    /// All the links are detached even if some fail. The first error is
+    /// returned.
+    pub fn detach_all(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for link in self.links.drain(..) {
+            let ret = link.detach();
+            if result.is_ok() {
+                result = ret;
+            }
+        }
+        result
+    }
+
+    /// Returns a stream of the events emitted to the perf map or ring buffer
+    /// `name`.
+    ///
+    /// Each sample is read as a `T`, which must have the same layout as the
+    /// type the BPF program passes to `PerfMap::insert()` or `RingBuf::output()`.
+    /// Samples whose size doesn't match `size_of::<T>()` are discarded and
+    /// counted by `EventStream::skipped_samples()`.
+    ///
+    /// The buffers of the map are read on a dedicated thread, so the stream
+    /// can be polled from any executor. The stream buffers up to
+    /// `LoaderBuilder::events_capacity()` events. When it's full, reading from
+    /// the map is suspended until the stream is polled again. The kernel drops
+    /// the samples that don't fit in the map buffers in the meantime.
+    ///
+    /// Fails if `name` isn't a perf map or ring buffer opened by the loader or
+    /// if its events have already been taken.
+    ///
+    /// # Safety
+    ///
+    /// Samples are copied from the map into `T` as they are, so `T` must be
+    /// plain data for which any sequence of bytes is a valid value. It can't
+    /// contain references, pointers to owned data like `Box` or `String`, or
+    /// types with invalid bit patterns like `bool`, `char` or enums.
     ///
     /// # Example
     ///
     /// ```no_run
-    /// use std::path::Path;
     /// use futures::stream::StreamExt;
     /// use redbpf::load::Loader;
+    /// #[repr(C)]
+    /// struct Event {
+    ///     pid: u64,
+    ///     latency: u64,
+    /// }
     /// # async {
-    /// let mut loader = Loader::load_file(&Path::new("probe.elf")).unwrap();
-    /// while let Some((map_name, events)) = loader.events.next().await {
-    ///     for event in events {
-    ///         // ...
-    ///     }
+    /// let mut loader = Loader::load_file("probe.elf").unwrap();
+    /// let mut events = unsafe { loader.events::<Event>("events") }.unwrap();
+    /// while let Some(event) = events.next().await {
+    ///     println!("{} {}", event.pid, event.latency);
     /// }
     /// # };
     /// ```
-    pub events: mpsc::UnboundedReceiver<(String, <PerfMessageStream as Stream>::Item)>,
-}
+    pub unsafe fn events<T: Send + 'static>(
+        &mut self,
+        name: &str,
+    ) -> Result<EventStream<T>, LoaderError> {
+        self.event_stream(name, |data| sample_as::<T>(data))
+    }
+
+    /// Returns a stream of the raw samples emitted to the perf map or ring
+    /// buffer `name`.
+    ///
+    /// See `events()`.
+    pub fn raw_events(&mut self, name: &str) -> Result<EventStream<Box<[u8]>>, LoaderError> {
+        self.event_stream(name, |data| Some(data.into()))
+    }
+
+    fn event_stream<T, F>(
+        &mut self,
+        name: &str,
+        mut parse: F,
+    ) -> Result<EventStream<T>, LoaderError>
+    where
+        T: Send + 'static,
+        F: FnMut(&[u8]) -> Option<T> + Send + 'static,
+    {
+        if let Some(i) = self.ring_bufs.iter().position(|r| r.map() == name) {
+            let reader = self.ring_bufs.remove(i);
+            return Ok(EventStream::with_ring_buf(
+                reader,
+                self.events_capacity,
+                parse,
+            ));
+        }
+
+        let reader = self.take_perf_reader(Some(name))?;
+        Ok(EventStream::with_parser(
+            reader,
+            self.events_capacity,
+            move |_, _, data| parse(data),
+        ))
+    }
+
+    /// Returns a blocking `PerfReader` for all the perf maps whose events
+    /// haven't been taken yet.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use redbpf::load::Loader;
+    /// let mut loader = Loader::load_file("probe.elf").unwrap();
+    /// let mut reader = loader.perf_reader().unwrap();
+    /// loop {
+    ///     reader
+    ///         .read(None, |map, cpu, data| {
+    ///             println!("{} bytes from {} on CPU {}", data.len(), map, cpu)
+    ///         })
+    ///         .unwrap();
+    /// }
+    /// ```
+    pub fn perf_reader(&mut self) -> Result<PerfReader, LoaderError> {
+        self.take_perf_reader(None)
+    }
+
+    /// Returns the number of samples lost so far by each perf map, per CPU.
+    ///
+    /// Samples are lost when the BPF programs emit them faster than they're
+    /// read, and the perf buffers fill up. Lost samples are only counted while
+    /// the events of the map are being read, see `events()`.
+    pub fn lost_samples(&self) -> Vec<LostSamplesCount> {
+        self.lost_counters
+            .iter()
+            .map(|(map, cpu, count)| LostSamplesCount {
+                map: map.clone(),
+                cpu: *cpu,
+                count: count.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Returns the names of the perf maps whose events haven't been taken yet.
+    pub fn perf_map_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.perf_buffers.iter().map(|b| b.map.clone()).collect();
+        names.dedup();
+        names
+    }
+
+    /// Returns a blocking `RingBufReader` for the ring buffer `name`.
+    ///
+    /// Fails if `name` isn't a ring buffer or if its events have already been
+    /// taken.
+    pub fn ring_buf_reader(&mut self, name: &str) -> Result<RingBufReader, LoaderError> {
+        match self.ring_bufs.iter().position(|r| r.map() == name) {
+            Some(i) => Ok(self.ring_bufs.remove(i)),
+            None => Err(LoaderError::ConfigError(format!(
+                "ring buffer not found or already taken: {}",
+                name
+            ))),
+        }
+    }
+
+    /// Returns the names of the ring buffers whose events haven't been taken
+    /// yet.
+    pub fn ring_buf_names(&self) -> Vec<String> {
+        self.ring_bufs.iter().map(|r| r.map().to_string()).collect()
+    }
+
+    fn take_perf_reader(&mut self, name: Option<&str>) -> Result<PerfReader, LoaderError> {
+        let (buffers, rest): (Vec<_>, Vec<_>) = mem::take(&mut self.perf_buffers)
+            .into_iter()
+            .partition(|b| name.map_or(true, |name| b.map == name));
+        self.perf_buffers = rest;
+        if let (Some(name), true) = (name, buffers.is_empty()) {
+            return Err(LoaderError::ConfigError(format!(
+                "perf map or ring buffer not found or already taken: {}",
+                name
+            )));
+        }
+
+        let mut reader = PerfReader::new()
+            .map_err(|e| LoaderError::PerfMapError(name.unwrap_or_default().to_string(), e))?;
+        for buffer in buffers {
+            let map = buffer.map.clone();
+            reader
+                .add_counted(&buffer.map, buffer.perf_map, buffer.lost)
+                .map_err(|e| LoaderError::PerfMapError(map, e))?;
+        }
+
+        Ok(reader)
+    }
 
-impl Loaded {
     pub fn map(&self, name: &str) -> Option<&Map> {
         self.module.maps.iter().find(|m| m.name == name)
     }
@@ -120,4 +509,39 @@ impl Loaded {
     pub fn socket_filters_mut(&mut self) -> impl Iterator<Item = &mut SocketFilter> {
         self.module.socket_filters_mut()
     }
+
+    pub fn trace_points_mut(&mut self) -> impl Iterator<Item = &mut TracePoint> {
+        self.module.trace_points_mut()
+    }
+
+    pub fn raw_trace_points_mut(&mut self) -> impl Iterator<Item = &mut RawTracePoint> {
+        self.module.raw_trace_points_mut()
+    }
+
+    pub fn perf_events_mut(&mut self) -> impl Iterator<Item = &mut PerfEvent> {
+        self.module.perf_events_mut()
+    }
+
+    pub fn fentries_mut(&mut self) -> impl Iterator<Item = &mut FEntry> {
+        self.module.fentries_mut()
+    }
+
+    pub fn fexits_mut(&mut self) -> impl Iterator<Item = &mut FExit> {
+        self.module.fexits_mut()
+    }
+
+    pub fn usdts_mut(&mut self) -> impl Iterator<Item = &mut Usdt> {
+        self.module.usdts_mut()
+    }
+}
+
+// Safety: any `size_of::<T>()` bytes must be a valid `T`
+unsafe fn sample_as<T>(data: &[u8]) -> Option<T> {
+    // the kernel pads raw samples so that perf records are 8 byte aligned
+    let size = mem::size_of::<T>();
+    if data.len() < size || data.len() - size >= 8 {
+        return None;
+    }
+
+    Some(ptr::read_unaligned(data.as_ptr() as *const T))
 }
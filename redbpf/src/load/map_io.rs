@@ -5,17 +5,24 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use futures::channel::mpsc;
+use futures::executor;
 use futures::prelude::*;
 use mio::unix::EventedFd;
 use mio::{Evented, PollOpt, Ready, Token};
 use std::io;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::pin::Pin;
-use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
 use tokio::io::PollEvented;
 
-use crate::{Event, PerfMap};
+use crate::cpus::CpuId;
+use crate::load::LoaderError;
+use crate::{Error, PerfMap, PerfReader, RingBufReader};
 
 pub struct MapIo(RawFd);
 
@@ -49,31 +56,42 @@ pub struct PerfMessageStream {
     poll: PollEvented<MapIo>,
     map: PerfMap,
     name: String,
+    lost: Arc<AtomicU64>,
 }
 
 impl PerfMessageStream {
-    pub fn new(name: String, map: PerfMap) -> Self {
-        let io = MapIo(map.fd);
-        let poll = PollEvented::new(io).unwrap();
-        PerfMessageStream { poll, map, name }
+    pub fn new(name: String, map: PerfMap) -> Result<Self, LoaderError> {
+        let io = MapIo(map.as_raw_fd());
+        let poll = match PollEvented::new(io) {
+            Ok(poll) => poll,
+            Err(e) => return Err(LoaderError::PerfMapError(name, Error::IO(e))),
+        };
+        Ok(PerfMessageStream {
+            poll,
+            map,
+            name,
+            lost: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Returns the name of the map the stream reads from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the counter of the samples lost by the map.
+    ///
+    /// The counter is increased as the stream reads lost sample records,
+    /// which the kernel emits when the perf buffer is full.
+    pub fn lost_samples(&self) -> Arc<AtomicU64> {
+        self.lost.clone()
     }
 
     fn read_messages(&mut self) -> Vec<Box<[u8]>> {
         let mut ret = Vec::new();
-        while let Some(ev) = self.map.read() {
-            match ev {
-                Event::Lost(lost) => {
-                    eprintln!("Possibly lost {} samples for {}", lost.count, &self.name);
-                }
-                Event::Sample(sample) => {
-                    let msg = unsafe {
-                        slice::from_raw_parts(sample.data.as_ptr(), sample.size as usize)
-                            .to_vec()
-                            .into_boxed_slice()
-                    };
-                    ret.push(msg);
-                }
-            };
+        let lost = self.map.for_each_sample(|_, data| ret.push(data.into()));
+        if lost > 0 {
+            self.lost.fetch_add(lost, Ordering::Relaxed);
         }
 
         ret
@@ -94,3 +112,103 @@ impl Stream for PerfMessageStream {
         Poll::Ready(Some(messages))
     }
 }
+
+// how often the reader thread of an `EventStream` checks if the stream was dropped
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Asynchronous adapter for `PerfReader` and `RingBufReader`.
+///
+/// The reader is polled on a dedicated thread, and the samples are sent to the
+/// stream through a bounded channel, so the stream can be polled from any
+/// executor. When the channel is full, the thread stops reading from the
+/// buffers until the stream is polled again. The thread exits when the stream
+/// is dropped.
+pub struct EventStream<T> {
+    receiver: mpsc::Receiver<T>,
+    skipped: Arc<AtomicU64>,
+}
+
+impl<T: Send + 'static> EventStream<T> {
+    /// Creates a stream of the samples read by `reader`.
+    ///
+    /// `parse` is called on the reader thread with the name of the map, the
+    /// CPU and the data of each sample. Samples for which it returns `None`
+    /// are skipped. Up to `capacity` items are buffered.
+    pub fn with_parser<F>(mut reader: PerfReader, capacity: usize, mut parse: F) -> EventStream<T>
+    where
+        F: FnMut(&str, CpuId, &[u8]) -> Option<T> + Send + 'static,
+    {
+        EventStream::spawn(capacity, move |emit, skipped| {
+            reader.read(Some(READ_TIMEOUT), |map, cpu, data| {
+                match parse(map, cpu, data) {
+                    Some(item) => emit(item),
+                    None => {
+                        skipped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        })
+    }
+
+    /// Creates a stream of the events read by `reader`.
+    ///
+    /// `parse` is called on the reader thread with the data of each event.
+    /// Events for which it returns `None` are skipped. Up to `capacity` items
+    /// are buffered.
+    pub fn with_ring_buf<F>(reader: RingBufReader, capacity: usize, mut parse: F) -> EventStream<T>
+    where
+        F: FnMut(&[u8]) -> Option<T> + Send + 'static,
+    {
+        EventStream::spawn(capacity, move |emit, skipped| {
+            reader.read(Some(READ_TIMEOUT), |data| match parse(data) {
+                Some(item) => emit(item),
+                None => {
+                    skipped.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+    }
+
+    fn spawn<R>(capacity: usize, mut read: R) -> EventStream<T>
+    where
+        R: FnMut(&mut dyn FnMut(T), &AtomicU64) -> crate::Result<usize> + Send + 'static,
+    {
+        let (mut sender, receiver) = mpsc::channel(capacity);
+        let skipped = Arc::new(AtomicU64::new(0));
+        let counter = skipped.clone();
+        thread::spawn(move || {
+            let mut closed = false;
+            while !closed && !sender.is_closed() {
+                let ret = read(
+                    &mut |item| {
+                        if !closed {
+                            closed = executor::block_on(sender.send(item)).is_err();
+                        }
+                    },
+                    &counter,
+                );
+                if ret.is_err() {
+                    break;
+                }
+            }
+        });
+
+        EventStream { receiver, skipped }
+    }
+}
+
+impl<T> EventStream<T> {
+    /// Returns the number of samples skipped so far because they couldn't be
+    /// parsed.
+    pub fn skipped_samples(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Stream for EventStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
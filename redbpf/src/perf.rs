@@ -43,6 +43,18 @@
 //! }
 //! ```
 //!
+//! `PerfMap::read` copies each event out of the ring buffer. To read samples
+//! in place, use `PerfMap::for_each_sample` instead:
+//!
+//! ```no_run
+//! # use redbpf::{Map, PerfMap};
+//! # let mut map = Map::load("my_perf_map", &vec![]).unwrap();
+//! let perfmap = PerfMap::bind(&mut map, -1, 0, 16, -1, 0).unwrap();
+//! let lost = perfmap.for_each_sample(|cpu, sample| {
+//!     // do something with the sample
+//! });
+//! ```
+//!
 //! The `PerfMap::bind` call semantics closely follow that of the
 //! `perf_event_open(2)`
 //! [syscall](http://www.man7.org/linux/man-pages/man2/perf_event_open.2.html).
@@ -50,23 +62,25 @@
 #![allow(clippy::cast_lossless)]
 #![allow(clippy::cast_ptr_alignment)]
 
+use crate::cpus::CpuId;
+use crate::fd::OwnedFd;
 use crate::{Error, Map, HashMap, Result};
 use std::cell::RefCell;
 use std::io;
 use std::mem;
-use std::os::unix::io::RawFd;
-use std::ptr::null_mut;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::{self, null_mut};
 use std::slice;
 use std::sync::atomic::{self, AtomicPtr, Ordering};
 
 use libc::{
-    c_void, close, ioctl, mmap, munmap, syscall, sysconf, SYS_perf_event_open, MAP_FAILED,
+    c_void, ioctl, mmap, munmap, syscall, sysconf, SYS_perf_event_open, MAP_FAILED,
     MAP_SHARED, PROT_READ, PROT_WRITE, _SC_PAGESIZE,
 };
 
 use crate::sys::perf::*;
 
-unsafe fn open_perf_buffer(pid: i32, cpu: i32, group: RawFd, flags: u32) -> Result<RawFd> {
+unsafe fn open_perf_buffer(pid: i32, cpu: i32, group: RawFd, flags: u32) -> io::Result<OwnedFd> {
     let mut attr = mem::zeroed::<perf_event_attr>();
 
     attr.config = perf_sw_ids_PERF_COUNT_SW_BPF_OUTPUT as u64;
@@ -76,18 +90,31 @@ unsafe fn open_perf_buffer(pid: i32, cpu: i32, group: RawFd, flags: u32) -> Resu
     attr.__bindgen_anon_1.sample_period = 1;
     attr.__bindgen_anon_2.wakeup_events = 1;
 
-    let pfd = syscall(
-        SYS_perf_event_open,
-        &attr as *const perf_event_attr,
-        pid,
-        cpu,
-        group,
-        flags | PERF_FLAG_FD_CLOEXEC,
-    );
+    perf_event_open(&attr, pid, cpu, group, flags)
+}
+
+/// Opens the perf event described by `attr`, see `perf_event_open(2)`.
+pub(crate) fn perf_event_open(
+    attr: &perf_event_attr,
+    pid: i32,
+    cpu: i32,
+    group: RawFd,
+    flags: u32,
+) -> io::Result<OwnedFd> {
+    let pfd = unsafe {
+        syscall(
+            SYS_perf_event_open,
+            attr as *const perf_event_attr,
+            pid,
+            cpu,
+            group,
+            flags | PERF_FLAG_FD_CLOEXEC,
+        )
+    };
     if pfd < 0 {
-        Err(Error::IO(io::Error::last_os_error()))
+        Err(io::Error::last_os_error())
     } else {
-        Ok(pfd as RawFd)
+        Ok(OwnedFd::new(pfd as RawFd))
     }
 }
 
@@ -116,7 +143,8 @@ pub struct PerfMap {
     page_size: usize,
     mmap_size: usize,
     buf: RefCell<Vec<u8>>,
-    pub fd: RawFd,
+    fd: OwnedFd,
+    cpu: CpuId,
 }
 
 impl PerfMap {
@@ -129,7 +157,11 @@ impl PerfMap {
         flags: u32,
     ) -> Result<PerfMap> {
         unsafe {
-            let fd = open_perf_buffer(pid, cpu, group, flags)?;
+            let fd = open_perf_buffer(pid, cpu, group, flags).map_err(|error| Error::Map {
+                op: "perf_event_open",
+                map: map.name.clone(),
+                error,
+            })?;
             let page_size = sysconf(_SC_PAGESIZE) as usize;
             let mmap_size = page_size * (page_cnt + 1);
             let base_ptr = mmap(
@@ -137,20 +169,22 @@ impl PerfMap {
                 mmap_size,
                 PROT_READ | PROT_WRITE,
                 MAP_SHARED,
-                fd,
+                fd.as_raw_fd(),
                 0,
             );
 
             if base_ptr == MAP_FAILED {
-                return Err(Error::IO(io::Error::last_os_error()));
+                return Err(Error::map("mmap", &map.name));
             }
 
-            if ioctl(fd, PERF_EVENT_IOC_ENABLE, 0) != 0 {
-                return Err(Error::IO(io::Error::last_os_error()));
+            if ioctl(fd.as_raw_fd(), PERF_EVENT_IOC_ENABLE, 0) != 0 {
+                let error = Error::map("enable", &map.name);
+                munmap(base_ptr, mmap_size);
+                return Err(error);
             }
 
-            let tm = HashMap::<i32, i32>::new(map).unwrap();
-            tm.set(cpu, fd);
+            let tm = HashMap::<i32, i32>::new(map)?;
+            tm.set(cpu, fd.as_raw_fd());
 
             Ok(PerfMap {
                 base_ptr: AtomicPtr::new(base_ptr as *mut perf_event_mmap_page),
@@ -159,10 +193,82 @@ impl PerfMap {
                 page_size,
                 mmap_size,
                 fd,
+                cpu,
             })
         }
     }
 
+    /// Returns the CPU the perf buffer was opened on.
+    pub fn cpu(&self) -> CpuId {
+        self.cpu
+    }
+
+    /// Calls `f` with the CPU and the data of each sample in the buffer.
+    ///
+    /// Samples are passed to `f` straight from the ring buffer without copying
+    /// them, unless they wrap around the end of the ring. The space taken by
+    /// each sample is released after `f` returns.
+    ///
+    /// Returns the number of samples the kernel reported as lost.
+    pub fn for_each_sample<F>(&self, mut f: F) -> u64
+    where
+        F: FnMut(CpuId, &[u8]),
+    {
+        let mut lost = 0;
+        unsafe {
+            let header = self.base_ptr.load(Ordering::SeqCst);
+            let raw_size = (self.page_cnt * self.page_size) as u64;
+            let base = (header as *const u8).add(self.page_size);
+            let data_head = ptr::read_volatile(&(*header).data_head);
+            atomic::fence(Ordering::SeqCst);
+            let mut data_tail = (*header).data_tail;
+
+            while data_tail < data_head {
+                let start = (data_tail % raw_size) as usize;
+                // records are 8 byte aligned, so the header never wraps
+                let event = base.add(start) as *const perf_event_header;
+                let size = (*event).size as usize;
+
+                // the buffer is taken out of the map for as long as the record
+                // is in use, so that `f` can read the map too
+                let mut copy = None;
+                let record = if start + size > raw_size as usize {
+                    let len = raw_size as usize - start;
+                    let mut buf = mem::take(&mut *self.buf.borrow_mut());
+                    buf.clear();
+                    buf.extend_from_slice(slice::from_raw_parts(base.add(start), len));
+                    buf.extend_from_slice(slice::from_raw_parts(base, size - len));
+                    copy.get_or_insert(buf).as_ptr()
+                } else {
+                    event as *const u8
+                };
+
+                match (*event).type_ {
+                    perf_event_type_PERF_RECORD_SAMPLE => {
+                        let sample = &*(record as *const Sample);
+                        f(
+                            self.cpu,
+                            slice::from_raw_parts(sample.data.as_ptr(), sample.size as usize),
+                        );
+                    }
+                    perf_event_type_PERF_RECORD_LOST => {
+                        lost += (*(record as *const LostSamples)).count;
+                    }
+                    _ => (),
+                }
+                if let Some(buf) = copy {
+                    *self.buf.borrow_mut() = buf;
+                }
+
+                data_tail += size as u64;
+                atomic::fence(Ordering::SeqCst);
+                (*header).data_tail = data_tail;
+            }
+        }
+
+        lost
+    }
+
     pub fn read(&self) -> Option<Event<'_>> {
         unsafe {
             let header = self.base_ptr.load(Ordering::SeqCst);
@@ -219,8 +325,13 @@ impl Drop for PerfMap {
                 self.base_ptr.load(Ordering::SeqCst) as *mut c_void,
                 self.mmap_size,
             );
-            ioctl(self.fd, PERF_EVENT_IOC_DISABLE, 0);
-            close(self.fd);
+            ioctl(self.fd.as_raw_fd(), PERF_EVENT_IOC_DISABLE, 0);
         }
     }
 }
+
+impl AsRawFd for PerfMap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
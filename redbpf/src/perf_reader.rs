@@ -0,0 +1,183 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Blocking perf event reader
+//!
+//! `PerfReader` polls any number of `PerfMap` buffers from a single thread
+//! using `epoll(7)`, and doesn't depend on any async runtime.
+//!
+//! ```no_run
+//! use redbpf::{Map, PerfMap, PerfReader};
+//!
+//! let mut map = Map::load("events", &vec![]).unwrap();
+//! let mut reader = PerfReader::new().unwrap();
+//! for cpu in redbpf::cpus::get_online().unwrap() {
+//!     let perf_map = PerfMap::bind(&mut map, -1, cpu, 16, -1, 0).unwrap();
+//!     reader.add("events", perf_map).unwrap();
+//! }
+//!
+//! loop {
+//!     reader
+//!         .read(None, |map, cpu, data| {
+//!             println!("{} bytes from {} on CPU {}", data.len(), map, cpu);
+//!         })
+//!         .unwrap();
+//! }
+//! ```
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use libc::{
+    epoll_create1, epoll_ctl, epoll_event, epoll_wait, EINTR, EPOLLIN, EPOLL_CLOEXEC, EPOLL_CTL_ADD,
+};
+
+use crate::cpus::CpuId;
+use crate::fd::OwnedFd;
+use crate::{Error, PerfMap, Result};
+
+/// The number of samples a perf map lost on a CPU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LostSamplesCount {
+    pub map: String,
+    pub cpu: CpuId,
+    pub count: u64,
+}
+
+struct Buffer {
+    map: String,
+    cpu: CpuId,
+    perf_map: PerfMap,
+    lost: Arc<AtomicU64>,
+}
+
+/// Reads the samples of several perf buffers from one thread.
+pub struct PerfReader {
+    epoll: OwnedFd,
+    buffers: Vec<Buffer>,
+    events: Vec<epoll_event>,
+}
+
+impl PerfReader {
+    pub fn new() -> Result<PerfReader> {
+        let fd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+
+        Ok(PerfReader {
+            epoll: OwnedFd::new(fd),
+            buffers: Vec::new(),
+            events: Vec::new(),
+        })
+    }
+
+    /// Adds the perf buffer `perf_map` opened for the map `map`.
+    pub fn add(&mut self, map: &str, perf_map: PerfMap) -> Result<()> {
+        self.add_counted(map, perf_map, Arc::new(AtomicU64::new(0)))
+    }
+
+    pub(crate) fn add_counted(
+        &mut self,
+        map: &str,
+        perf_map: PerfMap,
+        lost: Arc<AtomicU64>,
+    ) -> Result<()> {
+        let mut event = epoll_event {
+            events: EPOLLIN as u32,
+            u64: self.buffers.len() as u64,
+        };
+        let ret = unsafe {
+            epoll_ctl(
+                self.epoll.as_raw_fd(),
+                EPOLL_CTL_ADD,
+                perf_map.as_raw_fd(),
+                &mut event,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::map("epoll_ctl", map));
+        }
+
+        self.buffers.push(Buffer {
+            map: map.to_string(),
+            cpu: perf_map.cpu(),
+            perf_map,
+            lost,
+        });
+        self.events.push(epoll_event { events: 0, u64: 0 });
+        Ok(())
+    }
+
+    /// Waits for samples and passes them to `f`.
+    ///
+    /// Blocks until at least one buffer has samples or `timeout` elapses,
+    /// then reads all the samples available in the ready buffers. `f` is
+    /// called with the name of the map, the CPU and the data of each sample.
+    ///
+    /// Returns the number of samples read.
+    pub fn read<F>(&mut self, timeout: Option<Duration>, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&str, CpuId, &[u8]),
+    {
+        if self.buffers.is_empty() {
+            return Ok(0);
+        }
+
+        let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        let ready = unsafe {
+            epoll_wait(
+                self.epoll.as_raw_fd(),
+                self.events.as_mut_ptr(),
+                self.events.len() as i32,
+                timeout,
+            )
+        };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(EINTR) {
+                return Ok(0);
+            }
+            return Err(Error::IO(error));
+        }
+
+        let mut count = 0;
+        for event in &self.events[..ready as usize] {
+            let buffer = &self.buffers[event.u64 as usize];
+            let lost = buffer.perf_map.for_each_sample(|cpu, data| {
+                f(&buffer.map, cpu, data);
+                count += 1;
+            });
+            if lost > 0 {
+                buffer.lost.fetch_add(lost, Ordering::Relaxed);
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Returns the number of samples lost so far by each buffer.
+    pub fn lost_samples(&self) -> Vec<LostSamplesCount> {
+        self.buffers
+            .iter()
+            .map(|buffer| LostSamplesCount {
+                map: buffer.map.clone(),
+                cpu: buffer.cpu,
+                count: buffer.lost.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+impl AsRawFd for PerfReader {
+    /// Returns the epoll fd, which is readable when any buffer has samples.
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
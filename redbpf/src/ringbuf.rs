@@ -0,0 +1,214 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Ring buffer reader
+//!
+//! `RingBufReader` reads the events BPF programs send to `BPF_MAP_TYPE_RINGBUF`
+//! maps, available since Linux 5.8. Unlike perf maps, a ring buffer is shared
+//! by all the CPUs, so events are read in the order they were produced.
+//!
+//! ```no_run
+//! use redbpf::{Map, RingBufReader};
+//!
+//! let map = Map::load("events", &vec![]).unwrap();
+//! let reader = RingBufReader::new(&map).unwrap();
+//! loop {
+//!     reader
+//!         .read(None, |data| println!("{} bytes", data.len()))
+//!         .unwrap();
+//! }
+//! ```
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use libc::{
+    c_void, mmap, munmap, off_t, poll, pollfd, sysconf, _SC_PAGESIZE, EINTR, MAP_FAILED,
+    MAP_SHARED, POLLIN, PROT_READ, PROT_WRITE,
+};
+
+use crate::fd::OwnedFd;
+use crate::{Error, Map, Result};
+
+// not defined by the bcc headers bpf-sys is built with
+pub(crate) const BPF_MAP_TYPE_RINGBUF: u32 = 27;
+
+const BPF_RINGBUF_BUSY_BIT: u32 = 1 << 31;
+const BPF_RINGBUF_DISCARD_BIT: u32 = 1 << 30;
+const BPF_RINGBUF_HDR_SZ: usize = 8;
+
+/// Reads the events of a ring buffer map.
+pub struct RingBufReader {
+    map: String,
+    fd: OwnedFd,
+    page_size: usize,
+    size: usize,
+    // the page holding the consumer position, which is writable
+    consumer: *mut c_void,
+    // the page holding the producer position, followed by the data pages
+    producer: *mut c_void,
+}
+
+// the mmaped pages are only accessed through `&self`, from one thread at a time
+unsafe impl Send for RingBufReader {}
+
+impl RingBufReader {
+    /// Maps the ring buffer of `map`.
+    ///
+    /// The reader keeps its own file descriptor of the map, so it can
+    /// outlive `map`.
+    pub fn new(map: &Map) -> Result<RingBufReader> {
+        if map.kind != BPF_MAP_TYPE_RINGBUF {
+            return Err(Error::MapTypeMismatch {
+                map: map.name.clone(),
+            });
+        }
+
+        let fd = map.fd.try_clone().map_err(|error| Error::Map {
+            op: "clone",
+            map: map.name.clone(),
+            error,
+        })?;
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) } as usize;
+        let size = map.config.max_entries as usize;
+
+        let consumer = unsafe {
+            mmap(
+                ptr::null_mut(),
+                page_size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if consumer == MAP_FAILED {
+            return Err(Error::map("mmap", &map.name));
+        }
+
+        // the kernel maps the data pages twice in a row, so that records that
+        // wrap around the end of the buffer can be read in one piece
+        let producer = unsafe {
+            mmap(
+                ptr::null_mut(),
+                page_size + 2 * size,
+                PROT_READ,
+                MAP_SHARED,
+                fd.as_raw_fd(),
+                page_size as off_t,
+            )
+        };
+        if producer == MAP_FAILED {
+            let error = Error::map("mmap", &map.name);
+            unsafe { munmap(consumer, page_size) };
+            return Err(error);
+        }
+
+        Ok(RingBufReader {
+            map: map.name.clone(),
+            fd,
+            page_size,
+            size,
+            consumer,
+            producer,
+        })
+    }
+
+    /// Returns the name of the map.
+    pub fn map(&self) -> &str {
+        &self.map
+    }
+
+    /// Passes the data of every event available in the buffer to `f`.
+    ///
+    /// The data is borrowed straight from the ring buffer, and its space is
+    /// released to the kernel as soon as `f` returns. Returns the number of
+    /// events read.
+    pub fn for_each_sample<F>(&self, mut f: F) -> usize
+    where
+        F: FnMut(&[u8]),
+    {
+        let consumer_pos = unsafe { &*(self.consumer as *const AtomicUsize) };
+        let producer_pos = unsafe { &*(self.producer as *const AtomicUsize) };
+        let data = unsafe { (self.producer as *const u8).add(self.page_size) };
+        let mask = self.size - 1;
+
+        let mut count = 0;
+        let mut cons = consumer_pos.load(Ordering::Acquire);
+        loop {
+            let prod = producer_pos.load(Ordering::Acquire);
+            if cons >= prod {
+                return count;
+            }
+
+            while cons < prod {
+                let header = unsafe { data.add(cons & mask) };
+                let len = unsafe { &*(header as *const AtomicU32) }.load(Ordering::Acquire);
+                if len & BPF_RINGBUF_BUSY_BIT != 0 {
+                    // the record is still being written
+                    return count;
+                }
+
+                let size = (len & !(BPF_RINGBUF_BUSY_BIT | BPF_RINGBUF_DISCARD_BIT)) as usize;
+                if len & BPF_RINGBUF_DISCARD_BIT == 0 {
+                    f(unsafe { slice::from_raw_parts(header.add(BPF_RINGBUF_HDR_SZ), size) });
+                    count += 1;
+                }
+
+                // records are 8 byte aligned
+                cons += (size + BPF_RINGBUF_HDR_SZ + 7) & !7;
+                consumer_pos.store(cons, Ordering::Release);
+            }
+        }
+    }
+
+    /// Waits for events and passes them to `f`.
+    ///
+    /// Blocks until the buffer has events or `timeout` elapses, then reads all
+    /// the available events with `for_each_sample()`.
+    ///
+    /// Returns the number of events read.
+    pub fn read<F>(&self, timeout: Option<Duration>, f: F) -> Result<usize>
+    where
+        F: FnMut(&[u8]),
+    {
+        let mut fds = pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        if unsafe { poll(&mut fds, 1, timeout) } < 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(EINTR) {
+                return Ok(0);
+            }
+            return Err(Error::IO(error));
+        }
+
+        Ok(self.for_each_sample(f))
+    }
+}
+
+impl AsRawFd for RingBufReader {
+    /// Returns the fd of the map, which is readable when the buffer has events.
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Drop for RingBufReader {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.producer, self.page_size + 2 * self.size);
+            munmap(self.consumer, self.page_size);
+        }
+    }
+}
@@ -6,8 +6,9 @@
 // copied, modified, or distributed except according to those terms.
 
 use byteorder::{NativeEndian, ReadBytesExt};
-use goblin::elf::{Elf, Sym};
+use goblin::elf::{header::ET_DYN, program_header::PT_LOAD, Elf, Sym};
 use libc::pid_t;
+use std::collections::HashSet;
 use std::ffi::CStr;
 use std::fs::{self, File};
 use std::io::{self, BufRead, Cursor, Read};
@@ -23,6 +24,30 @@ lazy_static! {
 
 const CACHE_HEADER: &str = "glibc-ld.so.cache1.1";
 
+const AVAILABLE_FILTER_FUNCTIONS: [&str; 2] = [
+    "/sys/kernel/debug/tracing/available_filter_functions",
+    "/sys/kernel/tracing/available_filter_functions",
+];
+const KALLSYMS: &str = "/proc/kallsyms";
+
+const STAPSDT_NOTE_SECTION: &str = ".note.stapsdt";
+const STAPSDT_NOTE_OWNER: &str = "stapsdt";
+const STAPSDT_NOTE_TYPE: u32 = 3;
+const STAPSDT_BASE_SECTION: &str = ".stapsdt.base";
+const KPROBES_BLACKLIST: &str = "/sys/kernel/debug/kprobes/blacklist";
+
+// since 4.17 on x86_64 and 4.19 on aarch64, system calls are implemented by
+// wrappers that take the `pt_regs` of the caller as their only argument
+#[cfg(target_arch = "x86_64")]
+const SYSCALL_WRAPPER_PREFIX: Option<&str> = Some("__x64_sys_");
+#[cfg(target_arch = "aarch64")]
+const SYSCALL_WRAPPER_PREFIX: Option<&str> = Some("__arm64_sys_");
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SYSCALL_WRAPPER_PREFIX: Option<&str> = None;
+// older kernels take the arguments directly, in functions named `sys_<name>`
+// or, where the arguments are sign extended, `SyS_<name>`
+const SYSCALL_PREFIXES: [&str; 2] = ["sys_", "SyS_"];
+
 pub(crate) struct ElfSymbols<'a> {
     elf: Elf<'a>,
 }
@@ -61,6 +86,303 @@ impl<'a> ElfSymbols<'a> {
     }
 }
 
+/// A USDT probe defined in the `.note.stapsdt` section of a binary.
+#[derive(Debug, Clone)]
+pub(crate) struct UsdtProbe {
+    pub provider: String,
+    pub name: String,
+    /// Address of the probe, adjusted if the binary was prelinked
+    pub address: u64,
+    /// Offset of the probe in the file
+    pub offset: u64,
+    /// Offset of the semaphore in the file, or 0 if the probe has none
+    pub semaphore: u64,
+    /// Argument spec, like `-4@%eax 8@-8(%rbp)`
+    pub arguments: String,
+}
+
+/// Where the value of a USDT argument is found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum UsdtArgumentLocation {
+    Constant(i64),
+    /// The register at this offset in `struct pt_regs`
+    Register(u16),
+    /// The memory at `offset` from the address held by `register`
+    Memory {
+        register: u16,
+        offset: i64,
+    },
+}
+
+/// An argument of a USDT probe, decoded from its argument spec.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct UsdtArgument {
+    /// Size of the value in bytes
+    pub size: u8,
+    pub signed: bool,
+    pub location: UsdtArgumentLocation,
+}
+
+pub(crate) struct UsdtNotes {
+    /// Whether the binary is position independent, in which case the
+    /// addresses of the probes are relative to where it's loaded
+    pub position_independent: bool,
+    pub probes: Vec<UsdtProbe>,
+}
+
+impl UsdtNotes {
+    pub fn parse(data: &[u8]) -> goblin::error::Result<UsdtNotes> {
+        let elf = Elf::parse(data)?;
+        // prelinking moves the binary without updating the notes, the offset
+        // is found comparing the address of .stapsdt.base with the one
+        // recorded in each note
+        let base = elf
+            .section_headers
+            .iter()
+            .find(|sh| elf.shdr_strtab.get_unsafe(sh.sh_name) == Some(STAPSDT_BASE_SECTION))
+            .map(|sh| sh.sh_addr);
+        let file_offset = |address: u64| {
+            elf.program_headers
+                .iter()
+                .find(|ph| {
+                    ph.p_type == PT_LOAD
+                        && address >= ph.p_vaddr
+                        && address < ph.p_vaddr + ph.p_filesz
+                })
+                .map(|ph| address - ph.p_vaddr + ph.p_offset)
+        };
+
+        let mut probes = Vec::new();
+        let notes = elf
+            .iter_note_sections(data, Some(STAPSDT_NOTE_SECTION))
+            .into_iter()
+            .flatten();
+        for note in notes {
+            let note = note?;
+            if note.name != STAPSDT_NOTE_OWNER || note.n_type != STAPSDT_NOTE_TYPE {
+                continue;
+            }
+
+            // the note holds the addresses of the probe, of .stapsdt.base
+            // and of the semaphore, followed by the provider, name and
+            // arguments as nul terminated strings
+            let address_size = if elf.is_64 { 8 } else { 4 };
+            if note.desc.len() < 3 * address_size {
+                continue;
+            }
+            let mut cursor = Cursor::new(note.desc);
+            let mut read_address = || -> io::Result<u64> {
+                if elf.is_64 {
+                    cursor.read_u64::<NativeEndian>()
+                } else {
+                    cursor.read_u32::<NativeEndian>().map(u64::from)
+                }
+            };
+            let (mut address, note_base, mut semaphore) =
+                (read_address()?, read_address()?, read_address()?);
+            if let Some(base) = base {
+                address = address.wrapping_add(base).wrapping_sub(note_base);
+                if semaphore != 0 {
+                    semaphore = semaphore.wrapping_add(base).wrapping_sub(note_base);
+                }
+            }
+
+            let mut strings = note.desc[3 * address_size..]
+                .split(|b| *b == 0)
+                .map(|s| String::from_utf8_lossy(s).into_owned());
+            let (provider, name, arguments) = match (strings.next(), strings.next()) {
+                (Some(provider), Some(name)) => {
+                    (provider, name, strings.next().unwrap_or_default())
+                }
+                _ => continue,
+            };
+            let offset = match file_offset(address) {
+                Some(offset) => offset,
+                None => continue,
+            };
+            let semaphore = if semaphore == 0 {
+                0
+            } else {
+                match file_offset(semaphore) {
+                    Some(offset) => offset,
+                    None => continue,
+                }
+            };
+
+            probes.push(UsdtProbe {
+                provider,
+                name,
+                address,
+                offset,
+                semaphore,
+                arguments,
+            });
+        }
+
+        Ok(UsdtNotes {
+            position_independent: elf.header.e_type == ET_DYN,
+            probes,
+        })
+    }
+}
+
+impl UsdtProbe {
+    /// Decodes the argument spec of the probe.
+    ///
+    /// Returns the spec of the first argument that can't be decoded on error.
+    pub fn decode_arguments(&self) -> Result<Vec<UsdtArgument>, String> {
+        self.arguments
+            .split_whitespace()
+            .map(|spec| UsdtArgument::parse(spec).ok_or_else(|| spec.to_string()))
+            .collect()
+    }
+}
+
+impl UsdtArgument {
+    /// Parses an argument spec, like `-4@%eax` or `8@-8(%rbp)` on x86_64 and
+    /// `-4@x0` or `8@[sp, 16]` on aarch64.
+    fn parse(spec: &str) -> Option<UsdtArgument> {
+        let mut parts = spec.splitn(2, '@');
+        let (size, location) = (parts.next()?, parts.next()?);
+        let size = size.parse::<i8>().ok()?;
+        let signed = size < 0;
+        let size = size.unsigned_abs();
+        if ![1, 2, 4, 8].contains(&size) {
+            return None;
+        }
+
+        Some(UsdtArgument {
+            size,
+            signed,
+            location: parse_usdt_location(location)?,
+        })
+    }
+}
+
+fn parse_usdt_integer(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    // hex constants are the bits of the value, like `$0xffffffffffffffff`
+    // for -1
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()? as i64,
+        None => s.parse::<i64>().ok()?,
+    };
+
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+fn parse_usdt_location(location: &str) -> Option<UsdtArgumentLocation> {
+    if let Some(constant) = location.strip_prefix('$') {
+        return Some(UsdtArgumentLocation::Constant(parse_usdt_integer(
+            constant,
+        )?));
+    }
+    if let Some(register) = location.strip_prefix('%') {
+        return Some(UsdtArgumentLocation::Register(register_offset(register)?));
+    }
+
+    // offset(%register), where offset can be empty. Anything else, like
+    // symbol(%rip) or indexed addressing, isn't supported.
+    let register = location.strip_suffix(')')?;
+    let mut parts = register.splitn(2, "(%");
+    let (offset, register) = (parts.next()?, parts.next()?);
+    let offset = if offset.is_empty() {
+        0
+    } else {
+        parse_usdt_integer(offset)?
+    };
+
+    Some(UsdtArgumentLocation::Memory {
+        register: register_offset(register)?,
+        offset,
+    })
+}
+
+#[cfg(target_arch = "aarch64")]
+fn parse_usdt_location(location: &str) -> Option<UsdtArgumentLocation> {
+    // [register, offset] or [register]
+    if let Some(memory) = location.strip_prefix('[') {
+        let memory = memory.strip_suffix(']')?;
+        let mut parts = memory.splitn(2, ',');
+        let register = register_offset(parts.next()?.trim())?;
+        let offset = match parts.next() {
+            Some(offset) => parse_usdt_integer(offset.trim())?,
+            None => 0,
+        };
+        return Some(UsdtArgumentLocation::Memory { register, offset });
+    }
+
+    match parse_usdt_integer(location) {
+        Some(constant) => Some(UsdtArgumentLocation::Constant(constant)),
+        None => Some(UsdtArgumentLocation::Register(register_offset(location)?)),
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn parse_usdt_location(_location: &str) -> Option<UsdtArgumentLocation> {
+    None
+}
+
+// Offset of `register` in `struct pt_regs`. The lower parts of registers,
+// like %eax, are read from the full register.
+#[cfg(target_arch = "x86_64")]
+fn register_offset(register: &str) -> Option<u16> {
+    let offset = match register {
+        "rax" | "eax" | "ax" | "al" => 80,
+        "rbx" | "ebx" | "bx" | "bl" => 40,
+        "rcx" | "ecx" | "cx" | "cl" => 88,
+        "rdx" | "edx" | "dx" | "dl" => 96,
+        "rsi" | "esi" | "si" | "sil" => 104,
+        "rdi" | "edi" | "di" | "dil" => 112,
+        "rbp" | "ebp" | "bp" | "bpl" => 32,
+        "rsp" | "esp" | "sp" | "spl" => 152,
+        "rip" => 128,
+        _ => {
+            // r8 to r15, and their d, w and b parts
+            let n = register
+                .strip_prefix('r')?
+                .trim_end_matches(&['d', 'w', 'b'][..])
+                .parse::<u16>()
+                .ok()?;
+            match n {
+                8..=11 => 72 - (n - 8) * 8,
+                12..=15 => 24 - (n - 12) * 8,
+                _ => return None,
+            }
+        }
+    };
+
+    Some(offset)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn register_offset(register: &str) -> Option<u16> {
+    match register {
+        "sp" => Some(31 * 8),
+        _ => {
+            // x0 to x30, and their lower halves w0 to w30
+            let n = register
+                .strip_prefix('x')
+                .or_else(|| register.strip_prefix('w'))?
+                .parse::<u16>()
+                .ok()?;
+            if n <= 30 {
+                Some(n * 8)
+            } else {
+                None
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum CacheError {
     IOError(io::Error),
@@ -177,3 +499,293 @@ pub(crate) fn resolve_proc_maps_lib(pid: pid_t, lib: &str) -> Option<String> {
 
     ret.map(|(_, v)| v.clone())
 }
+
+/// Resolves the path of the library or binary `target`.
+///
+/// When a `pid` is given, `target` is looked up in the libraries mapped by
+/// the process, otherwise libraries that aren't given as an absolute path
+/// are looked up in the `ld.so` cache.
+pub(crate) fn resolve_target_path(target: &str, pid: Option<pid_t>) -> String {
+    if let Some(pid) = pid {
+        resolve_proc_maps_lib(pid, target).unwrap_or_else(|| target.to_string())
+    } else {
+        match (target.starts_with('/'), LD_SO_CACHE.as_ref()) {
+            (false, Ok(cache)) => cache.resolve(target).unwrap_or(target).to_string(),
+            _ => target.to_owned(),
+        }
+    }
+}
+
+/// Returns the address `offset` in the file at `path` is mapped at in the
+/// process `pid`.
+pub(crate) fn proc_maps_address(pid: pid_t, path: &str, offset: u64) -> io::Result<Option<u64>> {
+    let path = fs::canonicalize(path)?;
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+
+    // each line is `start-end perms offset dev inode path`
+    Ok(maps.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let mut range = fields.next()?.splitn(2, '-');
+        let start = u64::from_str_radix(range.next()?, 16).ok()?;
+        let end = u64::from_str_radix(range.next()?, 16).ok()?;
+        let map_offset = u64::from_str_radix(fields.nth(1)?, 16).ok()?;
+        if fields.nth(2).map(PathBuf::from)? != path
+            || offset < map_offset
+            || offset - map_offset >= end - start
+        {
+            return None;
+        }
+        Some(start + offset - map_offset)
+    }))
+}
+
+/// Returns the names of the kernel functions that can be probed.
+///
+/// The functions are read from `available_filter_functions` when tracefs is
+/// mounted, and from the text symbols of `/proc/kallsyms` otherwise, which
+/// may include a few functions that can't be traced. The functions in the
+/// kprobe blacklist are left out.
+pub(crate) fn kernel_functions() -> io::Result<Vec<String>> {
+    let available = AVAILABLE_FILTER_FUNCTIONS
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok());
+    let mut functions = match available {
+        // functions defined in modules are followed by the module name
+        Some(list) => list
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .map(String::from)
+            .collect::<Vec<_>>(),
+        None => fs::read_to_string(KALLSYMS)?
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace().skip(1);
+                match (fields.next()?, fields.next()?) {
+                    ("t", name) | ("T", name) => Some(name.to_string()),
+                    _ => None,
+                }
+            })
+            .collect(),
+    };
+
+    // each line is the address range of the function followed by its name
+    let blacklist = fs::read_to_string(KPROBES_BLACKLIST)
+        .map(|list| {
+            list.lines()
+                .filter_map(|line| line.split_whitespace().nth(1))
+                .map(String::from)
+                .collect::<HashSet<_>>()
+        })
+        .unwrap_or_default();
+    functions.retain(|name| !blacklist.contains(name));
+    functions.sort();
+    functions.dedup();
+
+    Ok(functions)
+}
+
+/// Returns the name of the kernel function implementing `syscall`, if the
+/// running kernel has it.
+pub(crate) fn syscall_function(syscall: &str) -> io::Result<Option<String>> {
+    let kallsyms = fs::read_to_string(KALLSYMS)?;
+    let names = kallsyms
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(2))
+        .collect::<HashSet<_>>();
+
+    Ok(SYSCALL_WRAPPER_PREFIX
+        .iter()
+        .chain(SYSCALL_PREFIXES.iter())
+        .map(|prefix| format!("{}{}", prefix, syscall))
+        .find(|function| names.contains(function.as_str())))
+}
+
+/// Returns whether the running kernel implements system calls with wrappers
+/// that take the registers of the caller.
+pub(crate) fn syscall_wrappers() -> io::Result<bool> {
+    let prefix = match SYSCALL_WRAPPER_PREFIX {
+        Some(prefix) => prefix,
+        None => return Ok(false),
+    };
+    Ok(fs::read_to_string(KALLSYMS)?
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(2))
+        .any(|name| name.starts_with(prefix)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_usdt_argument() {
+        use UsdtArgumentLocation::*;
+
+        let parse = |spec| UsdtArgument::parse(spec).unwrap();
+        assert_eq!(
+            parse("-4@%eax"),
+            UsdtArgument {
+                size: 4,
+                signed: true,
+                location: Register(80)
+            }
+        );
+        assert_eq!(
+            parse("8@-8(%rbp)"),
+            UsdtArgument {
+                size: 8,
+                signed: false,
+                location: Memory {
+                    register: 32,
+                    offset: -8
+                }
+            }
+        );
+        assert_eq!(parse("4@$5").location, Constant(5));
+        assert_eq!(parse("-8@$-0x10").location, Constant(-16));
+        assert_eq!(parse("8@$0xffffffffffffffff").location, Constant(-1));
+        assert_eq!(
+            parse("2@-0x10(%r12)").location,
+            Memory {
+                register: 24,
+                offset: -16
+            }
+        );
+        assert_eq!(
+            parse("1@(%rsp)").location,
+            Memory {
+                register: 152,
+                offset: 0
+            }
+        );
+        assert_eq!(parse("-4@%r8d").location, Register(72));
+
+        assert!(UsdtArgument::parse("3@%eax").is_none());
+        assert!(UsdtArgument::parse("%eax").is_none());
+        assert!(UsdtArgument::parse("8@%xmm0").is_none());
+        assert!(UsdtArgument::parse("8@sym(%rip)").is_none());
+        assert!(UsdtArgument::parse("8@8(%rax,%rbx,2)").is_none());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_register_offset() {
+        // the layout of struct pt_regs
+        let registers = [
+            "r15", "r14", "r13", "r12", "rbp", "rbx", "r11", "r10", "r9", "r8", "rax", "rcx",
+            "rdx", "rsi", "rdi",
+        ];
+        for (i, register) in registers.iter().enumerate() {
+            assert_eq!(
+                register_offset(register),
+                Some(i as u16 * 8),
+                "{}",
+                register
+            );
+        }
+        assert_eq!(register_offset("rip"), Some(128));
+        assert_eq!(register_offset("rsp"), Some(152));
+        for (part, register) in &[
+            ("al", "rax"),
+            ("esi", "rsi"),
+            ("r9w", "r9"),
+            ("r15b", "r15"),
+        ] {
+            assert_eq!(register_offset(part), register_offset(register));
+        }
+        assert_eq!(register_offset("r16"), None);
+        assert_eq!(register_offset("rr8"), None);
+    }
+
+    // Builds an executable with one USDT probe, whose note records
+    // `note_base` as the address of .stapsdt.base.
+    fn usdt_elf(base: u64, note_base: u64) -> Vec<u8> {
+        const VADDR: u64 = 0x40_0000;
+        let mut desc = Vec::new();
+        for address in &[VADDR + 0x100, note_base, VADDR + 0x300] {
+            desc.extend_from_slice(&address.to_ne_bytes());
+        }
+        desc.extend_from_slice(b"test\0hit\0-4@%eax 8@-8(%rbp)\0");
+        while desc.len() % 4 != 0 {
+            desc.push(0);
+        }
+        let mut note = Vec::new();
+        for word in &[8, desc.len() as u32, STAPSDT_NOTE_TYPE] {
+            note.extend_from_slice(&word.to_ne_bytes());
+        }
+        note.extend_from_slice(b"stapsdt\0");
+        note.extend_from_slice(&desc);
+        let strings = b"\0.note.stapsdt\0.stapsdt.base\0.shstrtab\0";
+
+        let (note_off, strings_off) = (0x100, 0x200);
+        let shoff = 0x300;
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2];
+        elf.push(if cfg!(target_endian = "little") { 1 } else { 2 });
+        elf.push(1);
+        elf.resize(16, 0);
+        elf.extend_from_slice(&2u16.to_ne_bytes()); // ET_EXEC
+        elf.extend_from_slice(&62u16.to_ne_bytes());
+        elf.extend_from_slice(&1u32.to_ne_bytes());
+        for word in &[0u64, 64, shoff] {
+            elf.extend_from_slice(&word.to_ne_bytes());
+        }
+        elf.extend_from_slice(&0u32.to_ne_bytes());
+        for half in &[64u16, 56, 1, 64, 4, 3] {
+            elf.extend_from_slice(&half.to_ne_bytes());
+        }
+        // a PT_LOAD segment mapping the whole file
+        elf.extend_from_slice(&PT_LOAD.to_ne_bytes());
+        elf.extend_from_slice(&5u32.to_ne_bytes());
+        for word in &[0u64, VADDR, VADDR, 0x1000, 0x1000, 0x1000] {
+            elf.extend_from_slice(&word.to_ne_bytes());
+        }
+
+        elf.resize(note_off, 0);
+        elf.extend_from_slice(&note);
+        elf.resize(strings_off, 0);
+        elf.extend_from_slice(strings);
+        elf.resize(shoff as usize, 0);
+        let sections = [
+            (0, 0, 0, 0, 0, 0),
+            (1, 7, VADDR + note_off as u64, note_off, note.len(), 4),
+            (15, 1, base, 0, 0, 1),
+            (29, 3, 0, strings_off, strings.len(), 1),
+        ];
+        for (name, sh_type, addr, offset, size, align) in sections.iter() {
+            elf.extend_from_slice(&(*name as u32).to_ne_bytes());
+            elf.extend_from_slice(&(*sh_type as u32).to_ne_bytes());
+            for word in &[0, *addr, *offset as u64, *size as u64] {
+                elf.extend_from_slice(&word.to_ne_bytes());
+            }
+            elf.extend_from_slice(&[0; 8]);
+            elf.extend_from_slice(&(*align as u64).to_ne_bytes());
+            elf.extend_from_slice(&0u64.to_ne_bytes());
+        }
+        elf.resize(0x1000, 0);
+        elf
+    }
+
+    #[test]
+    fn test_usdt_notes() {
+        let notes = UsdtNotes::parse(&usdt_elf(0x40_0800, 0x40_0800)).unwrap();
+        assert!(!notes.position_independent);
+        assert_eq!(notes.probes.len(), 1);
+        let probe = &notes.probes[0];
+        assert_eq!(
+            (probe.provider.as_str(), probe.name.as_str()),
+            ("test", "hit")
+        );
+        assert_eq!(probe.arguments, "-4@%eax 8@-8(%rbp)");
+        assert_eq!(probe.address, 0x40_0100);
+        assert_eq!(probe.offset, 0x100);
+        assert_eq!(probe.semaphore, 0x300);
+
+        // the binary was prelinked 0x80 bytes further
+        let notes = UsdtNotes::parse(&usdt_elf(0x40_0880, 0x40_0800)).unwrap();
+        let probe = &notes.probes[0];
+        assert_eq!(probe.address, 0x40_0180);
+        assert_eq!(probe.offset, 0x180);
+        assert_eq!(probe.semaphore, 0x380);
+    }
+}